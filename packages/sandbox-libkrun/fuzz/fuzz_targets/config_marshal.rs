@@ -0,0 +1,194 @@
+//! Fuzz the `LibkrunConfig` → C-argument conversion.
+//!
+//! Arbitrary configs and `setExec` calls are turned into a spec and then
+//! into the strings and arrays handed to libkrun. Conversion must never
+//! panic, every array must be NULL-terminated, and a config that
+//! `validateConfig` accepts must always marshal. Nothing here calls libkrun,
+//! so the harness runs on Linux:
+//!
+//! ```sh
+//! cargo +nightly fuzz run config_marshal
+//! ```
+
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/native/config.rs"]
+mod config;
+#[allow(dead_code)]
+#[path = "../../src/native/error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../../src/native/marshal.rs"]
+mod marshal;
+#[allow(dead_code)]
+#[path = "../../src/native/policy.rs"]
+mod policy;
+#[allow(dead_code)]
+#[path = "../../src/native/spec.rs"]
+mod spec;
+#[allow(dead_code)]
+#[path = "../../src/native/validate.rs"]
+mod validate;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    cpus: Option<u8>,
+    memory_mib: Option<u32>,
+    rootfs_path: Option<String>,
+    root: Option<(String, String, Option<String>, Option<String>)>,
+    clone_rootfs: Option<bool>,
+    workdir: Option<String>,
+    mounts: Option<HashMap<String, String>>,
+    /// Takes precedence over `mounts`, as the list form of the same field
+    mount_list: Option<Vec<(String, String, Option<String>, Option<bool>, Option<u32>)>>,
+    mount_policy: Option<(Option<Vec<String>>, Option<Vec<String>>, Option<bool>)>,
+    disks: Option<Vec<(String, String, bool, Option<bool>)>>,
+    scratch_disk_mib: Option<u32>,
+    /// (kind, binary, socket path, vfkit, mac, host loopback)
+    network: Option<(u8, Option<String>, Option<String>, Option<bool>, Option<String>, Option<bool>)>,
+    port_map: Option<Vec<(u32, u32, Option<String>)>>,
+    egress: Option<(Option<Vec<String>>, Option<Vec<String>>, Option<u32>, Option<String>)>,
+    env: Option<HashMap<String, String>>,
+    exec_path: String,
+    args: Vec<String>,
+    exec_env: HashMap<String, String>,
+}
+
+fuzz_target!(|input: Input| {
+    let port_map = input.port_map.map(|port_map| {
+        port_map
+            .into_iter()
+            .map(|(host_port, guest_port, host_addr)| config::PortMapping {
+                host_port,
+                guest_port,
+                host_addr,
+            })
+            .collect()
+    });
+    let disks = input.disks.map(|disks| {
+        disks
+            .into_iter()
+            .map(|(block_id, path, qcow2, read_only)| config::Disk {
+                block_id,
+                path,
+                format: if qcow2 { config::DiskFormat::Qcow2 } else { config::DiskFormat::Raw },
+                read_only,
+            })
+            .collect()
+    });
+    let mounts = match input.mount_list {
+        Some(list) => Some(config::Mounts::List(
+            list.into_iter()
+                .map(|(tag, host_path, guest_path, read_only, shm_size_mib)| config::Mount {
+                    tag,
+                    host_path,
+                    guest_path,
+                    read_only,
+                    shm_size_mib,
+                })
+                .collect(),
+        )),
+        None => input.mounts.map(config::Mounts::Map),
+    };
+    let config = config::LibkrunConfig {
+        cpus: input.cpus,
+        memory_mib: input.memory_mib,
+        rootfs_path: input.rootfs_path,
+        root: input.root.map(|(path, device, fstype, options)| config::RootConfig {
+            kind: config::RootKind::Disk,
+            path,
+            device,
+            fstype,
+            options,
+        }),
+        clone_rootfs: input.clone_rootfs,
+        workdir: input.workdir,
+        mounts,
+        mount_policy: input
+            .mount_policy
+            .map(|(allow, deny, allow_sensitive)| config::MountPolicy {
+                allow,
+                deny,
+                allow_sensitive,
+            }),
+        disks,
+        scratch_disk: input
+            .scratch_disk_mib
+            .map(|size_mib| config::ScratchDisk { size_mib }),
+        network: input
+            .network
+            .map(|(kind, binary, socket_path, vfkit, mac, host_loopback)| config::NetworkConfig {
+                kind: match kind % 7 {
+                    0 => config::NetworkKind::Tsi,
+                    1 => config::NetworkKind::Passt,
+                    2 => config::NetworkKind::Gvproxy,
+                    3 => config::NetworkKind::Unixstream,
+                    4 => config::NetworkKind::Unixgram,
+                    5 => config::NetworkKind::Nat,
+                    _ => config::NetworkKind::None,
+                },
+                binary,
+                socket_path,
+                vfkit,
+                mac,
+                host_loopback,
+            }),
+        port_map,
+        egress: input.egress.map(|(allow, deny, vsock_port, log_path)| config::EgressConfig {
+            allow,
+            deny,
+            vsock_port,
+            log_path,
+        }),
+        env: input.env,
+    };
+    let (vm_spec, problems) = config.into_spec();
+
+    match marshal::ConfigArgs::new(&vm_spec) {
+        Ok(args) => {
+            assert_eq!(args.mounts.len(), vm_spec.mounts.len());
+            // A disk root is attached first, and the scratch disk last
+            let root = usize::from(matches!(vm_spec.root, spec::RootSpec::Disk { .. }));
+            let scratch = usize::from(vm_spec.scratch_disk.is_some());
+            assert_eq!(args.disks.len(), root + vm_spec.disks.len() + scratch);
+            if let Some(env) = &args.env {
+                check_array(env, vm_spec.env.len());
+            }
+            match (&args.port_map, &vm_spec.port_map) {
+                (Some(array), Some(port_map)) => check_array(array, port_map.len()),
+                (None, None) => {}
+                // Only TSI takes libkrun's port map
+                (None, Some(_)) if vm_spec.network != spec::NetworkSpec::Tsi => {}
+                _ => panic!("port map presence changed during marshalling"),
+            }
+            assert_eq!(args.egress.is_some(), vm_spec.egress.is_some());
+        }
+        Err(err) => assert!(!problems.is_empty(), "validation missed: {}", err),
+    }
+
+    let mut exec_env: Vec<(String, String)> = input.exec_env.into_iter().collect();
+    exec_env.sort();
+    let exec = spec::ExecSpec {
+        path: input.exec_path,
+        args: input.args,
+        env: spec::merge_env(&vm_spec.env, &exec_env),
+    };
+    let exec = vm_spec.guest_exec(exec);
+    if let Ok(args) = marshal::ExecArgs::new(&exec) {
+        check_array(&args.argv, exec.args.len());
+        check_array(&args.envp, exec.env.len());
+    }
+});
+
+fn check_array(array: &marshal::CStringArray, len: usize) {
+    let ptrs = array.ptrs();
+    assert_eq!(ptrs.len(), len + 1);
+    let (last, items) = ptrs.split_last().expect("array always has a terminator");
+    assert!(last.is_null());
+    assert!(items.iter().all(|ptr| !ptr.is_null()));
+}
@@ -8,35 +8,597 @@ export interface LibkrunConfig {
   cpus?: number
   /** Memory in MiB */
   memoryMib?: number
-  /** Root filesystem path */
-  rootfsPath: string
+  /** Root filesystem path; exclusive with `root` */
+  rootfsPath?: string
+  /** Disk image to boot from instead of `rootfsPath` */
+  root?: RootConfig
+  /**
+   * Boot from a private clone of `rootfsPath` instead of the directory
+   * itself, so the guest's changes stay out of the base and other VMs
+   *
+   * The clone is reflinked where the filesystem supports it and removed
+   * when the context is freed.
+   */
+  cloneRootfs?: boolean
   /** Working directory inside VM */
   workdir?: string
-  /** virtiofs mounts: { tag: host_path } */
-  mounts?: Record<string, string>
-  /** Port mappings: ["host:guest", ...] */
-  portMap?: Array<string>
-  /** Environment variables */
+  /**
+   * virtiofs mounts, as a list of `Mount`s or the older
+   * `{ tag: hostPath }` map
+   */
+  mounts?: Array<Mount> | Record<string, string>
+  /**
+   * Which host paths `mounts`, `rootfsPath`, `root` and `disks` may
+   * share
+   *
+   * Omitted, any path may be shared except ones exposing credential
+   * stores such as `~/.ssh`.
+   */
+  mountPolicy?: MountPolicy
+  /** Disk images attached as virtio-blk devices, in order */
+  disks?: Array<Disk>
+  /** Empty disk for the guest's scratch data, removed with the VM */
+  scratchDisk?: ScratchDisk
+  /** How the guest reaches the network (default: TSI) */
+  network?: NetworkConfig
+  /**
+   * Ports forwarded from the host to the guest
+   *
+   * Omitted, libkrun exposes every port the guest listens on; an empty
+   * list exposes none. With passt, omitted forwards no ports. The other
+   * network kinds leave forwarding to their proxy.
+   */
+  portMap?: Array<PortMapping>
+  /**
+   * Route the guest's outbound connections through the crate's egress
+   * proxy, which only lets allowed hosts through
+   */
+  egress?: EgressConfig
+  /**
+   * Environment variables for the guest workload
+   *
+   * `setExec` merges its own `env` on top of these, with its values
+   * winning for duplicate keys. Keys must not contain `=` or NUL.
+   */
   env?: Record<string, string>
 }
+/**
+ * A root filesystem on a raw disk image
+ *
+ * The image is attached as block device `root`, ahead of any `disks`, and
+ * libkrun's init mounts it over a placeholder virtiofs root. Large images
+ * run much faster this way than shared over virtiofs.
+ */
+export interface RootConfig {
+  kind: RootKind
+  /** Host path of the raw image; it is attached read-write */
+  path: string
+  /** Guest device holding the root filesystem, e.g. `/dev/vda1` */
+  device: string
+  /** Filesystem type, e.g. `ext4` (default: auto-detect) */
+  fstype?: string
+  /** Comma-separated mount options */
+  options?: string
+}
+export const enum RootKind {
+  Disk = 'disk'
+}
+/** A host directory shared with the guest over virtio-fs */
+export interface Mount {
+  /** virtio-fs tag, at most 36 bytes; must be unique within a config */
+  tag: string
+  /** Host directory to share */
+  hostPath: string
+  /**
+   * Where to mount the tag in the guest before the workload starts
+   *
+   * The mount is done by a `/bin/sh` wrapper around the exec, so the
+   * guest needs `sh`, `mkdir` and `mount`. Omitted, mounting is left to
+   * the guest.
+   */
+  guestPath?: string
+  /**
+   * Mount read-only in the guest (default: false); requires `guestPath`
+   *
+   * libkrun's virtio-fs has no read-only mode, so this only protects
+   * against mistakes: a guest with root can remount the tag writable.
+   */
+  readOnly?: boolean
+  /** Size of the DAX shared memory window (default: libkrun's) */
+  shmSizeMib?: number
+}
+/**
+ * Rules for the host paths a VM is given: the directories of `mounts`
+ * and `rootfsPath`, and the images of `root` and `disks`
+ *
+ * Paths are compared after resolving symlinks, and the resolved path is
+ * what gets attached, so a symlink changed later cannot redirect it. A
+ * path that breaks a rule is reported as an `ERR_MOUNT_DENIED` error
+ * naming the rule.
+ */
+export interface MountPolicy {
+  /** Only directories inside one of these may be mounted (default: any) */
+  allow?: Array<string>
+  /**
+   * Directories that may neither be mounted, nor shared by mounting a
+   * directory containing them
+   */
+  deny?: Array<string>
+  /**
+   * Permit mounts exposing credential stores in the home directory, such
+   * as `~/.ssh`, `~/.aws` or `~/.config/gcloud` (default: false)
+   */
+  allowSensitive?: boolean
+}
+/** A disk image attached to the VM */
+export interface Disk {
+  /** Partition identifier; must be unique within a config */
+  blockId: string
+  /** Host path of the image */
+  path: string
+  /** Image format; never probed from the image contents */
+  format: DiskFormat
+  /** Attach read-only (default: false) */
+  readOnly?: boolean
+}
+/**
+ * A sparse raw image made for one context
+ *
+ * The image has no filesystem on it, and is attached as block device
+ * `scratch` after a disk root and any `disks`, so it is the last one. It
+ * lives in a temporary directory the crate manages and is removed when the
+ * context is freed or the VM exits.
+ */
+export interface ScratchDisk {
+  /** Size of the image; host space is only used as the guest writes */
+  sizeMib: number
+}
+export const enum DiskFormat {
+  Raw = 'raw',
+  Qcow2 = 'qcow2'
+}
+/** A host port forwarded to a guest port */
+export interface PortMapping {
+  /** Host port, 1-65535; must be unique within a config */
+  hostPort: number
+  /** Guest port, 1-65535 */
+  guestPort: number
+  /** Host address to bind (default: all addresses) */
+  hostAddr?: string
+}
+/**
+ * The guest's network backend
+ *
+ * TSI, libkrun's default, carries the guest's TCP and UDP sockets over
+ * vsock, so tools needing raw sockets or ICMP fail. The other kinds give
+ * the guest a virtio-net device instead:
+ *
+ * - `passt`: the crate starts a passt process for each VM and stops it
+ *   when the VM exits
+ * - `gvproxy`: libkrun connects to gvproxy's vfkit-mode socket
+ * - `unixstream`: the crate connects to a proxy listening on a stream
+ *   socket, such as socket_vmnet
+ * - `unixgram`: the crate connects to a proxy on a datagram socket, such as
+ *   vmnet-helper, from a socket of its own that is removed with the VM
+ * - `nat`: the crate runs a network stack of its own. The guest gets
+ *   10.0.2.15 by DHCP, with the gateway at 10.0.2.2 and DNS at 10.0.2.3,
+ *   and its TCP and UDP leave through host sockets. ICMP only reaches the
+ *   gateway.
+ * - `none`: no network at all. libkrun's implicit vsock device, which TSI
+ *   runs over, is left out too, so the guest only has loopback; with
+ *   `egress` it gets a vsock device without TSI that reaches nothing but
+ *   the egress proxy. Needs a libkrun exporting
+ *   `krun_disable_implicit_vsock` and `krun_add_vsock`, which the vendored
+ *   build does not; older builds fail with `ENOTSUP`.
+ */
+export interface NetworkConfig {
+  kind: NetworkKind
+  /**
+   * passt binary, as a path or a name looked up on `PATH` (default:
+   * `passt`)
+   */
+  binary?: string
+  /**
+   * The proxy's socket; required for `gvproxy`, `unixstream` and
+   * `unixgram`
+   */
+  socketPath?: string
+  /**
+   * Announce the `unixgram` connection as vfkit does, for gvproxy's
+   * vfkit mode (default: false)
+   */
+  vfkit?: boolean
+  /**
+   * MAC address of the guest's interface, as `xx:xx:xx:xx:xx:xx`
+   * (default: `5a:94:ef:e4:0c:ee`)
+   */
+  mac?: string
+  /**
+   * Let `nat` connections to the gateway reach services on the host's
+   * 127.0.0.1 (default: false)
+   */
+  hostLoopback?: boolean
+}
+export const enum NetworkKind {
+  Tsi = 'tsi',
+  Passt = 'passt',
+  Gvproxy = 'gvproxy',
+  Unixstream = 'unixstream',
+  Unixgram = 'unixgram',
+  Nat = 'nat',
+  None = 'none'
+}
+/**
+ * The crate's egress proxy and the hosts it lets the guest reach
+ *
+ * The proxy runs in this process for as long as the context, and the
+ * guest reaches it on vsock port `vsockPort` of the host (CID 2). It takes
+ * `CONNECT` requests, plain HTTP requests in proxy form, and TLS
+ * connections sent to it directly, which go to port 443 of the server they
+ * name. Hosts resolving to loopback or link-local addresses are always
+ * refused, and every refused attempt is recorded for `blockedEgress()`
+ * and appended to `logPath`.
+ *
+ * Only traffic sent through the proxy can be checked, so `egress`
+ * requires `network: { kind: 'none' }`, leaving the proxy the guest's only
+ * way out; any other network, TSI included, would let it go around.
+ */
+export interface EgressConfig {
+  /**
+   * Only hosts matching one of these may be reached (default: any)
+   *
+   * Entries are host names, IP addresses, or `*.example.com` for every
+   * subdomain of `example.com`.
+   */
+  allow?: Array<string>
+  /**
+   * Hosts that may never be reached, in the form of `allow`; they win
+   * over `allow`
+   */
+  deny?: Array<string>
+  /** vsock port the proxy listens on (default: 3128) */
+  vsockPort?: number
+  /**
+   * Host file each refused attempt is appended to as a line of JSON,
+   * in the form of `EgressBlock`; created if missing
+   */
+  logPath?: string
+}
+/** A connection the egress policy refused */
+export interface EgressBlock {
+  /** Host the guest asked for; empty if none could be made out */
+  host: string
+  /** Port the guest asked for; 0 if none could be made out */
+  port: number
+  /** `denied`, `notAllowed`, `localAddress` or `unidentified` */
+  rule: string
+  /** Milliseconds since the Unix epoch */
+  timeMs: number
+}
+/** A problem found by `validateConfig` */
+export interface ConfigProblem {
+  /** Config field at fault, e.g. `memoryMib` or `mounts.workspace` */
+  field: string
+  message: string
+  /** Mount policy rule, if the mount policy refused a mount */
+  rule?: string
+}
 export interface VmInfo {
   ctxId: number
   cid: number
   cpus: number
   memoryMib: number
 }
+/** Why a VM stopped */
+export const enum VmExitReason {
+  /** The guest workload exited on its own */
+  Exited = 'exited',
+  /** The guest exited after the host requested a graceful shutdown */
+  Shutdown = 'shutdown',
+  /** libkrun's init could not set up or run the workload (125-127) */
+  InitFailed = 'initFailed',
+  /** The VM could not be configured or started */
+  StartFailed = 'startFailed',
+  /** The host killed the VM */
+  Killed = 'killed',
+  /** The VMM process died from a signal the host did not send */
+  Crashed = 'crashed'
+}
+/** Lifecycle state of a libkrun context */
+export const enum VmState {
+  /** Context created and VM-level configuration applied */
+  Created = 'created',
+  /** Exec set; the VM can be started */
+  Configured = 'configured',
+  /** Supervisor process running */
+  Running = 'running',
+  /** Supervisor process has exited */
+  Exited = 'exited',
+  /** Context freed; kept so reuse is reported rather than reaching libkrun */
+  Freed = 'freed'
+}
+/** How a VM stopped */
+export interface VmExit {
+  reason: VmExitReason
+  /**
+   * Exit code of the guest init, unless the VMM died from a signal or the
+   * VM never started
+   */
+  exitCode?: number
+  /**
+   * Whether the host killed the VM: a hard stop, a shutdown timeout, or a
+   * shutdown this libkrun cannot pass on to the guest
+   */
+  killedByHost: boolean
+  /** Signal that ended the VMM process */
+  signal?: number
+  /** Wall time from start to exit in milliseconds */
+  wallTimeMs: number
+  /** Why the VM could not be started */
+  error?: string
+}
+export interface ShutdownOptions {
+  /** How long to wait for the guest before killing the VM (default: 10000) */
+  timeoutMs?: number
+}
+export interface RollbackOptions {
+  /**
+   * Roll back even while the VM is running (default: false)
+   *
+   * The guest may still hold cached copies of the old files.
+   */
+  force?: boolean
+}
+/** An unpacked image and the runtime settings from its config */
+export interface UnpackedImage {
+  /** Number of layers applied */
+  layers: number
+  /** Environment as `KEY=value` */
+  env: Array<string>
+  entrypoint: Array<string>
+  cmd: Array<string>
+  workingDir?: string
+  user?: string
+}
+/** A cached image ready to boot */
+export interface CachedRootfs {
+  /** Pass as `rootfsPath`, with `cloneRootfs: true` */
+  path: string
+  /** Chain key of the image's layers */
+  key: string
+  /** Layers found in the cache rather than unpacked */
+  reusedLayers: number
+  image: UnpackedImage
+}
+export interface GcOptions {
+  /**
+   * Evict least recently used entries until the store fits in this many
+   * bytes (default: 0, evicting every unused entry)
+   */
+  keepBytes?: number
+}
+export interface GcResult {
+  /** Entries removed */
+  evicted: number
+  freedBytes: number
+  /** Size of the entries left */
+  keptBytes: number
+}
+/** What a tree entry is */
+export const enum EntryKind {
+  File = 'file',
+  Dir = 'dir',
+  Symlink = 'symlink'
+}
+/** A path that differs between two snapshots */
+export interface TreeChange {
+  /** Path relative to the snapshot root */
+  path: string
+  /** Kind in the later snapshot, or the earlier one for deletions */
+  kind: EntryKind
+  /** Size in the later snapshot, or the earlier one for deletions */
+  size: number
+  /** Size in the earlier snapshot, for modifications */
+  previousSize?: number
+  mode: number
+  /** Mode in the earlier snapshot, when it changed */
+  previousMode?: number
+}
+/** Differences between two snapshots, each list sorted by path */
+export interface TreeDiff {
+  added: Array<TreeChange>
+  /** Contents, symlink target or kind changed */
+  modified: Array<TreeChange>
+  deleted: Array<TreeChange>
+  /** Only the mode changed */
+  modeChanged: Array<TreeChange>
+}
 /** Check if libkrun is available on this system */
 export declare function isAvailable(): boolean
 /** Get libkrun version string */
 export declare function getVersion(): string
-/** Create a new libkrun VM context */
+/**
+ * Check `config` against the host without creating a context
+ *
+ * Verifies `cpus` against the hypervisor's vCPU limit and `memoryMib`
+ * against host memory, checks that `rootfsPath` and every mount are
+ * directories, that mounts pass the mount policy and that disk images
+ * exist, and looks for duplicate virtiofs tags, bad ports and bad
+ * environment keys. Returns every problem found; an empty list means the
+ * config is usable.
+ */
+export declare function validateConfig(config: LibkrunConfig): Array<ConfigProblem>
+/**
+ * Remove rootfs clones left behind by processes that exited without
+ * freeing their contexts, returning how many were removed
+ *
+ * Clones of live contexts are removed when the context is freed; this
+ * only cleans up after crashes. It also runs once before the first clone.
+ */
+export declare function pruneRootfsClones(): number
+/**
+ * Unpack a local OCI image into `destDir` for use as `rootfsPath`
+ *
+ * `layoutOrTarPath` is an OCI image layout directory, a tarball of one, or
+ * a `docker save` tarball; nothing is fetched from a registry. Layers are
+ * applied in order with whiteouts, keeping modes, ownership and xattrs.
+ * `destDir` must be missing or empty, and is left empty on failure.
+ */
+export declare function unpackOciImage(layoutOrTarPath: string, destDir: string): Promise<UnpackedImage>
+/**
+ * Record every entry under `path`, hashing file contents in parallel
+ *
+ * Symlinks are recorded by target rather than followed, and sockets,
+ * FIFOs and device nodes are skipped. Files that disappear while the
+ * snapshot is taken are left out.
+ */
+export declare function snapshotTree(path: string): Promise<TreeSnapshot>
+/** Compare two snapshots, normally of the same directory */
+export declare function diffTree(before: TreeSnapshot, after: TreeSnapshot): TreeDiff
+/**
+ * Create a new libkrun VM context
+ *
+ * @deprecated Use `new VmHandle(config)`, which frees the context automatically
+ */
 export declare function createContext(config: LibkrunConfig): VmInfo
 /**
- * Start the VM (blocking - runs in the current thread)
- * Note: krun_start_enter blocks, so this needs special handling
+ * Start the VM in a supervisor process
+ *
+ * The recorded configuration is handed to the `krun-supervisor` helper,
+ * which calls `krun_start_enter` in its own process so the guest cannot
+ * block or exit the Node process.
+ *
+ * @deprecated Use `VmHandle.start()`
+ */
+export declare function startVm(ctxId: number): VmProcess
+/**
+ * Ask the guest to shut down through libkrun's shutdown eventfd and wait for
+ * it to exit, killing the VM process once `timeoutMs` has elapsed
+ *
+ * Resolves to `true` if the guest exited on its own. The context stays
+ * allocated until `freeContext`.
+ */
+export declare function shutdown(ctxId: number, options?: ShutdownOptions | undefined | null): Promise<boolean>
+/**
+ * Free a VM context, killing its supervisor if the VM is still running
+ *
+ * @deprecated Use `VmHandle.stop()`
  */
-export declare function startVm(ctxId: number): number
-/** Free a VM context */
 export declare function freeContext(ctxId: number): void
-/** Set the executable to run in the VM */
+/**
+ * Set the executable to run in the VM
+ *
+ * `env` is merged over `LibkrunConfig.env`, overriding duplicate keys.
+ *
+ * @deprecated Use `VmHandle.setExec()`
+ */
 export declare function setExec(ctxId: number, execPath: string, args: Array<string>, env: Record<string, string>): void
+/** A VM running in its own supervisor process */
+export declare class VmProcess {
+  /** PID of the supervisor process hosting the VM */
+  get pid(): number
+  /** Resolves once the VM has stopped, describing how it ended */
+  wait(): Promise<VmExit>
+}
+/**
+ * A libkrun context owned by JavaScript
+ *
+ * The context is freed by `stop()`, or when the handle is garbage
+ * collected, so an exception between creation and teardown cannot leak it.
+ */
+export declare class VmHandle {
+  /** Create a new libkrun VM context */
+  constructor(config: LibkrunConfig)
+  /**
+   * Set the executable to run in the VM
+   *
+   * `env` is merged over `LibkrunConfig.env`, overriding duplicate keys.
+   */
+  setExec(execPath: string, args: Array<string>, env: Record<string, string>): void
+  /** Start the VM in a supervisor process */
+  start(): VmProcess
+  /**
+   * Ask the guest to shut down, killing the VM once `timeoutMs` has
+   * elapsed. Resolves to `true` if the guest exited on its own.
+   */
+  shutdown(options?: ShutdownOptions | undefined | null): Promise<boolean>
+  /**
+   * Stop the VM if it is running and free the context
+   *
+   * The handle cannot be used again afterwards; calling `stop()` twice is
+   * a no-op.
+   */
+  stop(): void
+  /**
+   * Save the writable mounts as checkpoint `tag`
+   *
+   * Files are reflinked where the host filesystem supports it, so a
+   * checkpoint is cheap on APFS, btrfs and XFS; elsewhere they
+   * are copied. Checkpoints are removed with the context.
+   */
+  checkpoint(tag: string): Promise<void>
+  /**
+   * Restore the writable mounts from checkpoint `tag`, which is kept
+   *
+   * Refuses while the VM is running unless `force` is set.
+   */
+  rollback(tag: string, options?: RollbackOptions | undefined | null): Promise<void>
+  /** Remove checkpoint `tag`, resolving to whether it existed */
+  dropCheckpoint(tag: string): Promise<boolean>
+  /**
+   * Connections the egress proxy refused, oldest first
+   *
+   * Empty without `LibkrunConfig.egress`. Only the most recent 1024 are
+   * kept; `blockedEgressDropped()` counts the rest, and
+   * `EgressConfig.logPath` gets every one.
+   */
+  blockedEgress(): Array<EgressBlock>
+  /** How many refused connections `blockedEgress()` no longer lists */
+  blockedEgressDropped(): number
+  /** Current lifecycle state of the context */
+  get state(): VmState
+  /** Context, vsock CID and resources of this VM */
+  info(): VmInfo
+}
+/**
+ * A directory of unpacked images, shared by every process using it
+ *
+ * `prepare` unpacks an image into the store, reusing layers it already
+ * holds; `gc` evicts entries no live `VmHandle` was created from.
+ */
+export declare class RootfsStore {
+  /**
+   * Open the store in `dir`, by default the user's cache directory
+   *
+   * The directory is created on first use.
+   */
+  constructor(dir?: string | undefined | null)
+  /** Directory holding the store */
+  get dir(): string
+  /**
+   * Unpack a local OCI image into the store, as `unpackOciImage` does,
+   * unpacking only the layers not already cached
+   *
+   * Layers whose digest the image does not record, as in older
+   * `docker save` tarballs, are hashed first.
+   */
+  prepare(layoutOrTarPath: string): Promise<CachedRootfs>
+  /**
+   * Evict entries no live context was created from, least recently used
+   * first, until the store fits in `keepBytes`
+   *
+   * Also removes entries left half-built by processes that died. Sizes
+   * count data shared with other entries through hardlinks or reflinks
+   * in full, so the store usually takes less space than reported.
+   */
+  gc(options?: GcOptions | undefined | null): Promise<GcResult>
+}
+/** The state of a directory tree at one point in time */
+export declare class TreeSnapshot {
+  /** Directory the snapshot was taken of */
+  get root(): string
+  /** Number of regular files */
+  get fileCount(): number
+  /** Total size of the regular files */
+  get totalBytes(): number
+}
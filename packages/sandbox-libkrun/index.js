@@ -310,11 +310,27 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { isAvailable, getVersion, createContext, startVm, freeContext, setExec } = nativeBinding
+const { VmProcess, VmHandle, RootfsStore, TreeSnapshot, RootKind, DiskFormat, NetworkKind, VmExitReason, VmState, EntryKind, isAvailable, getVersion, validateConfig, pruneRootfsClones, unpackOciImage, snapshotTree, diffTree, createContext, startVm, shutdown, freeContext, setExec } = nativeBinding
 
+module.exports.VmProcess = VmProcess
+module.exports.VmHandle = VmHandle
+module.exports.RootfsStore = RootfsStore
+module.exports.TreeSnapshot = TreeSnapshot
+module.exports.RootKind = RootKind
+module.exports.DiskFormat = DiskFormat
+module.exports.NetworkKind = NetworkKind
+module.exports.VmExitReason = VmExitReason
+module.exports.VmState = VmState
+module.exports.EntryKind = EntryKind
 module.exports.isAvailable = isAvailable
 module.exports.getVersion = getVersion
+module.exports.validateConfig = validateConfig
+module.exports.pruneRootfsClones = pruneRootfsClones
+module.exports.unpackOciImage = unpackOciImage
+module.exports.snapshotTree = snapshotTree
+module.exports.diffTree = diffTree
 module.exports.createContext = createContext
 module.exports.startVm = startVm
+module.exports.shutdown = shutdown
 module.exports.freeContext = freeContext
 module.exports.setExec = setExec
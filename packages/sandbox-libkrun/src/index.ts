@@ -3,5 +3,37 @@ export { LibkrunSandbox } from './sandbox.js';
 export type {
   LibkrunConfig,
   VmInfo,
+  ConfigProblem,
+  RootConfig,
+  Mount,
+  MountPolicy,
+  MountRule,
+  Disk,
+  DiskFormat,
+  ScratchDisk,
+  PortMapping,
+  NetworkConfig,
+  NetworkKind,
+  EgressConfig,
+  EgressRule,
+  EgressBlock,
+  VmProcess,
+  VmExit,
+  VmExitReason,
+  VmState,
+  VmHandle,
+  ShutdownOptions,
+  RollbackOptions,
+  UnpackedImage,
+  CachedRootfs,
+  GcOptions,
+  GcResult,
+  RootfsStore,
+  EntryKind,
+  TreeSnapshot,
+  TreeChange,
+  TreeDiff,
+  KrunError,
   LibkrunNative,
+  LibkrunProviderOptions,
 } from './types.js';
@@ -0,0 +1,190 @@
+//! Supervisor process for a single libkrun VM.
+//!
+//! Spawned by the Node addon with a JSON-encoded `VmSpec` on the first line
+//! of stdin. The spec is replayed against a fresh libkrun context and the VM
+//! is entered with `krun_start_enter`, which takes over this process and
+//! exits with the guest's exit code once the VM shuts down.
+//!
+//! SIGTERM asks the guest to shut down orderly through libkrun's shutdown
+//! eventfd; SIGKILL remains the hard stop. The addon keeps stdin open for as
+//! long as it lives, so EOF means it died: the guest is then asked to shut
+//! down too, and given `PARENT_EXIT_GRACE` before the VM is ended.
+
+#[allow(dead_code)]
+#[path = "../error.rs"]
+mod error;
+#[cfg(target_os = "macos")]
+#[path = "../ffi.rs"]
+mod ffi;
+#[allow(dead_code)]
+#[path = "../marshal.rs"]
+mod marshal;
+#[allow(dead_code)]
+#[path = "../policy.rs"]
+mod policy;
+#[allow(dead_code)]
+#[path = "../spec.rs"]
+mod spec;
+
+/// How long the guest has to shut down once the addon's process is gone;
+/// the default timeout of `shutdown()`
+#[cfg(target_os = "macos")]
+const PARENT_EXIT_GRACE: std::time::Duration = std::time::Duration::from_secs(10);
+
+fn main() {
+    // Keep the status pipe away from anything libkrun spawns, so the parent
+    // sees EOF as soon as this process exits
+    let status = unsafe {
+        use std::os::fd::FromRawFd;
+        if libc::fcntl(spec::STATUS_FD, libc::F_SETFD, libc::FD_CLOEXEC) == 0 {
+            Some(std::fs::File::from_raw_fd(spec::STATUS_FD))
+        } else {
+            None
+        }
+    };
+
+    if let Err(reason) = run() {
+        eprintln!("krun-supervisor: {}", reason);
+        if let Some(mut status) = status {
+            use std::io::Write;
+            let _ = status.write_all(reason.as_bytes());
+        }
+        std::process::exit(1);
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn run() -> Result<(), String> {
+    use ffi::*;
+    use std::io::BufRead;
+
+    // Block SIGTERM before libkrun starts any threads so every thread
+    // inherits the mask and only the shutdown listener ever receives it
+    let sigterm = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+
+    let mut payload = Vec::new();
+    std::io::stdin()
+        .lock()
+        .read_until(b'\n', &mut payload)
+        .map_err(|e| format!("Failed to read VM spec: {}", e))?;
+    let spec: spec::VmSpec =
+        serde_json::from_slice(&payload).map_err(|e| format!("Invalid VM spec: {}", e))?;
+
+    // libkrun uses the network socket in this process only
+    if spec.network.uses_net_fd()
+        && unsafe { libc::fcntl(spec::NET_FD, libc::F_SETFD, libc::FD_CLOEXEC) } < 0
+    {
+        return Err(format!("Network socket missing: {}", std::io::Error::last_os_error()));
+    }
+
+    // Keep the spec pipe to watch the parent, and give the VMM a quiet
+    // stdin instead
+    let parent = unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+    if parent < 0 {
+        return Err(format!("Failed to keep the spec pipe: {}", std::io::Error::last_os_error()));
+    }
+    let devnull = std::fs::File::open("/dev/null").map_err(|e| format!("Failed to open /dev/null: {}", e))?;
+    unsafe {
+        use std::os::fd::AsRawFd;
+        if libc::dup2(devnull.as_raw_fd(), libc::STDIN_FILENO) < 0 {
+            return Err(format!("Failed to redirect stdin: {}", std::io::Error::last_os_error()));
+        }
+    }
+
+    unsafe {
+        let ctx_id = error::check(krun_create_ctx(), "krun_create_ctx", None).map_err(|e| e.to_string())? as u32;
+
+        spec.apply_config(ctx_id).map_err(|e| e.to_string())?;
+        spec.apply_network(ctx_id).map_err(|e| e.to_string())?;
+        if let Some(exec) = &spec.exec {
+            exec.apply(ctx_id).map_err(|e| e.to_string())?;
+        }
+
+        let shutdown_fd = krun_get_shutdown_eventfd(ctx_id);
+        if shutdown_fd >= 0 {
+            spawn_shutdown_listener(sigterm, shutdown_fd)?;
+        } else {
+            // Without the eventfd (libkrun built without EFI support) SIGTERM
+            // falls back to its default action and ends the VM immediately
+            eprintln!("krun-supervisor: graceful shutdown unavailable ({})", shutdown_fd);
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &sigterm, std::ptr::null_mut());
+        }
+        spawn_parent_watch(parent, Some(shutdown_fd).filter(|fd| *fd >= 0))?;
+
+        // Only returns if the VM could not be started
+        let result = krun_start_enter(ctx_id);
+        Err(error::check(result, "krun_start_enter", None)
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| format!("krun_start_enter returned {}", result)))
+    }
+}
+
+/// Forward SIGTERM to the guest by signalling libkrun's shutdown eventfd
+#[cfg(target_os = "macos")]
+fn spawn_shutdown_listener(sigterm: libc::sigset_t, shutdown_fd: i32) -> Result<(), String> {
+    std::thread::Builder::new()
+        .name("shutdown".to_string())
+        .spawn(move || loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&sigterm, &mut signal) } != 0 || signal != libc::SIGTERM {
+                continue;
+            }
+            request_shutdown(shutdown_fd);
+        })
+        .map(|_| ())
+        .map_err(|e| format!("Failed to spawn shutdown listener: {}", e))
+}
+
+/// End the VM once `parent`, the addon's end of the spec pipe, reports EOF
+///
+/// The guest is asked to shut down through `shutdown_fd` when there is one
+/// and given `PARENT_EXIT_GRACE`; libkrun exits this process first if it
+/// does.
+#[cfg(target_os = "macos")]
+fn spawn_parent_watch(parent: i32, shutdown_fd: Option<i32>) -> Result<(), String> {
+    std::thread::Builder::new()
+        .name("parent-watch".to_string())
+        .spawn(move || {
+            let mut byte = 0u8;
+            loop {
+                let read = unsafe { libc::read(parent, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+                let interrupted = std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted;
+                if read == 0 || (read < 0 && !interrupted) {
+                    break;
+                }
+            }
+            eprintln!("krun-supervisor: parent process exited, stopping the VM");
+            if let Some(shutdown_fd) = shutdown_fd {
+                request_shutdown(shutdown_fd);
+                std::thread::sleep(PARENT_EXIT_GRACE);
+            }
+            unsafe { libc::_exit(1) };
+        })
+        .map(|_| ())
+        .map_err(|e| format!("Failed to spawn parent watch: {}", e))
+}
+
+/// Ask the guest to shut down through libkrun's shutdown eventfd
+#[cfg(target_os = "macos")]
+fn request_shutdown(shutdown_fd: i32) {
+    let value: u64 = 1;
+    unsafe {
+        libc::write(
+            shutdown_fd,
+            &value as *const u64 as *const libc::c_void,
+            std::mem::size_of::<u64>(),
+        );
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn run() -> Result<(), String> {
+    Err("libkrun is only available on macOS".to_string())
+}
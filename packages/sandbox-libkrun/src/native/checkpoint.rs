@@ -0,0 +1,245 @@
+//! Named checkpoints of a context's writable mounts.
+//!
+//! A checkpoint is a [`rootfs`] clone of each writable mount's host
+//! directory, so it is reflinked where the filesystem allows and copied
+//! otherwise. It never shares an inode with the mount, so nothing the
+//! guest does to the mount reaches a checkpoint.
+//!
+//! Rolling back replaces the contents of each mount directory with a clone
+//! of the checkpoint, keeping the checkpoint for later rollbacks. The
+//! checkpoints of a context are removed with it; those left behind by a
+//! process that died are removed by [`prune`].
+
+use crate::rootfs;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Once;
+
+static NEXT_SET: AtomicU32 = AtomicU32::new(0);
+
+/// The checkpoints of one context, removed on drop
+#[derive(Debug, Default)]
+pub struct Checkpoints {
+    /// Created with the first checkpoint
+    dir: Option<PathBuf>,
+    /// Directory of each checkpoint, holding one clone per mount
+    saved: BTreeMap<String, PathBuf>,
+    next: u32,
+}
+
+impl Checkpoints {
+    pub fn contains(&self, tag: &str) -> bool {
+        self.saved.contains_key(tag)
+    }
+
+    /// Save `mounts` as `tag`, which must not already exist
+    pub fn create(&mut self, tag: &str, mounts: &[PathBuf]) -> io::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => {
+                static PRUNE: Once = Once::new();
+                PRUNE.call_once(|| {
+                    let _ = prune();
+                });
+
+                let parent = checkpoints_dir();
+                // Mounts may hold anything, so keep the copies to this user
+                fs::DirBuilder::new().recursive(true).mode(0o700).create(&parent)?;
+                let name = format!("{}-{}", std::process::id(), NEXT_SET.fetch_add(1, Ordering::Relaxed));
+                let dir = parent.join(name);
+                fs::create_dir(&dir)?;
+                self.dir.insert(dir).clone()
+            }
+        };
+
+        let path = dir.join(self.next.to_string());
+        self.next += 1;
+        fs::create_dir(&path)?;
+        for (i, mount) in mounts.iter().enumerate() {
+            if let Err(err) = rootfs::clone_into(mount, &path.join(i.to_string())) {
+                let _ = rootfs::remove_tree(&path);
+                return Err(err);
+            }
+        }
+        self.saved.insert(tag.to_string(), path);
+        Ok(())
+    }
+
+    /// Restore `mounts` from `tag`; returns false if there is no such
+    /// checkpoint
+    pub fn rollback(&self, tag: &str, mounts: &[PathBuf]) -> io::Result<bool> {
+        let Some(path) = self.saved.get(tag) else {
+            return Ok(false);
+        };
+        for (i, mount) in mounts.iter().enumerate() {
+            rootfs::restore_into(&path.join(i.to_string()), mount)?;
+        }
+        Ok(true)
+    }
+
+    /// Remove `tag`; returns false if there is no such checkpoint
+    pub fn remove(&mut self, tag: &str) -> io::Result<bool> {
+        match self.saved.remove(tag) {
+            Some(path) => rootfs::remove_tree(&path).map(|_| true),
+            None => Ok(false),
+        }
+    }
+}
+
+impl Drop for Checkpoints {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = rootfs::remove_tree(dir);
+        }
+    }
+}
+
+/// Remove checkpoints whose owning process has exited, returning how many
+/// contexts' worth were removed
+pub fn prune() -> io::Result<u32> {
+    let entries = match fs::read_dir(checkpoints_dir()) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(rootfs::orphaned) {
+            rootfs::remove_tree(&entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Directory holding this user's checkpoints, one `<pid>-<n>` directory
+/// per context
+fn checkpoints_dir() -> PathBuf {
+    rootfs::owned_dir("checkpoints")
+}
+
+/// Host directories of the mounts a guest can write to
+pub fn writable_mounts(mounts: &[crate::spec::MountSpec]) -> Vec<PathBuf> {
+    mounts
+        .iter()
+        .filter(|mount| !mount.read_only)
+        .map(|mount| PathBuf::from(&mount.host_path))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, TempDir};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mount(tmp: &TempDir) -> PathBuf {
+        let mount = tmp.join("mount");
+        write(&mount.join("state"), "saved");
+        write(&mount.join("locked"), "saved");
+        fs::set_permissions(mount.join("locked"), fs::Permissions::from_mode(0o444)).unwrap();
+        mount
+    }
+
+    #[test]
+    fn rollback_restores_the_checkpoint() {
+        let tmp = TempDir::new();
+        let mounts = vec![mount(&tmp)];
+        let mut checkpoints = Checkpoints::default();
+        checkpoints.create("a", &mounts).unwrap();
+        assert!(checkpoints.contains("a"));
+
+        fs::write(mounts[0].join("state"), "changed").unwrap();
+        write(&mounts[0].join("new/file"), "new");
+        assert!(checkpoints.rollback("a", &mounts).unwrap());
+        assert_eq!(fs::read_to_string(mounts[0].join("state")).unwrap(), "saved");
+        assert!(!mounts[0].join("new").exists());
+
+        // Kept, so a second rollback works too
+        fs::remove_file(mounts[0].join("state")).unwrap();
+        assert!(checkpoints.rollback("a", &mounts).unwrap());
+        assert_eq!(fs::read_to_string(mounts[0].join("state")).unwrap(), "saved");
+        assert!(!checkpoints.rollback("b", &mounts).unwrap());
+    }
+
+    #[test]
+    fn guest_writes_do_not_reach_the_checkpoint() {
+        let tmp = TempDir::new();
+        let mounts = vec![mount(&tmp)];
+        let mut checkpoints = Checkpoints::default();
+        checkpoints.create("a", &mounts).unwrap();
+
+        // Make a read-only file writable and change it in place, as a
+        // guest owning the mount can
+        let locked = mounts[0].join("locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(&locked, "changed").unwrap();
+        assert!(checkpoints.rollback("a", &mounts).unwrap());
+        assert_eq!(fs::read_to_string(&locked).unwrap(), "saved");
+
+        // Nor do changes made after a rollback
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(&locked, "changed again").unwrap();
+        assert!(checkpoints.rollback("a", &mounts).unwrap());
+        assert_eq!(fs::read_to_string(&locked).unwrap(), "saved");
+    }
+
+    #[test]
+    fn checkpoints_clone_each_mount() {
+        let tmp = TempDir::new();
+        let mounts = vec![mount(&tmp), tmp.join("other")];
+        write(&mounts[1].join("state"), "other");
+        let mut checkpoints = Checkpoints::default();
+        checkpoints.create("a", &mounts).unwrap();
+        fs::write(mounts[0].join("state"), "b").unwrap();
+        checkpoints.create("b", &mounts).unwrap();
+
+        fs::write(mounts[0].join("state"), "changed").unwrap();
+        fs::write(mounts[1].join("state"), "changed").unwrap();
+        assert!(checkpoints.rollback("b", &mounts).unwrap());
+        assert_eq!(fs::read_to_string(mounts[0].join("state")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(mounts[1].join("state")).unwrap(), "other");
+        assert!(checkpoints.rollback("a", &mounts).unwrap());
+        assert_eq!(fs::read_to_string(mounts[0].join("state")).unwrap(), "saved");
+    }
+
+    #[test]
+    fn remove_and_drop_delete_the_copies() {
+        let tmp = TempDir::new();
+        let mounts = vec![mount(&tmp)];
+        let mut checkpoints = Checkpoints::default();
+        checkpoints.create("a", &mounts).unwrap();
+        checkpoints.create("b", &mounts).unwrap();
+        let dir = checkpoints.dir.clone().unwrap();
+        assert!(dir.starts_with(checkpoints_dir()));
+        let a = checkpoints.saved["a"].clone();
+
+        assert!(checkpoints.remove("a").unwrap());
+        assert!(!a.exists());
+        assert!(!checkpoints.contains("a"));
+        assert!(!checkpoints.remove("a").unwrap());
+        assert!(!checkpoints.rollback("a", &mounts).unwrap());
+
+        drop(checkpoints);
+        assert!(!dir.exists());
+        assert_eq!(fs::read_to_string(mounts[0].join("state")).unwrap(), "saved");
+    }
+
+    #[test]
+    fn only_writable_mounts_are_saved() {
+        let mount = |host_path: &str, read_only| crate::spec::MountSpec {
+            tag: host_path.to_string(),
+            host_path: host_path.to_string(),
+            guest_path: None,
+            read_only,
+            shm_size_mib: None,
+        };
+        let mounts = [mount("/a", false), mount("/b", true), mount("/c", false)];
+        assert_eq!(writable_mounts(&mounts), [PathBuf::from("/a"), PathBuf::from("/c")]);
+    }
+}
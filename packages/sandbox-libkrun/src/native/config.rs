@@ -0,0 +1,630 @@
+//! Configuration objects accepted from JavaScript and their conversion into
+//! a [`VmSpec`].
+//!
+//! Besides the `#[napi(object)]` attributes this module is napi-free, so the
+//! fuzz harness can build it with napi-derive's `noop` feature.
+
+use crate::error::KrunError;
+use crate::policy;
+use crate::spec::{self, VmSpec};
+use crate::validate;
+use napi_derive::napi;
+use std::collections::HashMap;
+
+#[napi(object)]
+pub struct LibkrunConfig {
+    /// Number of virtual CPUs
+    pub cpus: Option<u8>,
+    /// Memory in MiB
+    pub memory_mib: Option<u32>,
+    /// Root filesystem path; exclusive with `root`
+    pub rootfs_path: Option<String>,
+    /// Disk image to boot from instead of `rootfsPath`
+    pub root: Option<RootConfig>,
+    /// Boot from a private clone of `rootfsPath` instead of the directory
+    /// itself, so the guest's changes stay out of the base and other VMs
+    ///
+    /// The clone is reflinked where the filesystem supports it and removed
+    /// when the context is freed.
+    pub clone_rootfs: Option<bool>,
+    /// Working directory inside VM
+    pub workdir: Option<String>,
+    /// virtiofs mounts, as a list of `Mount`s or the older
+    /// `{ tag: hostPath }` map
+    pub mounts: Option<Mounts>,
+    /// Which host paths `mounts`, `rootfsPath`, `root` and `disks` may
+    /// share
+    ///
+    /// Omitted, any path may be shared except ones exposing credential
+    /// stores such as `~/.ssh`.
+    pub mount_policy: Option<MountPolicy>,
+    /// Disk images attached as virtio-blk devices, in order
+    pub disks: Option<Vec<Disk>>,
+    /// Empty disk for the guest's scratch data, removed with the VM
+    pub scratch_disk: Option<ScratchDisk>,
+    /// How the guest reaches the network (default: TSI)
+    pub network: Option<NetworkConfig>,
+    /// Ports forwarded from the host to the guest
+    ///
+    /// Omitted, libkrun exposes every port the guest listens on; an empty
+    /// list exposes none. With passt, omitted forwards no ports. The other
+    /// network kinds leave forwarding to their proxy.
+    pub port_map: Option<Vec<PortMapping>>,
+    /// Route the guest's outbound connections through the crate's egress
+    /// proxy, which only lets allowed hosts through
+    pub egress: Option<EgressConfig>,
+    /// Environment variables for the guest workload
+    ///
+    /// `setExec` merges its own `env` on top of these, with its values
+    /// winning for duplicate keys. Keys must not contain `=` or NUL.
+    pub env: Option<HashMap<String, String>>,
+}
+
+/// A root filesystem on a raw disk image
+///
+/// The image is attached as block device `root`, ahead of any `disks`, and
+/// libkrun's init mounts it over a placeholder virtiofs root. Large images
+/// run much faster this way than shared over virtiofs.
+#[napi(object)]
+pub struct RootConfig {
+    pub kind: RootKind,
+    /// Host path of the raw image; it is attached read-write
+    pub path: String,
+    /// Guest device holding the root filesystem, e.g. `/dev/vda1`
+    pub device: String,
+    /// Filesystem type, e.g. `ext4` (default: auto-detect)
+    pub fstype: Option<String>,
+    /// Comma-separated mount options
+    pub options: Option<String>,
+}
+
+#[napi(string_enum = "lowercase")]
+pub enum RootKind {
+    Disk,
+}
+
+/// `mounts` in either of the forms JavaScript may pass
+pub enum Mounts {
+    /// `{ tag: hostPath }`, mounted in tag order
+    Map(HashMap<String, String>),
+    /// Mounted in order
+    List(Vec<Mount>),
+}
+
+/// A host directory shared with the guest over virtio-fs
+#[napi(object)]
+pub struct Mount {
+    /// virtio-fs tag, at most 36 bytes; must be unique within a config
+    pub tag: String,
+    /// Host directory to share
+    pub host_path: String,
+    /// Where to mount the tag in the guest before the workload starts
+    ///
+    /// The mount is done by a `/bin/sh` wrapper around the exec, so the
+    /// guest needs `sh`, `mkdir` and `mount`. Omitted, mounting is left to
+    /// the guest.
+    pub guest_path: Option<String>,
+    /// Mount read-only in the guest (default: false); requires `guestPath`
+    ///
+    /// libkrun's virtio-fs has no read-only mode, so this only protects
+    /// against mistakes: a guest with root can remount the tag writable.
+    pub read_only: Option<bool>,
+    /// Size of the DAX shared memory window (default: libkrun's)
+    pub shm_size_mib: Option<u32>,
+}
+
+/// Rules for the host paths a VM is given: the directories of `mounts`
+/// and `rootfsPath`, and the images of `root` and `disks`
+///
+/// Paths are compared after resolving symlinks, and the resolved path is
+/// what gets attached, so a symlink changed later cannot redirect it. A
+/// path that breaks a rule is reported as an `ERR_MOUNT_DENIED` error
+/// naming the rule.
+#[napi(object)]
+pub struct MountPolicy {
+    /// Only directories inside one of these may be mounted (default: any)
+    pub allow: Option<Vec<String>>,
+    /// Directories that may neither be mounted, nor shared by mounting a
+    /// directory containing them
+    pub deny: Option<Vec<String>>,
+    /// Permit mounts exposing credential stores in the home directory, such
+    /// as `~/.ssh`, `~/.aws` or `~/.config/gcloud` (default: false)
+    pub allow_sensitive: Option<bool>,
+}
+
+/// A disk image attached to the VM
+#[napi(object)]
+pub struct Disk {
+    /// Partition identifier; must be unique within a config
+    pub block_id: String,
+    /// Host path of the image
+    pub path: String,
+    /// Image format; never probed from the image contents
+    pub format: DiskFormat,
+    /// Attach read-only (default: false)
+    pub read_only: Option<bool>,
+}
+
+/// A sparse raw image made for one context
+///
+/// The image has no filesystem on it, and is attached as block device
+/// `scratch` after a disk root and any `disks`, so it is the last one. It
+/// lives in a temporary directory the crate manages and is removed when the
+/// context is freed or the VM exits.
+#[napi(object)]
+pub struct ScratchDisk {
+    /// Size of the image; host space is only used as the guest writes
+    pub size_mib: u32,
+}
+
+#[napi(string_enum = "lowercase")]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+impl From<DiskFormat> for spec::DiskFormat {
+    fn from(format: DiskFormat) -> Self {
+        match format {
+            DiskFormat::Raw => spec::DiskFormat::Raw,
+            DiskFormat::Qcow2 => spec::DiskFormat::Qcow2,
+        }
+    }
+}
+
+/// A host port forwarded to a guest port
+#[napi(object)]
+pub struct PortMapping {
+    /// Host port, 1-65535; must be unique within a config
+    pub host_port: u32,
+    /// Guest port, 1-65535
+    pub guest_port: u32,
+    /// Host address to bind (default: all addresses)
+    pub host_addr: Option<String>,
+}
+
+/// The guest's network backend
+///
+/// TSI, libkrun's default, carries the guest's TCP and UDP sockets over
+/// vsock, so tools needing raw sockets or ICMP fail. The other kinds give
+/// the guest a virtio-net device instead:
+///
+/// - `passt`: the crate starts a passt process for each VM and stops it
+///   when the VM exits
+/// - `gvproxy`: libkrun connects to gvproxy's vfkit-mode socket
+/// - `unixstream`: the crate connects to a proxy listening on a stream
+///   socket, such as socket_vmnet
+/// - `unixgram`: the crate connects to a proxy on a datagram socket, such as
+///   vmnet-helper, from a socket of its own that is removed with the VM
+/// - `nat`: the crate runs a network stack of its own. The guest gets
+///   10.0.2.15 by DHCP, with the gateway at 10.0.2.2 and DNS at 10.0.2.3,
+///   and its TCP and UDP leave through host sockets. ICMP only reaches the
+///   gateway.
+/// - `none`: no network at all. libkrun's implicit vsock device, which TSI
+///   runs over, is left out too, so the guest only has loopback; with
+///   `egress` it gets a vsock device without TSI that reaches nothing but
+///   the egress proxy. Needs a libkrun exporting
+///   `krun_disable_implicit_vsock` and `krun_add_vsock`, which the vendored
+///   build does not; older builds fail with `ENOTSUP`.
+#[napi(object)]
+pub struct NetworkConfig {
+    pub kind: NetworkKind,
+    /// passt binary, as a path or a name looked up on `PATH` (default:
+    /// `passt`)
+    pub binary: Option<String>,
+    /// The proxy's socket; required for `gvproxy`, `unixstream` and
+    /// `unixgram`
+    pub socket_path: Option<String>,
+    /// Announce the `unixgram` connection as vfkit does, for gvproxy's
+    /// vfkit mode (default: false)
+    pub vfkit: Option<bool>,
+    /// MAC address of the guest's interface, as `xx:xx:xx:xx:xx:xx`
+    /// (default: `5a:94:ef:e4:0c:ee`)
+    pub mac: Option<String>,
+    /// Let `nat` connections to the gateway reach services on the host's
+    /// 127.0.0.1 (default: false)
+    pub host_loopback: Option<bool>,
+}
+
+#[napi(string_enum = "lowercase")]
+pub enum NetworkKind {
+    Tsi,
+    Passt,
+    Gvproxy,
+    Unixstream,
+    Unixgram,
+    Nat,
+    None,
+}
+
+/// The crate's egress proxy and the hosts it lets the guest reach
+///
+/// The proxy runs in this process for as long as the context, and the
+/// guest reaches it on vsock port `vsockPort` of the host (CID 2). It takes
+/// `CONNECT` requests, plain HTTP requests in proxy form, and TLS
+/// connections sent to it directly, which go to port 443 of the server they
+/// name. Hosts resolving to loopback or link-local addresses are always
+/// refused, and every refused attempt is recorded for `blockedEgress()`
+/// and appended to `logPath`.
+///
+/// Only traffic sent through the proxy can be checked, so `egress`
+/// requires `network: { kind: 'none' }`, leaving the proxy the guest's only
+/// way out; any other network, TSI included, would let it go around.
+#[napi(object)]
+pub struct EgressConfig {
+    /// Only hosts matching one of these may be reached (default: any)
+    ///
+    /// Entries are host names, IP addresses, or `*.example.com` for every
+    /// subdomain of `example.com`.
+    pub allow: Option<Vec<String>>,
+    /// Hosts that may never be reached, in the form of `allow`; they win
+    /// over `allow`
+    pub deny: Option<Vec<String>>,
+    /// vsock port the proxy listens on (default: 3128)
+    pub vsock_port: Option<u32>,
+    /// Host file each refused attempt is appended to as a line of JSON,
+    /// in the form of `EgressBlock`; created if missing
+    pub log_path: Option<String>,
+}
+
+impl LibkrunConfig {
+    /// Build the spec for this config, collecting everything wrong with it
+    pub fn into_spec(self) -> (VmSpec, Vec<KrunError>) {
+        let mut problems = Vec::new();
+
+        let mut mounts = match self.mounts {
+            None => Vec::new(),
+            Some(Mounts::Map(map)) => {
+                let mut mounts: Vec<spec::MountSpec> = map
+                    .into_iter()
+                    .map(|(tag, host_path)| spec::MountSpec {
+                        tag,
+                        host_path,
+                        guest_path: None,
+                        read_only: false,
+                        shm_size_mib: None,
+                    })
+                    .collect();
+                mounts.sort_by(|a, b| a.tag.cmp(&b.tag));
+                mounts
+            }
+            Some(Mounts::List(list)) => list
+                .into_iter()
+                .map(|mount| spec::MountSpec {
+                    tag: mount.tag,
+                    host_path: mount.host_path,
+                    guest_path: mount.guest_path,
+                    read_only: mount.read_only.unwrap_or(false),
+                    shm_size_mib: mount.shm_size_mib,
+                })
+                .collect(),
+        };
+        let mount_policy = self.mount_policy.unwrap_or(MountPolicy {
+            allow: None,
+            deny: None,
+            allow_sensitive: None,
+        });
+        let mount_policy = policy::MountPolicy::new(
+            mount_policy.allow,
+            mount_policy.deny,
+            mount_policy.allow_sensitive.unwrap_or(false),
+            &mut problems,
+        );
+        for mount in &mut mounts {
+            confine(&mount_policy, &format!("mounts.{}", mount.tag), &mut mount.host_path, &mut problems);
+        }
+
+        let mut env: Vec<(String, String)> = self.env.unwrap_or_default().into_iter().collect();
+        env.sort();
+        problems.extend(spec::env_problems(&env));
+        let mut disks: Vec<spec::DiskSpec> = self
+            .disks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|disk| spec::DiskSpec {
+                block_id: disk.block_id,
+                path: disk.path,
+                format: disk.format.into(),
+                read_only: disk.read_only.unwrap_or(false),
+            })
+            .collect();
+        for disk in &mut disks {
+            confine(&mount_policy, &format!("disks.{}", disk.block_id), &mut disk.path, &mut problems);
+        }
+        let mut root = match (self.rootfs_path, self.root) {
+            (Some(path), Some(_)) => {
+                problems.push(KrunError::invalid("root", "rootfsPath and root are mutually exclusive"));
+                spec::RootSpec::Dir(path)
+            }
+            (Some(path), None) => spec::RootSpec::Dir(path),
+            (None, Some(root)) => match root.kind {
+                RootKind::Disk => spec::RootSpec::Disk {
+                    path: root.path,
+                    device: root.device,
+                    fstype: root.fstype,
+                    options: root.options,
+                },
+            },
+            // Reported by validate::problems
+            (None, None) => spec::RootSpec::default(),
+        };
+        // The root directory is shared read-write like any mount
+        match &mut root {
+            spec::RootSpec::Dir(path) => confine(&mount_policy, "rootfsPath", path, &mut problems),
+            spec::RootSpec::Disk { path, .. } => confine(&mount_policy, "root.path", path, &mut problems),
+        }
+        if self.clone_rootfs == Some(true) && !matches!(root, spec::RootSpec::Dir(_)) {
+            problems.push(KrunError::invalid("cloneRootfs", "only applies to rootfsPath"));
+        }
+        let (network, mac) = convert_network(self.network, &mut problems);
+        let port_map = self
+            .port_map
+            .map(|port_map| convert_port_map(port_map, &network, &mut problems));
+        let egress = self.egress.map(|egress| {
+            let vsock_port = egress.vsock_port.unwrap_or(3128);
+            if vsock_port == 0 {
+                problems.push(KrunError::invalid("egress.vsockPort", "must be at least 1"));
+            }
+            spec::EgressSpec {
+                policy: policy::EgressPolicy::new(egress.allow, egress.deny, &mut problems),
+                vsock_port,
+                socket_path: String::new(),
+                log_path: egress.log_path,
+            }
+        });
+
+        let spec = VmSpec {
+            cpus: self.cpus.unwrap_or(1),
+            memory_mib: self.memory_mib.unwrap_or(512),
+            root,
+            workdir: self.workdir,
+            mounts,
+            disks,
+            scratch_disk: self.scratch_disk.map(|scratch| spec::ScratchDiskSpec {
+                size_mib: scratch.size_mib,
+                path: String::new(),
+            }),
+            network,
+            mac,
+            port_map,
+            egress,
+            env,
+            exec: None,
+        };
+        problems.extend(validate::problems(&spec));
+        (spec, problems)
+    }
+}
+
+/// Check `path` against `policy`, replacing it with the canonical path
+/// that was checked so a symlink swapped in afterwards cannot redirect it
+fn confine(policy: &policy::MountPolicy, field: &str, path: &mut String, problems: &mut Vec<KrunError>) {
+    match policy.check(field, path) {
+        Ok(Some(canonical)) => *path = canonical,
+        Ok(None) => {}
+        Err(problem) => problems.push(problem),
+    }
+}
+
+fn convert_network(
+    network: Option<NetworkConfig>,
+    problems: &mut Vec<KrunError>,
+) -> (spec::NetworkSpec, Option<[u8; 6]>) {
+    let Some(network) = network else {
+        return (spec::NetworkSpec::Tsi, None);
+    };
+
+    // A missing path is reported by validate::problems
+    let socket_path = || network.socket_path.clone().unwrap_or_default();
+    let spec = match network.kind {
+        NetworkKind::Tsi => spec::NetworkSpec::Tsi,
+        NetworkKind::None => spec::NetworkSpec::None,
+        NetworkKind::Passt => spec::NetworkSpec::Passt {
+            binary: network.binary.clone().unwrap_or_else(|| "passt".to_string()),
+        },
+        NetworkKind::Gvproxy => spec::NetworkSpec::Gvproxy {
+            socket_path: socket_path(),
+        },
+        NetworkKind::Unixstream => spec::NetworkSpec::UnixStream {
+            socket_path: socket_path(),
+        },
+        NetworkKind::Unixgram => spec::NetworkSpec::UnixGram {
+            socket_path: socket_path(),
+            vfkit: network.vfkit.unwrap_or(false),
+        },
+        NetworkKind::Nat => spec::NetworkSpec::Nat {
+            host_loopback: network.host_loopback.unwrap_or(false),
+        },
+    };
+
+    let kind = &spec;
+    if network.binary.is_some() && !matches!(kind, spec::NetworkSpec::Passt { .. }) {
+        problems.push(KrunError::invalid("network.binary", "only applies to passt"));
+    }
+    if network.socket_path.is_some() && kind.socket_path().is_none() {
+        problems.push(KrunError::invalid(
+            "network.socketPath",
+            "only applies to gvproxy, unixstream and unixgram",
+        ));
+    }
+    if network.vfkit.is_some() && !matches!(kind, spec::NetworkSpec::UnixGram { .. }) {
+        problems.push(KrunError::invalid("network.vfkit", "only applies to unixgram"));
+    }
+    if network.host_loopback.is_some() && !matches!(kind, spec::NetworkSpec::Nat { .. }) {
+        problems.push(KrunError::invalid("network.hostLoopback", "only applies to nat"));
+    }
+    let mac = network.mac.and_then(|mac| {
+        if matches!(kind, spec::NetworkSpec::Tsi | spec::NetworkSpec::None) {
+            problems.push(KrunError::invalid(
+                "network.mac",
+                "needs a network kind other than tsi and none",
+            ));
+            return None;
+        }
+        let parsed = parse_mac(&mac);
+        match parsed {
+            Some(parsed) if parsed[0] & 1 != 0 => {
+                problems.push(KrunError::invalid("network.mac", format!("{} is a multicast address", mac)));
+            }
+            Some(_) => {}
+            None => problems.push(KrunError::invalid(
+                "network.mac",
+                format!("{:?} is not of the form xx:xx:xx:xx:xx:xx", mac),
+            )),
+        }
+        parsed
+    });
+    (spec, mac)
+}
+
+/// Six colon-separated pairs of hex digits
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut octets = [0; 6];
+    let mut parts = mac.split(':');
+    for octet in &mut octets {
+        let part = parts.next().filter(|part| part.len() == 2)?;
+        *octet = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(octets)
+}
+
+/// Convert the port map of a config, leaving out invalid entries
+fn convert_port_map(
+    port_map: Vec<PortMapping>,
+    network: &spec::NetworkSpec,
+    problems: &mut Vec<KrunError>,
+) -> Vec<spec::PortMapping> {
+    fn port(value: u32, what: &str) -> Result<u16, KrunError> {
+        u16::try_from(value)
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| KrunError::invalid("portMap", format!("{} {} is not in 1-65535", what, value)))
+    }
+
+    let convert = |mapping: PortMapping| {
+        let host_addr = mapping
+            .host_addr
+            .map(|addr| {
+                addr.parse()
+                    .map_err(|_| KrunError::invalid("portMap", format!("invalid hostAddr {:?}", addr)))
+            })
+            .transpose()?;
+        Ok(spec::PortMapping {
+            host_port: port(mapping.host_port, "hostPort")?,
+            guest_port: port(mapping.guest_port, "guestPort")?,
+            host_addr,
+        })
+    };
+
+    let mut converted = Vec::new();
+    for mapping in port_map {
+        match convert(mapping) {
+            Ok(mapping) => converted.push(mapping),
+            Err(problem) => problems.push(problem),
+        }
+    }
+    problems.extend(spec::port_map_problems(&converted, network));
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, TempDir};
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    fn config() -> LibkrunConfig {
+        LibkrunConfig {
+            cpus: None,
+            memory_mib: None,
+            rootfs_path: None,
+            root: None,
+            clone_rootfs: None,
+            workdir: None,
+            mounts: None,
+            mount_policy: None,
+            disks: None,
+            scratch_disk: None,
+            network: None,
+            port_map: None,
+            egress: None,
+            env: None,
+        }
+    }
+
+    fn denials(problems: &[KrunError]) -> Vec<(&str, &str)> {
+        problems
+            .iter()
+            .filter_map(|problem| Some((problem.field()?, problem.rule()?)))
+            .collect()
+    }
+
+    #[test]
+    fn home_as_rootfs_path_is_refused() {
+        let home = std::env::var("HOME").unwrap();
+        let (_, problems) = LibkrunConfig {
+            rootfs_path: Some(home),
+            ..config()
+        }
+        .into_spec();
+        assert_eq!(denials(&problems), [("rootfsPath", "sensitive")]);
+    }
+
+    #[test]
+    fn root_and_disk_images_follow_the_mount_policy() {
+        let tmp = TempDir::new();
+        write(&tmp.join("private/root.img"), "");
+        write(&tmp.join("public/data.img"), "");
+        symlink(tmp.join("private/root.img"), tmp.join("public/root.img")).unwrap();
+        symlink(tmp.join("public/data.img"), tmp.join("data-link.img")).unwrap();
+        let path = |name: &str| tmp.join(name).to_str().unwrap().to_string();
+        let disk = |block_id: &str, name: &str| Disk {
+            block_id: block_id.to_string(),
+            path: path(name),
+            format: DiskFormat::Raw,
+            read_only: None,
+        };
+
+        let (spec, problems) = LibkrunConfig {
+            root: Some(RootConfig {
+                kind: RootKind::Disk,
+                path: path("public/root.img"),
+                device: "/dev/vda".to_string(),
+                fstype: None,
+                options: None,
+            }),
+            disks: Some(vec![disk("secret", "private/root.img"), disk("data", "data-link.img")]),
+            mount_policy: Some(MountPolicy {
+                allow: None,
+                deny: Some(vec![path("private")]),
+                allow_sensitive: None,
+            }),
+            ..config()
+        }
+        .into_spec();
+        assert_eq!(denials(&problems), [("disks.secret", "denied"), ("root.path", "denied")]);
+        // The checked path is the one attached
+        assert_eq!(spec.disks[1].path, path("public/data.img"));
+    }
+
+    #[test]
+    fn rootfs_path_is_attached_in_its_canonical_form() {
+        let tmp = TempDir::new();
+        std::fs::create_dir_all(tmp.join("image/rootfs")).unwrap();
+        symlink(tmp.join("image/rootfs"), tmp.join("current")).unwrap();
+        let (spec, problems) = LibkrunConfig {
+            rootfs_path: Some(tmp.join("current").to_str().unwrap().to_string()),
+            mount_policy: Some(MountPolicy {
+                allow: Some(vec![tmp.join("image").to_str().unwrap().to_string()]),
+                deny: None,
+                allow_sensitive: None,
+            }),
+            ..config()
+        }
+        .into_spec();
+        assert!(denials(&problems).is_empty());
+        let spec::RootSpec::Dir(path) = spec.root else { panic!("not a directory root") };
+        assert_eq!(Path::new(&path), std::fs::canonicalize(tmp.join("image/rootfs")).unwrap());
+    }
+}
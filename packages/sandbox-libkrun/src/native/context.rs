@@ -0,0 +1,364 @@
+//! Context operations shared by the free-function API and [`VmHandle`].
+//!
+//! [`VmHandle`]: crate::handle::VmHandle
+
+use crate::checkpoint::{self, Checkpoints};
+use crate::egress::{EgressBlock, EgressProxy};
+use crate::error::KrunError;
+use crate::handle::VmProcess;
+use crate::registry::{self, Action, Backing, VmState};
+use crate::rootfs::RootfsClone;
+use crate::scratch::ScratchImage;
+use crate::spec::{self, ExecSpec, NetworkSpec, RootSpec};
+use crate::{net, store, supervisor, LibkrunConfig, VmInfo};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[cfg(target_os = "macos")]
+use crate::{error::check, ffi::*};
+
+type Result<T> = std::result::Result<T, KrunError>;
+
+static NEXT_CID: AtomicU32 = AtomicU32::new(3);
+
+pub fn create(config: LibkrunConfig) -> Result<VmInfo> {
+    #[cfg(target_os = "macos")]
+    {
+        let clone_rootfs = config.clone_rootfs == Some(true);
+        let (mut spec, problems) = config.into_spec();
+        if let Some(problem) = problems.into_iter().chain(store_problem(&spec, clone_rootfs)).next() {
+            return Err(problem);
+        }
+
+        let mut backing = Backing::default();
+        match &spec.root {
+            RootSpec::Dir(base) if clone_rootfs => {
+                // Taken first so gc cannot evict the image mid-clone
+                let lease = store::Lease::acquire(base.as_ref()).map_err(|error| KrunError::Io {
+                    op: "lease cached rootfs",
+                    error,
+                })?;
+                let clone = RootfsClone::create(base.as_ref()).map_err(|error| KrunError::Io {
+                    op: "clone rootfs",
+                    error,
+                })?;
+                spec.root = RootSpec::Dir(clone.path().to_string_lossy().into_owned());
+                backing.rootfs = Some(clone);
+                backing.lease = lease;
+            }
+            _ => {}
+        }
+        if let Some(scratch) = &mut spec.scratch_disk {
+            let image = ScratchImage::create(scratch.size_mib).map_err(|error| KrunError::Io {
+                op: "create scratch disk",
+                error,
+            })?;
+            scratch.path = image.path().to_string_lossy().into_owned();
+            backing.scratch = Some(image);
+        }
+        if let Some(egress) = &mut spec.egress {
+            let log = egress
+                .log_path
+                .as_ref()
+                .map(|path| fs::OpenOptions::new().append(true).create(true).open(path))
+                .transpose()
+                .map_err(|error| KrunError::Io {
+                    op: "open egress log",
+                    error,
+                })?;
+            let proxy = EgressProxy::start(egress.policy.clone(), log).map_err(|error| KrunError::Io {
+                op: "start egress proxy",
+                error,
+            })?;
+            egress.socket_path = proxy.path().to_string_lossy().into_owned();
+            backing.egress = Some(proxy);
+        }
+
+        unsafe {
+            let ctx_id = check(krun_create_ctx(), "krun_create_ctx", None)? as u32;
+
+            // The context is configured here too so libkrun rejects bad
+            // settings now rather than inside the supervisor
+            if let Err(err) = spec.apply_config(ctx_id) {
+                krun_free_ctx(ctx_id);
+                return Err(err);
+            }
+
+            let cid = NEXT_CID.fetch_add(1, Ordering::SeqCst);
+            let info = VmInfo {
+                ctx_id,
+                cid,
+                cpus: spec.cpus,
+                memory_mib: spec.memory_mib,
+            };
+            registry::insert(ctx_id, spec, backing);
+
+            Ok(info)
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = config;
+        Err(KrunError::NOT_MACOS)
+    }
+}
+
+/// Every problem `create` would run into with `config`, without creating a
+/// context
+pub fn validate(config: LibkrunConfig) -> Result<Vec<KrunError>> {
+    #[cfg(target_os = "macos")]
+    {
+        let clone_rootfs = config.clone_rootfs == Some(true);
+        let (spec, mut problems) = config.into_spec();
+        problems.extend(store_problem(&spec, clone_rootfs));
+        Ok(problems)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = config;
+        Err(KrunError::NOT_MACOS)
+    }
+}
+
+/// Booting a cached image in place would let the guest write into the cache
+fn store_problem(spec: &spec::VmSpec, clone_rootfs: bool) -> Option<KrunError> {
+    match &spec.root {
+        RootSpec::Dir(path) if !clone_rootfs && store::is_entry(path.as_ref()) => Some(KrunError::invalid(
+            "cloneRootfs",
+            "is required to boot a rootfsPath from a RootfsStore",
+        )),
+        _ => None,
+    }
+}
+
+pub fn set_exec(ctx_id: u32, exec_path: String, args: Vec<String>, env: HashMap<String, String>) -> Result<()> {
+    #[cfg(target_os = "macos")]
+    {
+        let env: Vec<(String, String)> = env.into_iter().collect();
+        if let Some(problem) = spec::env_problems(&env).into_iter().next() {
+            return Err(problem);
+        }
+
+        registry::transition(ctx_id, Action::SetExec, |ctx| {
+            let exec = ctx.spec.guest_exec(ExecSpec {
+                path: exec_path,
+                args,
+                env: spec::merge_env(&ctx.spec.env, &env),
+            });
+            exec.apply(ctx_id)?;
+            ctx.spec.exec = Some(exec);
+            Ok(())
+        })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = (ctx_id, exec_path, args, env);
+        Err(KrunError::NOT_MACOS)
+    }
+}
+
+pub fn start(ctx_id: u32) -> Result<VmProcess> {
+    #[cfg(target_os = "macos")]
+    {
+        registry::transition(ctx_id, Action::Start, |ctx| {
+            let (net_fd, backend) = net::connect(&ctx.spec).map_err(|error| KrunError::Io {
+                op: match ctx.spec.network {
+                    NetworkSpec::Passt { .. } => "start passt",
+                    NetworkSpec::Nat { .. } => "start network stack",
+                    _ => "connect to network socket",
+                },
+                error,
+            })?;
+            let supervisor = supervisor::spawn(&ctx.spec, net_fd).map_err(|error| KrunError::Io {
+                op: "start VM supervisor",
+                error,
+            })?;
+            // Best effort: without the mark, this process's files are only
+            // kept while it lives
+            if let Ok(mark) = crate::rootfs::SupervisorMark::new(supervisor.pid()) {
+                supervisor.hold_until_exit(mark);
+            }
+            if let Some(scratch) = ctx.backing.scratch.take() {
+                supervisor.hold_until_exit(scratch);
+            }
+            if let Some(backend) = backend {
+                supervisor.hold_until_exit(backend);
+            }
+            ctx.supervisor = Some(supervisor.clone());
+            Ok(VmProcess::new(supervisor))
+        })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = ctx_id;
+        Err(KrunError::NOT_MACOS)
+    }
+}
+
+/// Gracefully stop the VM of `ctx_id`, killing it after `timeout`. Resolves
+/// to whether the guest shut down on its own; the context is left allocated.
+pub async fn shutdown(ctx_id: u32, timeout: Duration) -> Result<bool> {
+    #[cfg(target_os = "macos")]
+    {
+        let supervisor = registry::transition(ctx_id, Action::Shutdown, |ctx| {
+            ctx.supervisor
+                .clone()
+                .ok_or_else(|| KrunError::InvalidState("VM not started".to_string()))
+        })?;
+        supervisor
+            .shutdown(timeout)
+            .await
+            .map_err(|error| KrunError::Io {
+                op: "shut down VM",
+                error,
+            })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = (ctx_id, timeout);
+        Err(KrunError::NOT_MACOS)
+    }
+}
+
+pub fn free(ctx_id: u32) -> Result<()> {
+    #[cfg(target_os = "macos")]
+    {
+        registry::transition(ctx_id, Action::Free, |ctx| {
+            if let Some(supervisor) = &ctx.supervisor {
+                supervisor.signal(libc::SIGKILL).map_err(|error| KrunError::Io {
+                    op: "kill VM supervisor",
+                    error,
+                })?;
+            }
+            unsafe { check(krun_free_ctx(ctx_id), "krun_free_ctx", None)? };
+            Ok(())
+        })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = ctx_id;
+        Err(KrunError::NOT_MACOS)
+    }
+}
+
+/// Save the writable mounts of `ctx_id` as checkpoint `tag`
+pub async fn checkpoint(ctx_id: u32, tag: String) -> Result<()> {
+    let (checkpoints, mounts) = checkpoint_target(ctx_id, Action::Checkpoint, &tag)?;
+    blocking(move || {
+        let mut checkpoints = lock(&checkpoints);
+        if checkpoints.contains(&tag) {
+            return Err(KrunError::invalid("tag", format!("checkpoint {:?} already exists", tag)));
+        }
+        checkpoints.create(&tag, &mounts).map_err(|error| KrunError::Io {
+            op: "checkpoint mounts",
+            error,
+        })
+    })
+    .await
+}
+
+/// Restore the writable mounts of `ctx_id` from checkpoint `tag`, refusing
+/// while the VM runs unless `force` is set
+pub async fn rollback(ctx_id: u32, tag: String, force: bool) -> Result<()> {
+    let (checkpoints, mounts) = checkpoint_target(ctx_id, Action::Rollback { force }, &tag)?;
+    blocking(move || {
+        let restored = lock(&checkpoints)
+            .rollback(&tag, &mounts)
+            .map_err(|error| KrunError::Io {
+                op: "roll back mounts",
+                error,
+            })?;
+        if !restored {
+            return Err(KrunError::invalid("tag", format!("no checkpoint named {:?}", tag)));
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Remove checkpoint `tag` of `ctx_id`, resolving to whether it existed
+pub async fn drop_checkpoint(ctx_id: u32, tag: String) -> Result<bool> {
+    let (checkpoints, _) = checkpoint_target(ctx_id, Action::Checkpoint, &tag)?;
+    blocking(move || {
+        lock(&checkpoints).remove(&tag).map_err(|error| KrunError::Io {
+            op: "drop checkpoint",
+            error,
+        })
+    })
+    .await
+}
+
+/// The checkpoints and writable mounts of `ctx_id`, if `action` is allowed
+fn checkpoint_target(
+    ctx_id: u32,
+    action: Action,
+    tag: &str,
+) -> Result<(Arc<Mutex<Checkpoints>>, Vec<std::path::PathBuf>)> {
+    if tag.is_empty() {
+        return Err(KrunError::invalid("tag", "must not be empty"));
+    }
+    registry::transition(ctx_id, action, |ctx| {
+        let mounts = checkpoint::writable_mounts(&ctx.spec.mounts);
+        if mounts.is_empty() {
+            return Err(KrunError::invalid("mounts", "has no writable mount to checkpoint"));
+        }
+        Ok((ctx.backing.checkpoints.clone(), mounts))
+    })
+}
+
+fn lock(checkpoints: &Mutex<Checkpoints>) -> MutexGuard<'_, Checkpoints> {
+    // Checkpoints are only recorded once complete, so a panic cannot leave
+    // them inconsistent
+    checkpoints.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Run filesystem work off the JS thread and the registry lock
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|err| {
+        Err(KrunError::Io {
+            op: "run checkpoint task",
+            error: std::io::Error::other(err),
+        })
+    })
+}
+
+/// Remove rootfs clones whose process exited without freeing its contexts
+pub fn prune_rootfs_clones() -> Result<u32> {
+    crate::rootfs::prune().map_err(|error| KrunError::Io {
+        op: "prune rootfs clones",
+        error,
+    })
+}
+
+/// Connections the egress proxy of `ctx_id` refused, oldest first; empty
+/// if the context has no proxy
+pub fn blocked_egress(ctx_id: u32) -> Result<Vec<EgressBlock>> {
+    registry::inspect(ctx_id, |ctx| {
+        ctx.backing
+            .egress
+            .as_ref()
+            .map(EgressProxy::blocked)
+            .unwrap_or_default()
+    })
+    .ok_or_else(|| KrunError::InvalidState(format!("Unknown context {}", ctx_id)))
+}
+
+/// How many refused connections [`blocked_egress`] no longer lists
+pub fn blocked_egress_dropped(ctx_id: u32) -> Result<u64> {
+    registry::inspect(ctx_id, |ctx| ctx.backing.egress.as_ref().map_or(0, EgressProxy::dropped))
+        .ok_or_else(|| KrunError::InvalidState(format!("Unknown context {}", ctx_id)))
+}
+
+/// Current lifecycle state of `ctx_id`
+pub fn state(ctx_id: u32) -> Result<VmState> {
+    registry::state(ctx_id).ok_or_else(|| KrunError::InvalidState(format!("Unknown context {}", ctx_id)))
+}
@@ -0,0 +1,642 @@
+//! The egress proxy a guest's outbound connections go through.
+//!
+//! libkrun connects the guest's vsock port to a Unix socket the crate
+//! listens on, one per context. Each connection opens with one of:
+//!
+//! - `CONNECT host:port`, after which the connection is a tunnel
+//! - a plain HTTP request with an absolute URL or a `Host` header, which is
+//!   forwarded with `Connection: close` so the next request comes in on a
+//!   connection of its own and is checked again
+//! - a TLS ClientHello, forwarded to port 443 of the server it names
+//!
+//! The host is checked against the context's [`EgressPolicy`] before
+//! anything is resolved, and again against the addresses it resolves to, so
+//! a public name pointing at loopback or link-local addresses such as a
+//! cloud metadata service is refused too. A TLS ClientHello sent through a
+//! tunnel must name an allowed server as well. Refused attempts are kept
+//! for [`EgressProxy::blocked`], the oldest dropped past [`MAX_BLOCKED`],
+//! and each is also appended to the context's log file, if it has one.
+
+use crate::net::{self, BoundSocket};
+use crate::policy::{self, EgressPolicy};
+use napi_derive::napi;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Connections served at once; later ones are closed until one finishes
+const MAX_CONNECTIONS: usize = 256;
+
+/// Refused attempts kept per context
+pub const MAX_BLOCKED: usize = 1024;
+
+/// How long the guest has to send its request or ClientHello
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest request head read, which also holds any TLS record
+const MAX_HEAD: usize = 32 * 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A connection the egress policy refused
+#[napi(object)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EgressBlock {
+    /// Host the guest asked for; empty if none could be made out
+    pub host: String,
+    /// Port the guest asked for; 0 if none could be made out
+    pub port: u32,
+    /// `denied`, `notAllowed`, `localAddress` or `unidentified`
+    pub rule: String,
+    /// Milliseconds since the Unix epoch
+    pub time_ms: f64,
+}
+
+/// A running proxy, stopped on drop
+#[derive(Debug)]
+pub struct EgressProxy {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    socket: BoundSocket,
+}
+
+#[derive(Debug)]
+struct Shared {
+    policy: EgressPolicy,
+    stop: AtomicBool,
+    active: AtomicUsize,
+    blocked: Mutex<VecDeque<EgressBlock>>,
+    /// Refused attempts no longer in `blocked`
+    dropped: AtomicU64,
+    /// Gets a line of JSON per refused attempt
+    log: Option<Mutex<File>>,
+}
+
+impl EgressProxy {
+    /// Listen for the guest's connections on a fresh socket of the crate's,
+    /// appending refused attempts to `log` if given
+    pub fn start(policy: EgressPolicy, log: Option<File>) -> io::Result<EgressProxy> {
+        let socket = BoundSocket::reserve()?;
+        let listener =
+            UnixListener::bind(socket.path()).map_err(|err| net::with_path(err, &socket.path().to_string_lossy()))?;
+        let shared = Arc::new(Shared {
+            policy,
+            stop: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            blocked: Mutex::new(VecDeque::new()),
+            dropped: AtomicU64::new(0),
+            log: log.map(Mutex::new),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("libkrun-egress".to_string())
+                .spawn(move || accept(listener, shared))?
+        };
+        Ok(EgressProxy {
+            shared,
+            thread: Some(thread),
+            socket,
+        })
+    }
+
+    /// Socket for libkrun to connect the guest's vsock port to
+    pub fn path(&self) -> &Path {
+        self.socket.path()
+    }
+
+    /// Attempts refused so far, oldest first
+    pub fn blocked(&self) -> Vec<EgressBlock> {
+        self.shared.blocked().iter().cloned().collect()
+    }
+
+    /// How many refused attempts [`EgressProxy::blocked`] no longer has
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for EgressProxy {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop; connections already open run to completion
+        let _ = UnixStream::connect(self.socket.path());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn blocked(&self) -> MutexGuard<'_, VecDeque<EgressBlock>> {
+        // Entries are pushed whole, so a panic cannot leave the log torn
+        self.blocked.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn block(&self, host: &str, port: u16, rule: &str) {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |elapsed| elapsed.as_millis() as f64);
+        let block = EgressBlock {
+            host: host.to_string(),
+            port: u32::from(port),
+            rule: rule.to_string(),
+            time_ms,
+        };
+        if let Some(log) = &self.log {
+            let mut line = serde_json::to_vec(&block).expect("EgressBlock serializes");
+            line.push(b'\n');
+            // One write to a file opened for appending, so lines stay whole.
+            // There is no one to report a failure to; the ring still has it.
+            let mut log = log.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let _ = log.write_all(&line);
+        }
+
+        let mut blocked = self.blocked();
+        if blocked.len() == MAX_BLOCKED {
+            blocked.pop_front();
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+        blocked.push_back(block);
+    }
+}
+
+/// Releases a connection slot when the connection's thread is done with it
+struct Slot(Arc<Shared>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn accept(listener: UnixListener, shared: Arc<Shared>) {
+    for guest in listener.incoming() {
+        if shared.stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(guest) = guest else {
+            // Out of descriptors, most likely; let connections finish
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+        if shared.active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            shared.active.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+        let slot = Slot(shared.clone());
+        // Dropping the closure when the spawn fails releases the slot
+        let _ = thread::Builder::new()
+            .name("libkrun-egress-conn".to_string())
+            .spawn(move || {
+                let _ = serve(guest, &slot.0);
+            });
+    }
+}
+
+/// What a connection asked for
+enum Request {
+    Connect { host: String, port: u16 },
+    Http { host: String, port: u16 },
+    Tls { host: String },
+}
+
+fn serve(guest: UnixStream, shared: &Shared) -> io::Result<()> {
+    guest.set_read_timeout(Some(HEAD_TIMEOUT))?;
+    let head = read_head(&guest)?;
+    guest.set_read_timeout(None)?;
+
+    let is_http = head.first() != Some(&tls::HANDSHAKE);
+    let Some((request, forward)) = parse(&head) else {
+        shared.block("", 0, "unidentified");
+        if is_http {
+            respond(&guest, "400 Bad Request", "the proxy could not tell which host the request is for\n");
+        }
+        return Ok(());
+    };
+    let (host, port) = match &request {
+        Request::Connect { host, port } | Request::Http { host, port } => (host.clone(), *port),
+        Request::Tls { host } => (host.clone(), 443),
+    };
+
+    let refused = |rule: &str| {
+        shared.block(&host, port, rule);
+        if is_http {
+            let body = format!("egress to {} is refused by the sandbox's policy ({})\n", host, rule);
+            respond(&guest, "403 Forbidden", &body);
+        }
+    };
+    if let Some(rule) = shared.policy.check(&host) {
+        refused(rule);
+        return Ok(());
+    }
+    let addrs: Vec<_> = match (host.as_str(), port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => Vec::new(),
+    };
+    if addrs.iter().any(|addr| is_local(addr.ip())) {
+        refused("localAddress");
+        return Ok(());
+    }
+    let Some(upstream) = addrs
+        .iter()
+        .find_map(|addr| TcpStream::connect_timeout(addr, CONNECT_TIMEOUT).ok())
+    else {
+        if is_http {
+            let body = format!("could not connect to {}:{}\n", host, port);
+            respond(&guest, "502 Bad Gateway", &body);
+        }
+        return Ok(());
+    };
+
+    let tunnel = match request {
+        Request::Connect { .. } => {
+            (&guest).write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+            Some(port)
+        }
+        Request::Http { .. } | Request::Tls { .. } => None,
+    };
+    thread::scope(|scope| {
+        scope.spawn(|| upload(shared, &guest, &upstream, forward, tunnel));
+        download(&upstream, &guest);
+    });
+    Ok(())
+}
+
+/// Read up to the end of the HTTP head or the first TLS record
+///
+/// A head over [`MAX_HEAD`] is returned incomplete and fails to parse.
+fn read_head(guest: &UnixStream) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 4096];
+    while !head_complete(&head) && head.len() < MAX_HEAD {
+        let n = (&*guest).read(&mut buf)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(head)
+}
+
+fn head_complete(head: &[u8]) -> bool {
+    match head.first() {
+        None => false,
+        Some(&tls::HANDSHAKE) => tls::record_len(head).is_some_and(|len| head.len() >= len),
+        Some(_) => find(head, b"\r\n\r\n").is_some(),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// The request in `head`, and the bytes to send upstream first
+fn parse(head: &[u8]) -> Option<(Request, Vec<u8>)> {
+    if head.first() == Some(&tls::HANDSHAKE) {
+        let host = policy::normalize_host(&tls::server_name(head)?)?;
+        return Some((Request::Tls { host }, head.to_vec()));
+    }
+
+    let end = find(head, b"\r\n\r\n")?;
+    let text = std::str::from_utf8(&head[..end]).ok()?;
+    let mut lines = text.split("\r\n");
+    let request_line = lines.next()?;
+    let mut parts = request_line.split(' ');
+    let (method, target, _version) = (parts.next()?, parts.next()?, parts.next()?);
+    let headers: Vec<(&str, &str)> = lines
+        .map(|line| line.split_once(':').map(|(name, value)| (name.trim(), value.trim())))
+        .collect::<Option<_>>()?;
+    // Bytes the guest sent after the head
+    let rest = &head[end + 4..];
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = authority(target, None)?;
+        return Some((Request::Connect { host, port }, rest.to_vec()));
+    }
+
+    let authority_of_target = target
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| target[7..].split(['/', '?', '#']).next().unwrap_or_default());
+    let host_header = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map(|(_, value)| *value);
+    let (host, port) = authority(authority_of_target.or(host_header)?, Some(80))?;
+
+    let mut forward = format!("{}\r\n", request_line);
+    for (name, value) in &headers {
+        let hop_by_hop = ["connection", "proxy-connection", "keep-alive", "proxy-authorization"]
+            .iter()
+            .any(|hop| name.eq_ignore_ascii_case(hop));
+        if !hop_by_hop {
+            forward.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    forward.push_str("Connection: close\r\n\r\n");
+    let mut forward = forward.into_bytes();
+    forward.extend_from_slice(rest);
+    Some((Request::Http { host, port }, forward))
+}
+
+/// Normalized host and port of `host[:port]`, with `default_port` used when
+/// the port is left out
+fn authority(authority: &str, default_port: Option<u16>) -> Option<(String, u16)> {
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok().filter(|port| *port != 0)?,
+        None => default_port?,
+    };
+    Some((policy::normalize_host(host)?, port))
+}
+
+/// Addresses the guest must not reach through the host
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(IpAddr::V4(ip)),
+            None => ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xffc0) == 0xfe80,
+        },
+    }
+}
+
+fn respond(mut guest: &UnixStream, status: &str, body: &str) {
+    let _ = write!(
+        guest,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
+
+/// Copy the guest's side of the connection upstream, starting with
+/// `forward`
+///
+/// In a tunnel to `tunnel`'s port, a TLS ClientHello must name a server the
+/// policy allows.
+fn upload(shared: &Shared, guest: &UnixStream, upstream: &TcpStream, mut forward: Vec<u8>, tunnel: Option<u16>) {
+    let result = (|| {
+        if let Some(port) = tunnel {
+            let mut buf = [0; 4096];
+            let partial_hello =
+                |data: &[u8]| data[0] == tls::HANDSHAKE && !head_complete(data) && data.len() < MAX_HEAD;
+            while forward.is_empty() || partial_hello(&forward) {
+                let n = (&*guest).read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                forward.extend_from_slice(&buf[..n]);
+            }
+            let server = tls::server_name(&forward).and_then(|name| policy::normalize_host(&name));
+            if let Some(server) = server {
+                if let Some(rule) = shared.policy.check(&server) {
+                    shared.block(&server, port, rule);
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+            }
+        }
+        (&*upstream).write_all(&forward)?;
+        io::copy(&mut &*guest, &mut &*upstream)
+    })();
+    match result {
+        Ok(_) => {
+            let _ = upstream.shutdown(Shutdown::Write);
+        }
+        Err(_) => {
+            let _ = upstream.shutdown(Shutdown::Both);
+            let _ = guest.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Copy the server's side of the connection to the guest
+fn download(upstream: &TcpStream, guest: &UnixStream) {
+    match io::copy(&mut &*upstream, &mut &*guest) {
+        Ok(_) => {
+            let _ = guest.shutdown(Shutdown::Write);
+        }
+        Err(_) => {
+            let _ = guest.shutdown(Shutdown::Both);
+            let _ = upstream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Just enough of TLS to find the server name in a ClientHello
+mod tls {
+    /// Content type of handshake records
+    pub const HANDSHAKE: u8 = 0x16;
+
+    const CLIENT_HELLO: u8 = 1;
+    const SERVER_NAME: u16 = 0;
+    const HOST_NAME: u8 = 0;
+
+    /// Length of the record `data` starts with, header included
+    pub fn record_len(data: &[u8]) -> Option<usize> {
+        let header = data.get(..5)?;
+        Some(5 + usize::from(u16::from_be_bytes([header[3], header[4]])))
+    }
+
+    /// Server name of the ClientHello in the first record of `data`
+    ///
+    /// A ClientHello split over several records is not looked into.
+    pub fn server_name(data: &[u8]) -> Option<String> {
+        if data.first() != Some(&HANDSHAKE) {
+            return None;
+        }
+        let mut record = Reader(data.get(5..record_len(data)?)?);
+        if record.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        let len = record.u24()?;
+        let mut hello = Reader(record.take(len)?);
+        // Version and random
+        hello.take(2 + 32)?;
+        let len = usize::from(hello.u8()?);
+        hello.take(len)?;
+        let len = hello.u16()?;
+        hello.take(usize::from(len))?;
+        let len = usize::from(hello.u8()?);
+        hello.take(len)?;
+        let len = hello.u16()?;
+        let mut extensions = Reader(hello.take(usize::from(len))?);
+        while !extensions.0.is_empty() {
+            let kind = extensions.u16()?;
+            let len = extensions.u16()?;
+            let mut extension = Reader(extensions.take(usize::from(len))?);
+            if kind != SERVER_NAME {
+                continue;
+            }
+            let len = extension.u16()?;
+            let mut names = Reader(extension.take(usize::from(len))?);
+            while !names.0.is_empty() {
+                let kind = names.u8()?;
+                let len = names.u16()?;
+                let name = names.take(usize::from(len))?;
+                if kind == HOST_NAME {
+                    return String::from_utf8(name.to_vec()).ok();
+                }
+            }
+            return None;
+        }
+        None
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+            if self.0.len() < len {
+                return None;
+            }
+            let (taken, rest) = self.0.split_at(len);
+            self.0 = rest;
+            Some(taken)
+        }
+
+        fn u8(&mut self) -> Option<u8> {
+            Some(self.take(1)?[0])
+        }
+
+        fn u16(&mut self) -> Option<u16> {
+            let bytes = self.take(2)?;
+            Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+        }
+
+        fn u24(&mut self) -> Option<usize> {
+            let bytes = self.take(3)?;
+            Some(usize::from(bytes[0]) << 16 | usize::from(bytes[1]) << 8 | usize::from(bytes[2]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn policy(deny: &[&str]) -> EgressPolicy {
+        let mut problems = Vec::new();
+        let deny = deny.iter().map(|host| host.to_string()).collect();
+        let policy = EgressPolicy::new(None, Some(deny), &mut problems);
+        assert!(problems.is_empty());
+        policy
+    }
+
+    /// Send `request` through `proxy`, returning its response
+    fn send(proxy: &EgressProxy, request: &str) -> String {
+        let mut guest = UnixStream::connect(proxy.path()).unwrap();
+        guest.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        guest.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn log_lines(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn refused_requests_are_answered_and_logged() {
+        let tmp = TempDir::new();
+        let log_path = tmp.join("egress.log");
+        let log = File::create(&log_path).unwrap();
+        let proxy = EgressProxy::start(policy(&["denied.example"]), Some(log)).unwrap();
+
+        let response = send(&proxy, "CONNECT denied.example:443 HTTP/1.1\r\nHost: denied.example:443\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+        let response = send(&proxy, "GET http://127.0.0.1:8080/ HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+        let response = send(&proxy, "nonsense\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+
+        let blocked: Vec<_> = proxy
+            .blocked()
+            .into_iter()
+            .map(|block| (block.host, block.port, block.rule))
+            .collect();
+        assert_eq!(
+            blocked,
+            [
+                ("denied.example".to_string(), 443, "denied".to_string()),
+                ("127.0.0.1".to_string(), 8080, "localAddress".to_string()),
+                (String::new(), 0, "unidentified".to_string()),
+            ]
+        );
+        let lines = log_lines(&log_path);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["host"], "denied.example");
+        assert_eq!(lines[0]["port"], 443);
+        assert_eq!(lines[0]["rule"], "denied");
+        assert!(lines[0]["timeMs"].as_f64().unwrap() > 0.0);
+        assert_eq!(proxy.dropped(), 0);
+
+        let path = proxy.path().to_path_buf();
+        drop(proxy);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn ring_counts_what_it_drops_and_the_log_keeps_everything() {
+        let tmp = TempDir::new();
+        let log_path = tmp.join("egress.log");
+        let proxy = EgressProxy::start(EgressPolicy::default(), Some(File::create(&log_path).unwrap())).unwrap();
+        for port in 0..MAX_BLOCKED + 5 {
+            proxy.shared.block("denied.example", port as u16, "denied");
+        }
+
+        let blocked = proxy.blocked();
+        assert_eq!(blocked.len(), MAX_BLOCKED);
+        assert_eq!(blocked[0].port, 5);
+        assert_eq!(proxy.dropped(), 5);
+        let lines = log_lines(&log_path);
+        assert_eq!(lines.len(), MAX_BLOCKED + 5);
+        assert_eq!(lines[0]["port"], 0);
+    }
+
+    #[test]
+    fn requests_name_their_host() {
+        let host = |head: &str| {
+            parse(head.as_bytes()).map(|(request, forward)| match request {
+                Request::Connect { host, port } => ("connect", host, port, forward),
+                Request::Http { host, port } => ("http", host, port, forward),
+                Request::Tls { host } => ("tls", host, 443, forward),
+            })
+        };
+
+        let (kind, name, port, forward) = host("CONNECT Example.COM:8443 HTTP/1.1\r\n\r\nhello").unwrap();
+        assert_eq!((kind, name.as_str(), port, forward.as_slice()), ("connect", "example.com", 8443, &b"hello"[..]));
+        let (kind, name, port, _) = host("GET http://example.com/path HTTP/1.1\r\nHost: other.example\r\n\r\n").unwrap();
+        assert_eq!((kind, name.as_str(), port), ("http", "example.com", 80));
+        let (kind, name, port, _) = host("GET /path HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").unwrap();
+        assert_eq!((kind, name.as_str(), port), ("http", "example.com", 8080));
+        assert!(host("GET /path HTTP/1.1\r\n\r\n").is_none());
+        assert!(host("GET /path HTTP/1.1\r\nHost: example.com").is_none());
+    }
+}
@@ -0,0 +1,172 @@
+//! Errors raised while configuring or running a libkrun context.
+//!
+//! libkrun reports failures as `-errno`; [`KrunError`] keeps that errno
+//! together with the call and config field it belongs to, so JavaScript can
+//! branch on `err.code` instead of parsing messages.
+//!
+//! This module is shared with `bin/krun-supervisor.rs` and must not depend on
+//! napi.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum KrunError {
+    /// A libkrun call returned `-errno`
+    Call {
+        call: &'static str,
+        errno: i32,
+        /// Config field (as named in JavaScript) the call applied
+        field: Option<String>,
+    },
+    /// A config value was rejected before reaching libkrun
+    InvalidConfig { field: String, reason: String },
+    /// A mount was refused by the mount policy
+    MountDenied {
+        field: String,
+        /// Policy rule that refused it: `notAllowed`, `denied` or `sensitive`
+        rule: &'static str,
+        reason: String,
+    },
+    /// The operation is not allowed in the context's current state
+    InvalidState(String),
+    /// A host-side operation around the VM failed
+    Io { op: &'static str, error: io::Error },
+    /// libkrun, or the libkrun build in use, cannot do this
+    Unsupported(&'static str),
+}
+
+impl KrunError {
+    /// libkrun cannot run on this platform
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    pub const NOT_MACOS: KrunError = KrunError::Unsupported("libkrun is only available on macOS");
+
+    pub fn invalid(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        KrunError::InvalidConfig {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Errno name such as `EINVAL`, or an `ERR_*` code for failures that do
+    /// not come from a syscall
+    pub fn code(&self) -> String {
+        match self {
+            KrunError::Call { errno, .. } => errno_name(*errno),
+            KrunError::InvalidConfig { .. } => "EINVAL".to_string(),
+            KrunError::MountDenied { .. } => "ERR_MOUNT_DENIED".to_string(),
+            KrunError::InvalidState(_) => "ERR_INVALID_STATE".to_string(),
+            KrunError::Io { error, .. } => error
+                .raw_os_error()
+                .map(errno_name)
+                .unwrap_or_else(|| "EIO".to_string()),
+            KrunError::Unsupported(_) => "ENOTSUP".to_string(),
+        }
+    }
+
+    /// The libkrun function that failed
+    pub fn call(&self) -> Option<&'static str> {
+        match self {
+            KrunError::Call { call, .. } => Some(call),
+            _ => None,
+        }
+    }
+
+    /// The config field that caused the failure
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            KrunError::Call { field, .. } => field.as_deref(),
+            KrunError::InvalidConfig { field, .. } | KrunError::MountDenied { field, .. } => Some(field),
+            _ => None,
+        }
+    }
+
+    /// The mount policy rule that refused a mount
+    pub fn rule(&self) -> Option<&'static str> {
+        match self {
+            KrunError::MountDenied { rule, .. } => Some(rule),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KrunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KrunError::Call { call, errno, field } => {
+                write!(f, "{} failed", call)?;
+                if let Some(field) = field {
+                    write!(f, " for {}", field)?;
+                }
+                write!(f, ": {}", errno_name(*errno))
+            }
+            KrunError::InvalidConfig { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+            KrunError::MountDenied { field, reason, .. } => write!(f, "Mount policy refuses {}: {}", field, reason),
+            KrunError::InvalidState(reason) => f.write_str(reason),
+            KrunError::Io { op, error } => write!(f, "Failed to {}: {}", op, error),
+            KrunError::Unsupported(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for KrunError {}
+
+/// Turn a libkrun return value into an error if it is a negative errno
+pub fn check(ret: i32, call: &'static str, field: Option<&str>) -> Result<i32, KrunError> {
+    if ret < 0 {
+        return Err(KrunError::Call {
+            call,
+            errno: -ret,
+            field: field.map(str::to_string),
+        });
+    }
+    Ok(ret)
+}
+
+/// Symbolic name of `errno`, falling back to `ERRNO_<n>`
+pub fn errno_name(errno: i32) -> String {
+    // Aliases (EAGAIN/EWOULDBLOCK, ENOTSUP/EOPNOTSUPP on Linux) resolve to
+    // whichever comes first
+    const NAMES: &[(i32, &str)] = &[
+        (libc::EPERM, "EPERM"),
+        (libc::ENOENT, "ENOENT"),
+        (libc::ESRCH, "ESRCH"),
+        (libc::EINTR, "EINTR"),
+        (libc::EIO, "EIO"),
+        (libc::ENXIO, "ENXIO"),
+        (libc::E2BIG, "E2BIG"),
+        (libc::ENOEXEC, "ENOEXEC"),
+        (libc::EBADF, "EBADF"),
+        (libc::ECHILD, "ECHILD"),
+        (libc::EAGAIN, "EAGAIN"),
+        (libc::ENOMEM, "ENOMEM"),
+        (libc::EACCES, "EACCES"),
+        (libc::EFAULT, "EFAULT"),
+        (libc::EBUSY, "EBUSY"),
+        (libc::EEXIST, "EEXIST"),
+        (libc::EXDEV, "EXDEV"),
+        (libc::ENODEV, "ENODEV"),
+        (libc::ENOTDIR, "ENOTDIR"),
+        (libc::EISDIR, "EISDIR"),
+        (libc::EINVAL, "EINVAL"),
+        (libc::ENFILE, "ENFILE"),
+        (libc::EMFILE, "EMFILE"),
+        (libc::ENOSPC, "ENOSPC"),
+        (libc::EROFS, "EROFS"),
+        (libc::EPIPE, "EPIPE"),
+        (libc::ERANGE, "ERANGE"),
+        (libc::ENAMETOOLONG, "ENAMETOOLONG"),
+        (libc::ENOSYS, "ENOSYS"),
+        (libc::ENOTSUP, "ENOTSUP"),
+        (libc::EOPNOTSUPP, "EOPNOTSUPP"),
+        (libc::EADDRINUSE, "EADDRINUSE"),
+        (libc::EADDRNOTAVAIL, "EADDRNOTAVAIL"),
+        (libc::ECONNREFUSED, "ECONNREFUSED"),
+        (libc::ETIMEDOUT, "ETIMEDOUT"),
+    ];
+    NAMES
+        .iter()
+        .find(|(value, _)| *value == errno)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("ERRNO_{}", errno))
+}
@@ -0,0 +1,111 @@
+//! libkrun C API bindings (simplified subset)
+//!
+//! Signatures follow `vendor/libkrun/include/libkrun.h`. Every call returns
+//! zero (or a non-negative value) on success and a negative errno on failure.
+//! The module is shared with the supervisor binary, which uses a different
+//! subset of the bindings than the addon.
+
+#![allow(dead_code)]
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+
+// virtio-net features, from uapi/linux/virtio_net.h
+pub const NET_FEATURE_CSUM: u32 = 1 << 0;
+pub const NET_FEATURE_GUEST_CSUM: u32 = 1 << 1;
+pub const NET_FEATURE_GUEST_TSO4: u32 = 1 << 7;
+pub const NET_FEATURE_GUEST_UFO: u32 = 1 << 10;
+pub const NET_FEATURE_HOST_TSO4: u32 = 1 << 11;
+pub const NET_FEATURE_HOST_UFO: u32 = 1 << 14;
+
+/// The features libkrun enables for passt and gvproxy
+pub const COMPAT_NET_FEATURES: u32 = NET_FEATURE_CSUM
+    | NET_FEATURE_GUEST_CSUM
+    | NET_FEATURE_GUEST_TSO4
+    | NET_FEATURE_GUEST_UFO
+    | NET_FEATURE_HOST_TSO4
+    | NET_FEATURE_HOST_UFO;
+
+#[link(name = "krun")]
+extern "C" {
+    pub fn krun_create_ctx() -> i32;
+    pub fn krun_get_max_vcpus() -> i32;
+    pub fn krun_free_ctx(ctx_id: u32) -> i32;
+    pub fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32;
+    pub fn krun_set_root(ctx_id: u32, root_path: *const c_char) -> i32;
+    pub fn krun_set_root_disk_remount(
+        ctx_id: u32,
+        device: *const c_char,
+        fstype: *const c_char,
+        options: *const c_char,
+    ) -> i32;
+    pub fn krun_set_workdir(ctx_id: u32, workdir_path: *const c_char) -> i32;
+    pub fn krun_set_exec(
+        ctx_id: u32,
+        exec_path: *const c_char,
+        argv: *const *const c_char,
+        envp: *const *const c_char,
+    ) -> i32;
+    pub fn krun_set_env(ctx_id: u32, envp: *const *const c_char) -> i32;
+    pub fn krun_add_disk2(
+        ctx_id: u32,
+        block_id: *const c_char,
+        disk_path: *const c_char,
+        disk_format: u32,
+        read_only: bool,
+    ) -> i32;
+    pub fn krun_add_virtiofs(ctx_id: u32, tag: *const c_char, path: *const c_char) -> i32;
+    pub fn krun_add_virtiofs2(ctx_id: u32, tag: *const c_char, path: *const c_char, shm_size: u64) -> i32;
+    pub fn krun_set_passt_fd(ctx_id: u32, fd: c_int) -> i32;
+    pub fn krun_set_gvproxy_path(ctx_id: u32, c_path: *mut c_char) -> i32;
+    pub fn krun_add_net_unixstream(
+        ctx_id: u32,
+        c_path: *const c_char,
+        fd: c_int,
+        c_mac: *mut u8,
+        features: u32,
+        flags: u32,
+    ) -> i32;
+    pub fn krun_add_net_unixgram(
+        ctx_id: u32,
+        c_path: *const c_char,
+        fd: c_int,
+        c_mac: *mut u8,
+        features: u32,
+        flags: u32,
+    ) -> i32;
+    pub fn krun_set_net_mac(ctx_id: u32, c_mac: *mut u8) -> i32;
+    pub fn krun_set_port_map(ctx_id: u32, port_map: *const *const c_char) -> i32;
+    pub fn krun_add_vsock_port2(ctx_id: u32, port: u32, c_filepath: *const c_char, listen: bool) -> i32;
+    pub fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32;
+    pub fn krun_start_enter(ctx_id: u32) -> c_int;
+}
+
+/// Calls only newer libkrun builds export, looked up at run time so that
+/// everything else still works against older ones such as the vendored
+/// build
+pub struct VsockCalls {
+    pub krun_disable_implicit_vsock: unsafe extern "C" fn(ctx_id: u32) -> i32,
+    pub krun_add_vsock: unsafe extern "C" fn(ctx_id: u32, tsi_features: u32) -> i32,
+}
+
+impl VsockCalls {
+    /// `None` if the loaded libkrun lacks either call
+    pub fn lookup() -> Option<Self> {
+        let symbol = |name: &CStr| {
+            let address = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+            (!address.is_null()).then_some(address)
+        };
+        let disable = symbol(c"krun_disable_implicit_vsock")?;
+        let add = symbol(c"krun_add_vsock")?;
+        // Both are declared in libkrun.h with these signatures
+        unsafe {
+            Some(VsockCalls {
+                krun_disable_implicit_vsock: std::mem::transmute::<*mut libc::c_void, unsafe extern "C" fn(u32) -> i32>(
+                    disable,
+                ),
+                krun_add_vsock: std::mem::transmute::<*mut libc::c_void, unsafe extern "C" fn(u32, u32) -> i32>(add),
+            })
+        }
+    }
+}
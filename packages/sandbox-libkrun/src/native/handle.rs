@@ -0,0 +1,284 @@
+//! Owned VM handles exposed to JavaScript.
+
+use crate::egress::EgressBlock;
+use crate::error::KrunError;
+use crate::registry::VmState;
+use crate::{
+    context, supervisor, LibkrunConfig, RollbackOptions, Settled, ShutdownOptions, VmExit, VmExitReason, VmInfo,
+};
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::HashMap;
+
+/// A VM running in its own supervisor process
+#[napi]
+pub struct VmProcess {
+    supervisor: supervisor::Supervisor,
+}
+
+impl VmProcess {
+    pub(crate) fn new(supervisor: supervisor::Supervisor) -> Self {
+        VmProcess { supervisor }
+    }
+}
+
+#[napi]
+impl VmProcess {
+    /// PID of the supervisor process hosting the VM
+    #[napi(getter, catch_unwind)]
+    pub fn pid(&self) -> u32 {
+        self.supervisor.pid()
+    }
+
+    /// Resolves once the VM has stopped, describing how it ended
+    #[napi(catch_unwind)]
+    pub async fn wait(&self) -> Settled<VmExit> {
+        Settled(
+            self.supervisor
+                .wait()
+                .await
+                .map(VmExit::from)
+                .map_err(|error| KrunError::Io {
+                    op: "wait for VM",
+                    error,
+                }),
+        )
+    }
+}
+
+impl From<supervisor::Exit> for VmExit {
+    fn from(exit: supervisor::Exit) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+
+        let signal = exit.status.signal();
+        let exit_code = exit.status.code().filter(|_| exit.start_error.is_none());
+        let reason = if exit.start_error.is_some() {
+            VmExitReason::StartFailed
+        } else if signal.is_some() {
+            if exit.killed_by_host {
+                VmExitReason::Killed
+            } else {
+                VmExitReason::Crashed
+            }
+        } else if matches!(exit_code, Some(125..=127)) {
+            VmExitReason::InitFailed
+        } else if exit.shutdown_requested {
+            VmExitReason::Shutdown
+        } else {
+            VmExitReason::Exited
+        };
+
+        VmExit {
+            reason,
+            exit_code,
+            killed_by_host: exit.killed_by_host,
+            signal,
+            wall_time_ms: exit.wall_time.as_secs_f64() * 1000.0,
+            error: exit.start_error,
+        }
+    }
+}
+
+/// A libkrun context owned by JavaScript
+///
+/// The context is freed by `stop()`, or when the handle is garbage
+/// collected, so an exception between creation and teardown cannot leak it.
+#[napi]
+pub struct VmHandle {
+    info: VmInfo,
+    freed: bool,
+}
+
+#[napi]
+impl VmHandle {
+    /// Create a new libkrun VM context
+    #[napi(constructor, catch_unwind)]
+    pub fn new(env: Env, config: LibkrunConfig) -> Result<Self> {
+        let info = context::create(config).map_err(|e| e.into_napi(env))?;
+        Ok(VmHandle { info, freed: false })
+    }
+
+    /// Set the executable to run in the VM
+    ///
+    /// `env` is merged over `LibkrunConfig.env`, overriding duplicate keys.
+    #[napi(catch_unwind)]
+    pub fn set_exec(
+        &self,
+        napi_env: Env,
+        exec_path: String,
+        args: Vec<String>,
+        env: HashMap<String, String>,
+    ) -> Result<()> {
+        self.ensure_live()
+            .and_then(|_| context::set_exec(self.info.ctx_id, exec_path, args, env))
+            .map_err(|e| e.into_napi(napi_env))
+    }
+
+    /// Start the VM in a supervisor process
+    #[napi(catch_unwind)]
+    pub fn start(&self, env: Env) -> Result<VmProcess> {
+        self.ensure_live()
+            .and_then(|_| context::start(self.info.ctx_id))
+            .map_err(|e| e.into_napi(env))
+    }
+
+    /// Ask the guest to shut down, killing the VM once `timeoutMs` has
+    /// elapsed. Resolves to `true` if the guest exited on its own.
+    #[napi(catch_unwind)]
+    pub async fn shutdown(&self, options: Option<ShutdownOptions>) -> Settled<bool> {
+        if let Err(err) = self.ensure_live() {
+            return Settled(Err(err));
+        }
+        Settled(context::shutdown(self.info.ctx_id, ShutdownOptions::timeout(options)).await)
+    }
+
+    /// Stop the VM if it is running and free the context
+    ///
+    /// The handle cannot be used again afterwards; calling `stop()` twice is
+    /// a no-op. If freeing fails the handle stays live, so `stop()` can be
+    /// retried and the context is still freed on garbage collection.
+    #[napi(catch_unwind)]
+    pub fn stop(&mut self, env: Env) -> Result<()> {
+        if self.freed {
+            return Ok(());
+        }
+        context::free(self.info.ctx_id).map_err(|e| e.into_napi(env))?;
+        self.freed = true;
+        Ok(())
+    }
+
+    /// Save the writable mounts as checkpoint `tag`
+    ///
+    /// Files are reflinked where the host filesystem supports it, so a
+    /// checkpoint is cheap on APFS, btrfs and XFS; elsewhere they
+    /// are copied. Checkpoints are removed with the context.
+    #[napi(catch_unwind)]
+    pub async fn checkpoint(&self, tag: String) -> Settled<()> {
+        if let Err(err) = self.ensure_live() {
+            return Settled(Err(err));
+        }
+        Settled(context::checkpoint(self.info.ctx_id, tag).await)
+    }
+
+    /// Restore the writable mounts from checkpoint `tag`, which is kept
+    ///
+    /// Refuses while the VM is running unless `force` is set.
+    #[napi(catch_unwind)]
+    pub async fn rollback(&self, tag: String, options: Option<RollbackOptions>) -> Settled<()> {
+        if let Err(err) = self.ensure_live() {
+            return Settled(Err(err));
+        }
+        let force = options.and_then(|o| o.force).unwrap_or(false);
+        Settled(context::rollback(self.info.ctx_id, tag, force).await)
+    }
+
+    /// Remove checkpoint `tag`, resolving to whether it existed
+    #[napi(catch_unwind)]
+    pub async fn drop_checkpoint(&self, tag: String) -> Settled<bool> {
+        if let Err(err) = self.ensure_live() {
+            return Settled(Err(err));
+        }
+        Settled(context::drop_checkpoint(self.info.ctx_id, tag).await)
+    }
+
+    /// Connections the egress proxy refused, oldest first
+    ///
+    /// Empty without `LibkrunConfig.egress`. Only the most recent 1024 are
+    /// kept; `blockedEgressDropped()` counts the rest, and
+    /// `EgressConfig.logPath` gets every one.
+    #[napi(catch_unwind)]
+    pub fn blocked_egress(&self, env: Env) -> Result<Vec<EgressBlock>> {
+        self.ensure_live()
+            .and_then(|_| context::blocked_egress(self.info.ctx_id))
+            .map_err(|e| e.into_napi(env))
+    }
+
+    /// How many refused connections `blockedEgress()` no longer lists
+    #[napi(catch_unwind)]
+    pub fn blocked_egress_dropped(&self, env: Env) -> Result<f64> {
+        self.ensure_live()
+            .and_then(|_| context::blocked_egress_dropped(self.info.ctx_id))
+            .map(|dropped| dropped as f64)
+            .map_err(|e| e.into_napi(env))
+    }
+
+    /// Current lifecycle state of the context
+    #[napi(getter, catch_unwind)]
+    pub fn state(&self, env: Env) -> Result<VmState> {
+        context::state(self.info.ctx_id).map_err(|e| e.into_napi(env))
+    }
+
+    /// Context, vsock CID and resources of this VM
+    #[napi(catch_unwind)]
+    pub fn info(&self) -> VmInfo {
+        self.info.clone()
+    }
+
+    fn ensure_live(&self) -> std::result::Result<(), KrunError> {
+        if self.freed {
+            return Err(KrunError::InvalidState("VM handle has been stopped".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VmHandle {
+    fn drop(&mut self) {
+        if !self.freed {
+            let _ = context::free(self.info.ctx_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::time::Duration;
+
+    fn exit(status: i32, killed_by_host: bool, shutdown_requested: bool) -> VmExit {
+        VmExit::from(supervisor::Exit {
+            status: ExitStatus::from_raw(status),
+            wall_time: Duration::from_millis(1500),
+            start_error: None,
+            killed_by_host,
+            shutdown_requested,
+        })
+    }
+
+    #[test]
+    fn host_signals_are_kills_and_others_crashes() {
+        // SIGTERM the supervisor could not turn into a shutdown
+        let killed = exit(libc::SIGTERM, true, true);
+        assert!(matches!(killed.reason, VmExitReason::Killed));
+        assert_eq!(killed.signal, Some(libc::SIGTERM));
+        assert!(killed.killed_by_host);
+        assert!(killed.exit_code.is_none());
+
+        assert!(matches!(exit(libc::SIGKILL, true, true).reason, VmExitReason::Killed));
+        assert!(matches!(exit(libc::SIGSEGV, false, false).reason, VmExitReason::Crashed));
+        assert!(matches!(exit(libc::SIGTERM, false, false).reason, VmExitReason::Crashed));
+    }
+
+    #[test]
+    fn exit_codes_map_to_reasons() {
+        let shutdown = exit(0, false, true);
+        assert!(matches!(shutdown.reason, VmExitReason::Shutdown));
+        assert_eq!(shutdown.exit_code, Some(0));
+        assert_eq!(shutdown.wall_time_ms, 1500.0);
+        assert!(matches!(exit(3 << 8, false, false).reason, VmExitReason::Exited));
+        assert!(matches!(exit(126 << 8, false, false).reason, VmExitReason::InitFailed));
+
+        let failed = VmExit::from(supervisor::Exit {
+            status: ExitStatus::from_raw(1 << 8),
+            wall_time: Duration::ZERO,
+            start_error: Some("krun_start_enter failed".to_string()),
+            killed_by_host: false,
+            shutdown_requested: false,
+        });
+        assert!(matches!(failed.reason, VmExitReason::StartFailed));
+        assert!(failed.exit_code.is_none());
+        assert_eq!(failed.error.as_deref(), Some("krun_start_enter failed"));
+    }
+}
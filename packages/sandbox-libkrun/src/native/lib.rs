@@ -1,47 +1,128 @@
 #![deny(clippy::all)]
+// Everything but the error paths is compiled out off macOS
+#![cfg_attr(not(target_os = "macos"), allow(dead_code, unused_imports))]
 
 use napi::bindgen_prelude::*;
 use napi_derive::napi;
 use std::collections::HashMap;
-use std::ffi::{CString, c_void};
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::os::raw::c_int;
-
-// libkrun C API bindings (simplified subset)
-#[link(name = "krun")]
-extern "C" {
-    fn krun_create_ctx() -> u32;
-    fn krun_free_ctx(ctx_id: u32) -> c_int;
-    fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> c_int;
-    fn krun_set_root(ctx_id: u32, root_path: *const i8) -> c_int;
-    fn krun_set_workdir(ctx_id: u32, workdir_path: *const i8) -> c_int;
-    fn krun_set_exec(ctx_id: u32, exec_path: *const i8, argv: *const *const i8, envp: *const *const i8) -> c_int;
-    fn krun_add_virtiofs(ctx_id: u32, tag: *const i8, path: *const i8) -> c_int;
-    fn krun_set_port_map(ctx_id: u32, port_map: *const i8) -> c_int;
-    fn krun_start_enter(ctx_id: u32) -> c_int;
-}
-
-static NEXT_CID: AtomicU32 = AtomicU32::new(3);
+use std::time::Duration;
 
+mod checkpoint;
+mod config;
+mod context;
+mod egress;
+mod error;
+#[cfg(target_os = "macos")]
+mod ffi;
+mod handle;
+mod marshal;
+mod nat;
+mod net;
+mod oci;
+mod passt;
+mod policy;
+mod registry;
+mod rootfs;
+mod scratch;
+mod spec;
+mod store;
+mod supervisor;
+#[cfg(test)]
+mod testutil;
+mod tree;
+mod validate;
+
+use error::KrunError;
+#[cfg(target_os = "macos")]
+use ffi::*;
+pub use config::{LibkrunConfig, PortMapping};
+pub use egress::EgressBlock;
+pub use handle::{VmHandle, VmProcess};
+pub use oci::UnpackedImage;
+pub use store::RootfsStore;
+pub use tree::{TreeDiff, TreeSnapshot};
+
+impl KrunError {
+    /// Convert into a JS `Error` carrying `code`, and `call` and `field`
+    /// when known
+    pub(crate) fn into_napi(self, env: Env) -> Error {
+        let call = self.call();
+        let field = self.field().map(str::to_string);
+        let rule = self.rule();
+        let error = JsError::from(Error::new(self.code(), self.to_string())).into_unknown(env);
+
+        let decorated = error.coerce_to_object().and_then(|mut object| {
+            if let Some(call) = call {
+                object.set_named_property("call", env.create_string(call)?)?;
+            }
+            if let Some(field) = field {
+                object.set_named_property("field", env.create_string(&field)?)?;
+            }
+            if let Some(rule) = rule {
+                object.set_named_property("rule", env.create_string(rule)?)?;
+            }
+            Ok(object.into_unknown())
+        });
+        decorated.map_or_else(|err| err, Error::from)
+    }
+}
+
+/// Outcome of an async function
+///
+/// Async functions have no `Env` of their own; converting the outcome on the
+/// JS thread lets a rejection carry the same properties as a sync throw.
+pub struct Settled<T>(std::result::Result<T, KrunError>);
+
+impl<T: ToNapiValue> ToNapiValue for Settled<T> {
+    unsafe fn to_napi_value(env: sys::napi_env, val: Self) -> Result<sys::napi_value> {
+        match val.0 {
+            Ok(value) => T::to_napi_value(env, value),
+            Err(err) => Err(err.into_napi(Env::from_raw(env))),
+        }
+    }
+}
+
+// `config` stays napi-free for the fuzz harness, so the conversions of its
+// one non-derived type live here
+impl FromNapiValue for config::Mounts {
+    unsafe fn from_napi_value(env: sys::napi_env, value: sys::napi_value) -> Result<Self> {
+        if type_of!(env, value)? != ValueType::Object {
+            return Err(Error::new(
+                Status::ObjectExpected,
+                "mounts must be an array of mounts or a { tag: hostPath } object",
+            ));
+        }
+        let mut is_array = false;
+        check_status!(sys::napi_is_array(env, value, &mut is_array))?;
+        if is_array {
+            Ok(config::Mounts::List(Vec::from_napi_value(env, value)?))
+        } else {
+            Ok(config::Mounts::Map(HashMap::from_napi_value(env, value)?))
+        }
+    }
+}
+
+impl ToNapiValue for config::Mounts {
+    unsafe fn to_napi_value(env: sys::napi_env, value: Self) -> Result<sys::napi_value> {
+        match value {
+            config::Mounts::Map(map) => HashMap::to_napi_value(env, map),
+            config::Mounts::List(list) => Vec::to_napi_value(env, list),
+        }
+    }
+}
+
+/// A problem found by `validateConfig`
 #[napi(object)]
-pub struct LibkrunConfig {
-    /// Number of virtual CPUs
-    pub cpus: Option<u8>,
-    /// Memory in MiB
-    pub memory_mib: Option<u32>,
-    /// Root filesystem path
-    pub rootfs_path: String,
-    /// Working directory inside VM
-    pub workdir: Option<String>,
-    /// virtiofs mounts: { tag: host_path }
-    pub mounts: Option<HashMap<String, String>>,
-    /// Port mappings: ["host:guest", ...]
-    pub port_map: Option<Vec<String>>,
-    /// Environment variables
-    pub env: Option<HashMap<String, String>>,
+pub struct ConfigProblem {
+    /// Config field at fault, e.g. `memoryMib` or `mounts.workspace`
+    pub field: String,
+    pub message: String,
+    /// Mount policy rule, if the mount policy refused a mount
+    pub rule: Option<String>,
 }
 
 #[napi(object)]
+#[derive(Clone)]
 pub struct VmInfo {
     pub ctx_id: u32,
     pub cid: u32,
@@ -49,16 +130,76 @@ pub struct VmInfo {
     pub memory_mib: u32,
 }
 
+/// Why a VM stopped
+#[napi(string_enum = "camelCase")]
+pub enum VmExitReason {
+    /// The guest workload exited on its own
+    Exited,
+    /// The guest exited after the host requested a graceful shutdown
+    Shutdown,
+    /// libkrun's init could not set up or run the workload (125-127)
+    InitFailed,
+    /// The VM could not be configured or started
+    StartFailed,
+    /// The host killed the VM
+    Killed,
+    /// The VMM process died from a signal the host did not send
+    Crashed,
+}
+
+/// How a VM stopped
+#[napi(object)]
+pub struct VmExit {
+    pub reason: VmExitReason,
+    /// Exit code of the guest init, unless the VMM died from a signal or the
+    /// VM never started
+    pub exit_code: Option<i32>,
+    /// Whether the host killed the VM: a hard stop, a shutdown timeout, or a
+    /// shutdown this libkrun cannot pass on to the guest
+    pub killed_by_host: bool,
+    /// Signal that ended the VMM process
+    pub signal: Option<i32>,
+    /// Wall time from start to exit in milliseconds
+    pub wall_time_ms: f64,
+    /// Why the VM could not be started
+    pub error: Option<String>,
+}
+
+#[napi(object)]
+pub struct ShutdownOptions {
+    /// How long to wait for the guest before killing the VM (default: 10000)
+    pub timeout_ms: Option<u32>,
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u32 = 10_000;
+
+impl ShutdownOptions {
+    fn timeout(options: Option<ShutdownOptions>) -> Duration {
+        let timeout_ms = options
+            .and_then(|o| o.timeout_ms)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MS);
+        Duration::from_millis(timeout_ms.into())
+    }
+}
+
+#[napi(object)]
+pub struct RollbackOptions {
+    /// Roll back even while the VM is running (default: false)
+    ///
+    /// The guest may still hold cached copies of the old files.
+    pub force: Option<bool>,
+}
+
 /// Check if libkrun is available on this system
-#[napi]
+#[napi(catch_unwind)]
 pub fn is_available() -> bool {
     // Check if we can create a context (tests libkrun presence)
     #[cfg(target_os = "macos")]
     {
         unsafe {
             let ctx = krun_create_ctx();
-            if ctx != u32::MAX {
-                krun_free_ctx(ctx);
+            if ctx >= 0 {
+                krun_free_ctx(ctx as u32);
                 return true;
             }
         }
@@ -71,164 +212,135 @@ pub fn is_available() -> bool {
 }
 
 /// Get libkrun version string
-#[napi]
+#[napi(catch_unwind)]
 pub fn get_version() -> String {
     // libkrun doesn't expose version API, return build info
     "libkrun (macOS Virtualization.framework)".to_string()
 }
 
-/// Create a new libkrun VM context
-#[napi]
-pub fn create_context(config: LibkrunConfig) -> Result<VmInfo> {
-    #[cfg(target_os = "macos")]
-    {
-        unsafe {
-            let ctx_id = krun_create_ctx();
-            if ctx_id == u32::MAX {
-                return Err(Error::from_reason("Failed to create libkrun context"));
-            }
-
-            let cpus = config.cpus.unwrap_or(1);
-            let memory_mib = config.memory_mib.unwrap_or(512);
-
-            // Set VM config
-            if krun_set_vm_config(ctx_id, cpus, memory_mib) != 0 {
-                krun_free_ctx(ctx_id);
-                return Err(Error::from_reason("Failed to set VM config"));
-            }
-
-            // Set root filesystem
-            let rootfs = CString::new(config.rootfs_path.clone())
-                .map_err(|_| Error::from_reason("Invalid rootfs path"))?;
-            if krun_set_root(ctx_id, rootfs.as_ptr()) != 0 {
-                krun_free_ctx(ctx_id);
-                return Err(Error::from_reason("Failed to set rootfs"));
-            }
-
-            // Set working directory
-            if let Some(workdir) = &config.workdir {
-                let workdir_c = CString::new(workdir.clone())
-                    .map_err(|_| Error::from_reason("Invalid workdir"))?;
-                if krun_set_workdir(ctx_id, workdir_c.as_ptr()) != 0 {
-                    krun_free_ctx(ctx_id);
-                    return Err(Error::from_reason("Failed to set workdir"));
-                }
-            }
-
-            // Add virtiofs mounts
-            if let Some(mounts) = &config.mounts {
-                for (tag, path) in mounts {
-                    let tag_c = CString::new(tag.clone())
-                        .map_err(|_| Error::from_reason("Invalid mount tag"))?;
-                    let path_c = CString::new(path.clone())
-                        .map_err(|_| Error::from_reason("Invalid mount path"))?;
-                    if krun_add_virtiofs(ctx_id, tag_c.as_ptr(), path_c.as_ptr()) != 0 {
-                        krun_free_ctx(ctx_id);
-                        return Err(Error::from_reason(format!("Failed to add virtiofs mount: {}", tag)));
-                    }
-                }
-            }
+/// Check `config` against the host without creating a context
+///
+/// Verifies `cpus` against the hypervisor's vCPU limit and `memoryMib`
+/// against host memory, checks that `rootfsPath` and every mount are
+/// directories, that mounts pass the mount policy and that disk images
+/// exist, and looks for duplicate virtiofs tags, bad ports and bad
+/// environment keys. Returns every problem found; an empty list means the
+/// config is usable.
+#[napi(catch_unwind)]
+pub fn validate_config(env: Env, config: LibkrunConfig) -> Result<Vec<ConfigProblem>> {
+    let problems = context::validate(config).map_err(|e| e.into_napi(env))?;
+    Ok(problems
+        .into_iter()
+        .map(|problem| ConfigProblem {
+            field: problem.field().unwrap_or_default().to_string(),
+            message: problem.to_string(),
+            rule: problem.rule().map(str::to_string),
+        })
+        .collect())
+}
 
-            // Set port mappings
-            if let Some(port_map) = &config.port_map {
-                let port_map_str = port_map.join(",");
-                let port_map_c = CString::new(port_map_str)
-                    .map_err(|_| Error::from_reason("Invalid port map"))?;
-                if krun_set_port_map(ctx_id, port_map_c.as_ptr()) != 0 {
-                    krun_free_ctx(ctx_id);
-                    return Err(Error::from_reason("Failed to set port map"));
-                }
-            }
+/// Remove rootfs clones left behind by processes that exited without
+/// freeing their contexts, returning how many were removed
+///
+/// Clones of live contexts are removed when the context is freed; this
+/// only cleans up after crashes. It also runs once before the first clone.
+#[napi(catch_unwind)]
+pub fn prune_rootfs_clones(env: Env) -> Result<u32> {
+    context::prune_rootfs_clones().map_err(|e| e.into_napi(env))
+}
 
-            let cid = NEXT_CID.fetch_add(1, Ordering::SeqCst);
+/// Unpack a local OCI image into `destDir` for use as `rootfsPath`
+///
+/// `layoutOrTarPath` is an OCI image layout directory, a tarball of one, or
+/// a `docker save` tarball; nothing is fetched from a registry. Layers are
+/// applied in order with whiteouts, keeping modes, ownership and xattrs.
+/// `destDir` must be missing or empty, and is left empty on failure.
+#[napi(catch_unwind)]
+pub async fn unpack_oci_image(layout_or_tar_path: String, dest_dir: String) -> Settled<UnpackedImage> {
+    let unpacked =
+        tokio::task::spawn_blocking(move || oci::unpack(layout_or_tar_path.as_ref(), dest_dir.as_ref())).await;
+    Settled(unpacked.unwrap_or_else(|err| {
+        Err(KrunError::Io {
+            op: "unpack OCI image",
+            error: std::io::Error::other(err),
+        })
+    }))
+}
 
-            Ok(VmInfo {
-                ctx_id,
-                cid,
-                cpus,
-                memory_mib,
-            })
-        }
-    }
+/// Record every entry under `path`, hashing file contents in parallel
+///
+/// Symlinks are recorded by target rather than followed, and sockets,
+/// FIFOs and device nodes are skipped. Files that disappear while the
+/// snapshot is taken are left out.
+#[napi(catch_unwind)]
+pub async fn snapshot_tree(path: String) -> Settled<TreeSnapshot> {
+    let snapshot = tokio::task::spawn_blocking(move || tree::snapshot(path.as_ref())).await;
+    Settled(
+        snapshot
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+            .map_err(|error| KrunError::Io {
+                op: "snapshot tree",
+                error,
+            }),
+    )
+}
 
-    #[cfg(not(target_os = "macos"))]
-    {
-        Err(Error::from_reason("libkrun is only available on macOS"))
-    }
+/// Compare two snapshots, normally of the same directory
+#[napi(catch_unwind)]
+pub fn diff_tree(before: &TreeSnapshot, after: &TreeSnapshot) -> TreeDiff {
+    tree::diff(before, after)
 }
 
-/// Start the VM (blocking - runs in the current thread)
-/// Note: krun_start_enter blocks, so this needs special handling
-#[napi]
-pub fn start_vm(ctx_id: u32) -> Result<i32> {
-    #[cfg(target_os = "macos")]
-    {
-        unsafe {
-            let result = krun_start_enter(ctx_id);
-            Ok(result)
-        }
-    }
+/// Create a new libkrun VM context
+///
+/// @deprecated Use `new VmHandle(config)`, which frees the context automatically
+#[napi(catch_unwind)]
+pub fn create_context(env: Env, config: LibkrunConfig) -> Result<VmInfo> {
+    context::create(config).map_err(|e| e.into_napi(env))
+}
 
-    #[cfg(not(target_os = "macos"))]
-    {
-        Err(Error::from_reason("libkrun is only available on macOS"))
-    }
+/// Start the VM in a supervisor process
+///
+/// The recorded configuration is handed to the `krun-supervisor` helper,
+/// which calls `krun_start_enter` in its own process so the guest cannot
+/// block or exit the Node process.
+///
+/// @deprecated Use `VmHandle.start()`
+#[napi(catch_unwind)]
+pub fn start_vm(env: Env, ctx_id: u32) -> Result<VmProcess> {
+    context::start(ctx_id).map_err(|e| e.into_napi(env))
 }
 
-/// Free a VM context
-#[napi]
-pub fn free_context(ctx_id: u32) -> Result<()> {
-    #[cfg(target_os = "macos")]
-    {
-        unsafe {
-            if krun_free_ctx(ctx_id) != 0 {
-                return Err(Error::from_reason("Failed to free context"));
-            }
-        }
-        Ok(())
-    }
+/// Ask the guest to shut down through libkrun's shutdown eventfd and wait for
+/// it to exit, killing the VM process once `timeoutMs` has elapsed
+///
+/// Resolves to `true` if the guest exited on its own. The context stays
+/// allocated until `freeContext`.
+#[napi(catch_unwind)]
+pub async fn shutdown(ctx_id: u32, options: Option<ShutdownOptions>) -> Settled<bool> {
+    Settled(context::shutdown(ctx_id, ShutdownOptions::timeout(options)).await)
+}
 
-    #[cfg(not(target_os = "macos"))]
-    {
-        Err(Error::from_reason("libkrun is only available on macOS"))
-    }
+/// Free a VM context, killing its supervisor if the VM is still running
+///
+/// @deprecated Use `VmHandle.stop()`
+#[napi(catch_unwind)]
+pub fn free_context(env: Env, ctx_id: u32) -> Result<()> {
+    context::free(ctx_id).map_err(|e| e.into_napi(env))
 }
 
 /// Set the executable to run in the VM
-#[napi]
-pub fn set_exec(ctx_id: u32, exec_path: String, args: Vec<String>, env: HashMap<String, String>) -> Result<()> {
-    #[cfg(target_os = "macos")]
-    {
-        unsafe {
-            let exec_c = CString::new(exec_path)
-                .map_err(|_| Error::from_reason("Invalid exec path"))?;
-
-            // Build argv array
-            let args_c: Vec<CString> = args.iter()
-                .map(|a| CString::new(a.clone()).unwrap())
-                .collect();
-            let mut argv_ptrs: Vec<*const i8> = args_c.iter().map(|a| a.as_ptr()).collect();
-            argv_ptrs.push(std::ptr::null());
-
-            // Build envp array
-            let env_strings: Vec<String> = env.iter()
-                .map(|(k, v)| format!("{}={}", k, v))
-                .collect();
-            let env_c: Vec<CString> = env_strings.iter()
-                .map(|e| CString::new(e.clone()).unwrap())
-                .collect();
-            let mut envp_ptrs: Vec<*const i8> = env_c.iter().map(|e| e.as_ptr()).collect();
-            envp_ptrs.push(std::ptr::null());
-
-            if krun_set_exec(ctx_id, exec_c.as_ptr(), argv_ptrs.as_ptr(), envp_ptrs.as_ptr()) != 0 {
-                return Err(Error::from_reason("Failed to set exec"));
-            }
-        }
-        Ok(())
-    }
-
-    #[cfg(not(target_os = "macos"))]
-    {
-        Err(Error::from_reason("libkrun is only available on macOS"))
-    }
+///
+/// `env` is merged over `LibkrunConfig.env`, overriding duplicate keys.
+///
+/// @deprecated Use `VmHandle.setExec()`
+#[napi(catch_unwind)]
+pub fn set_exec(
+    napi_env: Env,
+    ctx_id: u32,
+    exec_path: String,
+    args: Vec<String>,
+    env: HashMap<String, String>,
+) -> Result<()> {
+    context::set_exec(ctx_id, exec_path, args, env).map_err(|e| e.into_napi(napi_env))
 }
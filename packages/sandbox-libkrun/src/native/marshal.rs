@@ -0,0 +1,278 @@
+//! Checked conversion of a [`VmSpec`] into the C arguments libkrun takes.
+//!
+//! Every string and array handed to libkrun is built here, before the first
+//! FFI call, and problems come back as [`KrunError`]s rather than panics. The
+//! module never calls libkrun itself, so it builds and is fuzzed on any
+//! platform.
+//!
+//! This module is shared with `bin/krun-supervisor.rs` and must not depend on
+//! napi.
+
+use crate::error::KrunError;
+use crate::spec::{DiskFormat, ExecSpec, NetworkSpec, RootSpec, VmSpec, ROOT_BLOCK_ID, SCRATCH_BLOCK_ID};
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+pub fn cstring(value: &str, field: &str) -> Result<CString, KrunError> {
+    CString::new(value).map_err(|_| KrunError::invalid(field, "must not contain NUL"))
+}
+
+/// An owned `const char *const []`, terminated by NULL
+pub struct CStringArray {
+    // Owns the storage `ptrs` points into; moving the Vec keeps it in place
+    _strings: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl CStringArray {
+    pub fn new<I, S>(items: I, field: &str) -> Result<Self, KrunError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let strings = items
+            .into_iter()
+            .map(|item| cstring(item.as_ref(), field))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ptrs = strings
+            .iter()
+            .map(|s| s.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect();
+        Ok(CStringArray {
+            _strings: strings,
+            ptrs,
+        })
+    }
+
+    pub fn as_ptr(&self) -> *const *const c_char {
+        self.ptrs.as_ptr()
+    }
+
+    /// The pointer array including the NULL terminator
+    #[allow(dead_code)] // inspected by the fuzz harness
+    pub fn ptrs(&self) -> &[*const c_char] {
+        &self.ptrs
+    }
+}
+
+/// `KEY=value` entries for an `envp`
+fn envp(env: &[(String, String)], field: &str) -> Result<CStringArray, KrunError> {
+    CStringArray::new(env.iter().map(|(k, v)| format!("{}={}", k, v)), field)
+}
+
+pub struct MountArgs {
+    pub tag: CString,
+    pub path: CString,
+    /// DAX window in bytes; `None` uses `krun_add_virtiofs`
+    pub shm_size: Option<u64>,
+    /// Config field reported if libkrun rejects the mount
+    pub field: String,
+}
+
+pub struct DiskArgs {
+    pub block_id: CString,
+    pub path: CString,
+    /// `KRUN_DISK_FORMAT_*`
+    pub format: u32,
+    pub read_only: bool,
+    /// Config field reported if libkrun rejects the disk
+    pub field: String,
+}
+
+pub enum RootArgs {
+    Dir(CString),
+    /// The image itself is the first of [`ConfigArgs::disks`]
+    Disk {
+        device: CString,
+        /// `None` passes NULL, which libkrun treats like `auto`
+        fstype: Option<CString>,
+        options: Option<CString>,
+    },
+}
+
+impl RootArgs {
+    fn new(root: &RootSpec) -> Result<Self, KrunError> {
+        Ok(match root {
+            RootSpec::Dir(path) => RootArgs::Dir(cstring(path, "rootfsPath")?),
+            RootSpec::Disk {
+                device,
+                fstype,
+                options,
+                ..
+            } => RootArgs::Disk {
+                device: cstring(device, "root.device")?,
+                fstype: fstype.as_deref().map(|s| cstring(s, "root.fstype")).transpose()?,
+                options: options.as_deref().map(|s| cstring(s, "root.options")).transpose()?,
+            },
+        })
+    }
+}
+
+/// C arguments for the VM-level configuration of a spec
+pub struct ConfigArgs {
+    pub root: RootArgs,
+    pub workdir: Option<CString>,
+    pub mounts: Vec<MountArgs>,
+    pub disks: Vec<DiskArgs>,
+    /// `None` when there is no environment; an empty `envp` would still
+    /// replace libkrun's default one
+    pub env: Option<CStringArray>,
+    /// `None` keeps libkrun's default of exposing every guest port, and is
+    /// always `None` for networks other than TSI, which libkrun gives no
+    /// port map
+    pub port_map: Option<CStringArray>,
+    /// vsock port and host socket of the egress proxy
+    pub egress: Option<(u32, CString)>,
+}
+
+impl ConfigArgs {
+    pub fn new(spec: &VmSpec) -> Result<Self, KrunError> {
+        let mounts = spec
+            .mounts
+            .iter()
+            .map(|mount| {
+                let field = format!("mounts.{}", mount.tag);
+                Ok(MountArgs {
+                    tag: cstring(&mount.tag, "mounts")?,
+                    path: cstring(&mount.host_path, &field)?,
+                    shm_size: mount.shm_size_mib.map(|mib| u64::from(mib) << 20),
+                    field,
+                })
+            })
+            .collect::<Result<Vec<_>, KrunError>>()?;
+
+        let root = match &spec.root {
+            RootSpec::Disk { path, .. } => Some(DiskArgs {
+                block_id: cstring(ROOT_BLOCK_ID, "root")?,
+                path: cstring(path, "root.path")?,
+                format: DiskFormat::Raw.to_krun(),
+                read_only: false,
+                field: "root.path".to_string(),
+            }),
+            RootSpec::Dir(_) => None,
+        };
+        let mut disks = root
+            .into_iter()
+            .map(Ok)
+            .chain(spec.disks.iter().map(|disk| {
+                let field = format!("disks.{}", disk.block_id);
+                Ok(DiskArgs {
+                    block_id: cstring(&disk.block_id, "disks")?,
+                    path: cstring(&disk.path, &field)?,
+                    format: disk.format.to_krun(),
+                    read_only: disk.read_only,
+                    field,
+                })
+            }))
+            .collect::<Result<Vec<_>, KrunError>>()?;
+        if let Some(scratch) = &spec.scratch_disk {
+            disks.push(DiskArgs {
+                block_id: cstring(SCRATCH_BLOCK_ID, "scratchDisk")?,
+                path: cstring(&scratch.path, "scratchDisk")?,
+                format: DiskFormat::Raw.to_krun(),
+                read_only: false,
+                field: "scratchDisk".to_string(),
+            });
+        }
+
+        Ok(ConfigArgs {
+            root: RootArgs::new(&spec.root)?,
+            workdir: spec
+                .workdir
+                .as_deref()
+                .map(|workdir| cstring(workdir, "workdir"))
+                .transpose()?,
+            mounts,
+            disks,
+            env: Some(&spec.env)
+                .filter(|env| !env.is_empty())
+                .map(|env| envp(env, "env"))
+                .transpose()?,
+            port_map: spec
+                .port_map
+                .as_ref()
+                .filter(|_| spec.network == NetworkSpec::Tsi)
+                .map(|port_map| CStringArray::new(port_map.iter().map(|m| m.to_krun()), "portMap"))
+                .transpose()?,
+            egress: spec
+                .egress
+                .as_ref()
+                .map(|egress| cstring(&egress.socket_path, "egress").map(|path| (egress.vsock_port, path)))
+                .transpose()?,
+        })
+    }
+}
+
+/// C arguments for `krun_set_exec`
+pub struct ExecArgs {
+    pub path: CString,
+    pub argv: CStringArray,
+    pub envp: CStringArray,
+}
+
+impl ExecArgs {
+    pub fn new(exec: &ExecSpec) -> Result<Self, KrunError> {
+        Ok(ExecArgs {
+            path: cstring(&exec.path, "execPath")?,
+            argv: CStringArray::new(&exec.args, "args")?,
+            envp: envp(&exec.env, "env")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{DiskSpec, ScratchDiskSpec};
+
+    #[test]
+    fn disk_root_is_the_first_disk() {
+        let spec = VmSpec {
+            root: RootSpec::Disk {
+                path: "/images/root.img".to_string(),
+                device: "/dev/vda".to_string(),
+                fstype: None,
+                options: None,
+            },
+            disks: vec![DiskSpec {
+                block_id: "data".to_string(),
+                path: "/images/data.img".to_string(),
+                format: DiskFormat::Raw,
+                read_only: true,
+            }],
+            scratch_disk: Some(ScratchDiskSpec {
+                size_mib: 1,
+                path: "/tmp/scratch.img".to_string(),
+            }),
+            ..VmSpec::default()
+        };
+        let args = ConfigArgs::new(&spec).unwrap();
+        let disks: Vec<_> = args
+            .disks
+            .iter()
+            .map(|disk| (disk.block_id.to_str().unwrap(), disk.path.to_str().unwrap(), disk.read_only))
+            .collect();
+        assert_eq!(
+            disks,
+            [
+                ("root", "/images/root.img", false),
+                ("data", "/images/data.img", true),
+                ("scratch", "/tmp/scratch.img", false),
+            ]
+        );
+        assert_eq!(args.disks[0].field, "root.path");
+        assert!(matches!(&args.root, RootArgs::Disk { device, .. } if device.to_str() == Ok("/dev/vda")));
+    }
+
+    #[test]
+    fn dir_root_adds_no_disk() {
+        let spec = VmSpec {
+            root: RootSpec::Dir("/rootfs".to_string()),
+            ..VmSpec::default()
+        };
+        let args = ConfigArgs::new(&spec).unwrap();
+        assert!(args.disks.is_empty());
+        assert!(matches!(&args.root, RootArgs::Dir(path) if path.to_str() == Ok("/rootfs")));
+    }
+}
@@ -0,0 +1,1400 @@
+//! The built-in userspace network, attached to the VM as a unixgram backend.
+//!
+//! One end of a datagram socketpair carries the guest's Ethernet frames to
+//! a thread in this process, where smoltcp terminates ARP, IPv4 and TCP.
+//! The network is QEMU's user-mode layout:
+//!
+//! - the guest is leased [`GUEST`] by a DHCP responder on the gateway
+//! - [`DNS`] answers A queries through the host's resolver
+//! - TCP and UDP to any other address leave through host sockets, so the
+//!   guest's traffic appears as this process's; [`GATEWAY`] stands for the
+//!   host's loopback when allowed
+//!
+//! The interface accepts packets for every address (smoltcp's AnyIP), so a
+//! socket bound to the destination can stand in for each remote peer. A
+//! guest's SYN is held back until the host connection is open: it then
+//! reaches a listener made for it, or, if the host refused, no listener, so
+//! the guest gets a reset just as it would from the real peer. ICMP is only
+//! answered for the gateway and DNS addresses.
+//!
+//! The frames carry no offloads, so the VM's device is added without
+//! checksum or segmentation features.
+
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol, HardwareAddress, IpAddress,
+    IpCidr, IpEndpoint, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket,
+};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The host side of the network, reached by the guest as its router
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+/// The one address leased over DHCP
+pub const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const PREFIX_LEN: u8 = 24;
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x5a, 0x94, 0xef, 0xe4, 0x0c, 0xdd]);
+
+/// Ethernet header plus the guest's 1500-byte MTU
+const MAX_FRAME: usize = 1514;
+const TCP_BUFFER: usize = 64 * 1024;
+const MAX_TCP: usize = 512;
+const MAX_UDP: usize = 256;
+const MAX_LOOKUPS: usize = 32;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+const UDP_IDLE: Duration = Duration::from_secs(60);
+
+/// A running network, stopped on drop
+#[derive(Debug)]
+pub struct Nat {
+    stop: Arc<AtomicBool>,
+    waker: UnixStream,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Nat {
+    /// Serve the guest on the other end of `guest`
+    ///
+    /// With `host_loopback`, connections to [`GATEWAY`] go to the host's
+    /// 127.0.0.1; otherwise they are refused.
+    pub fn start(guest: UnixDatagram, host_loopback: bool) -> io::Result<Nat> {
+        let (waker, wake) = UnixStream::pair()?;
+        waker.set_nonblocking(true)?;
+        wake.set_nonblocking(true)?;
+        guest.set_nonblocking(true)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut stack = Stack::new(guest, wake, waker.try_clone()?, host_loopback);
+
+        let stopped = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("libkrun-nat".to_string())
+            .spawn(move || stack.run(&stopped))?;
+        Ok(Nat {
+            stop,
+            waker,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Nat {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = (&self.waker).write(&[0]);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Frames between the guest socket and smoltcp
+#[derive(Default)]
+struct Queue {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+struct QueueRx(Vec<u8>);
+struct QueueTx<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::Device for Queue {
+    type RxToken<'a> = QueueRx;
+    type TxToken<'a> = QueueTx<'a>;
+
+    fn receive(&mut self, _: smoltcp::time::Instant) -> Option<(QueueRx, QueueTx<'_>)> {
+        let frame = self.rx.pop_front()?;
+        Some((QueueRx(frame), QueueTx(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: smoltcp::time::Instant) -> Option<QueueTx<'_>> {
+        Some(QueueTx(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = MAX_FRAME;
+        caps
+    }
+}
+
+impl phy::RxToken for QueueRx {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for QueueTx<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        self.0.push_back(frame);
+        result
+    }
+}
+
+/// A guest connection, from its address to the peer it dialled
+type Flow = (IpEndpoint, IpEndpoint);
+
+enum Tcp {
+    /// Waiting for the host connection, holding the guest's latest SYN
+    Connecting(Vec<u8>),
+    Open {
+        handle: SocketHandle,
+        stream: TcpStream,
+        /// The host closed its side, so the guest's was closed too
+        host_closed: bool,
+        /// The guest closed its side, so the host's was shut down too
+        guest_closed: bool,
+    },
+}
+
+struct UdpFlow {
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+/// Work finished off the network thread
+enum Event {
+    Connected(Flow, io::Result<TcpStream>),
+    Resolved(IpEndpoint, Vec<u8>),
+}
+
+struct Stack {
+    iface: Interface,
+    device: Queue,
+    sockets: SocketSet<'static>,
+    guest: UnixDatagram,
+    wake: UnixStream,
+    /// Cloned into connect and lookup threads
+    waker: UnixStream,
+    events: mpsc::Receiver<Event>,
+    event_sender: mpsc::Sender<Event>,
+    host_loopback: bool,
+    dhcp: SocketHandle,
+    dns: SocketHandle,
+    lookups: usize,
+    tcp: HashMap<Flow, Tcp>,
+    /// One smoltcp socket per peer address, shared by the flows to it
+    udp_peers: HashMap<IpEndpoint, (SocketHandle, Instant)>,
+    udp: HashMap<Flow, UdpFlow>,
+    epoch: Instant,
+}
+
+impl Stack {
+    fn new(guest: UnixDatagram, wake: UnixStream, waker: UnixStream, host_loopback: bool) -> Stack {
+        let mut device = Queue::default();
+        let epoch = Instant::now();
+        let mut config = Config::new(HardwareAddress::Ethernet(GATEWAY_MAC));
+        // Seeds TCP initial sequence numbers
+        config.random_seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64)
+            ^ u64::from(std::process::id());
+        let mut iface = Interface::new(config, &mut device, smoltcp::time::Instant::ZERO);
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(GATEWAY), PREFIX_LEN));
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(DNS), PREFIX_LEN));
+        });
+        // Routing everything through an address of our own is what makes
+        // AnyIP accept it
+        let _ = iface.routes_mut().add_default_ipv4_route(GATEWAY);
+        iface.set_any_ip(true);
+
+        let mut sockets = SocketSet::new(Vec::new());
+        let dhcp = sockets.add(bound_udp(IpEndpoint::new(IpAddress::Ipv4(GATEWAY), dhcp::SERVER_PORT)));
+        let dns = sockets.add(bound_udp(IpEndpoint::new(IpAddress::Ipv4(DNS), dns::PORT)));
+        let (event_sender, events) = mpsc::channel();
+
+        Stack {
+            iface,
+            device,
+            sockets,
+            guest,
+            wake,
+            waker,
+            events,
+            event_sender,
+            host_loopback,
+            dhcp,
+            dns,
+            lookups: 0,
+            tcp: HashMap::new(),
+            udp_peers: HashMap::new(),
+            udp: HashMap::new(),
+            epoch,
+        }
+    }
+
+    fn now(&self) -> smoltcp::time::Instant {
+        smoltcp::time::Instant::from_micros(self.epoch.elapsed().as_micros() as i64)
+    }
+
+    fn run(&mut self, stop: &AtomicBool) {
+        let mut frame = vec![0; 65536];
+        while !stop.load(Ordering::Relaxed) {
+            let mut fds = vec![
+                pollfd(self.wake.as_raw_fd(), libc::POLLIN),
+                pollfd(self.guest.as_raw_fd(), libc::POLLIN),
+            ];
+            // UDP first, so their flows line up with `fds[2..]`
+            let udp: Vec<Flow> = self.udp.keys().copied().collect();
+            for flow in &udp {
+                fds.push(pollfd(self.udp[flow].socket.as_raw_fd(), libc::POLLIN));
+            }
+            for conn in self.tcp.values() {
+                if let Tcp::Open { handle, stream, host_closed, .. } = conn {
+                    let socket = self.sockets.get::<tcp::Socket>(*handle);
+                    let mut events = 0;
+                    if !host_closed && socket.can_send() {
+                        events |= libc::POLLIN;
+                    }
+                    if socket.recv_queue() > 0 {
+                        events |= libc::POLLOUT;
+                    }
+                    if events != 0 {
+                        fds.push(pollfd(stream.as_raw_fd(), events));
+                    }
+                }
+            }
+
+            let now = self.now();
+            let delay = self
+                .iface
+                .poll_delay(now, &self.sockets)
+                .map_or(Duration::from_secs(1), Duration::from)
+                .min(Duration::from_secs(1));
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, delay.as_millis() as i32) };
+            if ret < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                return;
+            }
+
+            let mut drained = [0; 64];
+            while matches!((&self.wake).read(&mut drained), Ok(n) if n > 0) {}
+            // Bounded, so a flood from the guest cannot starve the host side
+            for _ in 0..256 {
+                match self.guest.recv(&mut frame) {
+                    Ok(len) => self.inspect(&frame[..len]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => return,
+                }
+            }
+            while let Ok(event) = self.events.try_recv() {
+                self.handle(event);
+            }
+            // TCP is relayed for every connection in `service`
+            for (fd, flow) in fds[2..].iter().zip(udp) {
+                if fd.revents != 0 {
+                    self.udp_from_host(flow);
+                }
+            }
+
+            self.poll();
+            self.service();
+            self.poll();
+            self.flush();
+            self.collect();
+        }
+    }
+
+    fn poll(&mut self) {
+        let now = self.now();
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+    }
+
+    /// Send smoltcp's frames to the guest, dropping them if its socket is
+    /// full as a congested link would
+    fn flush(&mut self) {
+        while let Some(frame) = self.device.tx.pop_front() {
+            if self.guest.send(&frame).is_err() {
+                self.device.tx.clear();
+            }
+        }
+    }
+
+    /// Prepare for a frame from the guest, then queue it for smoltcp unless
+    /// it must wait or be dropped
+    fn inspect(&mut self, frame: &[u8]) {
+        let Ok(ethernet) = EthernetFrame::new_checked(frame) else {
+            return;
+        };
+        match ethernet.ethertype() {
+            EthernetProtocol::Arp => {
+                // AnyIP would answer for every address, including the one
+                // the guest probes for conflicts before taking it
+                let answered = ArpPacket::new_checked(ethernet.payload())
+                    .and_then(|arp| ArpRepr::parse(&arp))
+                    .is_ok_and(|arp| match arp {
+                        ArpRepr::EthernetIpv4 {
+                            operation,
+                            target_protocol_addr,
+                            ..
+                        } => operation != ArpOperation::Request || [GATEWAY, DNS].contains(&target_protocol_addr),
+                        _ => false,
+                    });
+                if !answered {
+                    return;
+                }
+            }
+            EthernetProtocol::Ipv4 => {
+                let Ok(ip) = Ipv4Packet::new_checked(ethernet.payload()) else {
+                    return;
+                };
+                let (src, dst) = (ip.src_addr(), ip.dst_addr());
+                match ip.next_header() {
+                    IpProtocol::Tcp => {
+                        let Ok(tcp) = TcpPacket::new_checked(ip.payload()) else {
+                            return;
+                        };
+                        if tcp.syn() && !tcp.ack() {
+                            let flow = (endpoint(src, tcp.src_port()), endpoint(dst, tcp.dst_port()));
+                            if !self.tcp_syn(flow, frame) {
+                                return;
+                            }
+                        }
+                    }
+                    IpProtocol::Udp => {
+                        let Ok(udp) = UdpPacket::new_checked(ip.payload()) else {
+                            return;
+                        };
+                        self.udp_peer(endpoint(dst, udp.dst_port()));
+                    }
+                    IpProtocol::Icmp if [GATEWAY, DNS].contains(&dst) => {}
+                    _ => return,
+                }
+            }
+            _ => return,
+        }
+        self.device.rx.push_back(frame.to_vec());
+    }
+
+    /// Where a guest connection to `peer` goes on the host, if anywhere
+    fn host_target(&self, peer: IpEndpoint) -> Option<SocketAddr> {
+        let IpAddress::Ipv4(addr) = peer.addr;
+        let port = peer.port;
+        if addr == GATEWAY {
+            return (self.host_loopback && port != dhcp::SERVER_PORT)
+                .then(|| SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        }
+        let local = u32::from(addr) >> (32 - PREFIX_LEN) == u32::from(GATEWAY) >> (32 - PREFIX_LEN);
+        let routable = !local
+            && !addr.is_loopback()
+            && !addr.is_unspecified()
+            && !addr.is_broadcast()
+            && !addr.is_multicast();
+        routable.then(|| SocketAddr::from((addr, port)))
+    }
+
+    /// Handle a SYN opening `flow`; returns whether to pass it on now
+    fn tcp_syn(&mut self, flow: Flow, frame: &[u8]) -> bool {
+        match self.tcp.get_mut(&flow) {
+            Some(Tcp::Connecting(syn)) => {
+                *syn = frame.to_vec();
+                return false;
+            }
+            Some(Tcp::Open { .. }) => return true,
+            None => {}
+        }
+        // Without a listener smoltcp resets the connection
+        let Some(target) = self.host_target(flow.1) else {
+            return true;
+        };
+        if self.tcp.len() >= MAX_TCP {
+            return true;
+        }
+
+        self.tcp.insert(flow, Tcp::Connecting(frame.to_vec()));
+        let sender = self.event_sender.clone();
+        let waker = self.waker.try_clone();
+        std::thread::spawn(move || {
+            let stream = TcpStream::connect_timeout(&target, CONNECT_TIMEOUT);
+            if sender.send(Event::Connected(flow, stream)).is_ok() {
+                if let Ok(mut waker) = waker {
+                    let _ = waker.write(&[0]);
+                }
+            }
+        });
+        false
+    }
+
+    /// Make sure a socket stands in for `peer`, if it is reachable
+    fn udp_peer(&mut self, peer: IpEndpoint) {
+        if let Some((_, last_used)) = self.udp_peers.get_mut(&peer) {
+            *last_used = Instant::now();
+            return;
+        }
+        if self.host_target(peer).is_none() || self.udp_peers.len() >= MAX_UDP {
+            return;
+        }
+        let handle = self.sockets.add(bound_udp(peer));
+        self.udp_peers.insert(peer, (handle, Instant::now()));
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Connected(flow, stream) => {
+                let Some(Tcp::Connecting(syn)) = self.tcp.remove(&flow) else {
+                    return;
+                };
+                let socket = stream
+                    .and_then(|stream| {
+                        stream.set_nonblocking(true)?;
+                        stream.set_nodelay(true)?;
+                        Ok(stream)
+                    })
+                    .ok()
+                    .and_then(|stream| {
+                        let mut socket = tcp::Socket::new(
+                            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+                            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+                        );
+                        socket.set_nagle_enabled(false);
+                        socket.listen(flow.1).ok()?;
+                        Some((socket, stream))
+                    });
+                if let Some((socket, stream)) = socket {
+                    let handle = self.sockets.add(socket);
+                    self.tcp.insert(
+                        flow,
+                        Tcp::Open {
+                            handle,
+                            stream,
+                            host_closed: false,
+                            guest_closed: false,
+                        },
+                    );
+                }
+                // Polled at once, so no other listener for the same peer
+                // can take this SYN
+                self.device.rx.push_back(syn);
+                self.poll();
+            }
+            Event::Resolved(client, reply) => {
+                self.lookups -= 1;
+                let _ = self.sockets.get_mut::<udp::Socket>(self.dns).send_slice(&reply, client);
+            }
+        }
+    }
+
+    /// Move data between smoltcp's sockets and the host
+    fn service(&mut self) {
+        let server = self.sockets.get_mut::<udp::Socket>(self.dhcp);
+        while let Ok((request, _)) = server.recv() {
+            // The client may not have its address yet
+            if let Some(reply) = dhcp::reply(request) {
+                let _ = server.send_slice(&reply, endpoint(Ipv4Addr::BROADCAST, dhcp::CLIENT_PORT));
+            }
+        }
+
+        while let Ok((query, meta)) = self.sockets.get_mut::<udp::Socket>(self.dns).recv() {
+            let client = meta.endpoint;
+            match dns::parse(query) {
+                Ok(question) if self.lookups < MAX_LOOKUPS => {
+                    self.lookups += 1;
+                    let sender = self.event_sender.clone();
+                    let waker = self.waker.try_clone();
+                    std::thread::spawn(move || {
+                        let reply = question.answer();
+                        if sender.send(Event::Resolved(client, reply)).is_ok() {
+                            if let Ok(mut waker) = waker {
+                                let _ = waker.write(&[0]);
+                            }
+                        }
+                    });
+                }
+                Ok(question) => {
+                    let reply = question.reply(dns::SERVFAIL, &[]);
+                    let _ = self.sockets.get_mut::<udp::Socket>(self.dns).send_slice(&reply, client);
+                }
+                Err(Some(reply)) => {
+                    let _ = self.sockets.get_mut::<udp::Socket>(self.dns).send_slice(&reply, client);
+                }
+                Err(None) => {}
+            }
+        }
+
+        for conn in self.tcp.values_mut() {
+            if let Tcp::Open {
+                handle,
+                stream,
+                host_closed,
+                guest_closed,
+            } = conn
+            {
+                relay(self.sockets.get_mut(*handle), stream, host_closed, guest_closed);
+            }
+        }
+
+        let peers: Vec<_> = self.udp_peers.iter().map(|(peer, (handle, _))| (*peer, *handle)).collect();
+        for (peer, handle) in peers {
+            let socket = self.sockets.get_mut::<udp::Socket>(handle);
+            let mut datagrams = Vec::new();
+            while let Ok((payload, meta)) = socket.recv() {
+                datagrams.push((meta.endpoint, payload.to_vec()));
+            }
+            for (client, payload) in datagrams {
+                let flow = (client, peer);
+                if !self.udp.contains_key(&flow) {
+                    if self.udp.len() >= MAX_UDP {
+                        continue;
+                    }
+                    let Some(socket) = self.host_target(peer).and_then(|target| host_udp(target).ok()) else {
+                        continue;
+                    };
+                    self.udp.insert(
+                        flow,
+                        UdpFlow {
+                            socket,
+                            last_used: Instant::now(),
+                        },
+                    );
+                }
+                if let Some(udp) = self.udp.get_mut(&flow) {
+                    udp.last_used = Instant::now();
+                    let _ = udp.socket.send(&payload);
+                }
+            }
+        }
+    }
+
+    /// Pass datagrams from the host back to the guest
+    fn udp_from_host(&mut self, flow: Flow) {
+        let (Some(udp), Some((handle, _))) = (self.udp.get_mut(&flow), self.udp_peers.get(&flow.1)) else {
+            return;
+        };
+        let socket = self.sockets.get_mut::<udp::Socket>(*handle);
+        let mut buf = [0; 65536];
+        while socket.can_send() {
+            match udp.socket.recv(&mut buf) {
+                Ok(len) => {
+                    udp.last_used = Instant::now();
+                    let _ = socket.send_slice(&buf[..len], flow.0);
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Drop finished connections and idle UDP flows
+    fn collect(&mut self) {
+        let sockets = &mut self.sockets;
+        self.tcp.retain(|_, conn| {
+            let Tcp::Open { handle, .. } = conn else {
+                return true;
+            };
+            // A listener still listening after its SYN was polled lost it
+            let done = matches!(
+                sockets.get::<tcp::Socket>(*handle).state(),
+                tcp::State::Closed | tcp::State::TimeWait | tcp::State::Listen
+            );
+            if done {
+                sockets.remove(*handle);
+            }
+            !done
+        });
+
+        let now = Instant::now();
+        self.udp.retain(|_, udp| now.duration_since(udp.last_used) < UDP_IDLE);
+        let udp = &self.udp;
+        self.udp_peers.retain(|peer, (handle, last_used)| {
+            let idle = now.duration_since(*last_used) >= UDP_IDLE && !udp.keys().any(|(_, flow_peer)| flow_peer == peer);
+            if idle {
+                sockets.remove(*handle);
+            }
+            !idle
+        });
+    }
+}
+
+/// Copy what each side has sent to the other, passing on closes and resets
+fn relay(socket: &mut tcp::Socket, stream: &mut TcpStream, host_closed: &mut bool, guest_closed: &mut bool) {
+    while socket.can_recv() {
+        let written = socket.recv(|data| match stream.write(data) {
+            Ok(len) => (len, Ok(len)),
+            Err(err) => (0, Err(err)),
+        });
+        match written {
+            Ok(Ok(0)) => break,
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Ok(Err(_)) | Err(_) => {
+                socket.abort();
+                return;
+            }
+        }
+    }
+    let guest_finished = matches!(
+        socket.state(),
+        tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing | tcp::State::TimeWait
+    );
+    if guest_finished && !*guest_closed && socket.recv_queue() == 0 {
+        *guest_closed = true;
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+
+    while !*host_closed && socket.can_send() {
+        let read = socket.send(|buf| match stream.read(buf) {
+            Ok(len) => (len, Ok(len)),
+            Err(err) => (0, Err(err)),
+        });
+        match read {
+            Ok(Ok(0)) => {
+                *host_closed = true;
+                socket.close();
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Ok(Err(_)) | Err(_) => {
+                socket.abort();
+                return;
+            }
+        }
+    }
+}
+
+fn endpoint(addr: Ipv4Addr, port: u16) -> IpEndpoint {
+    IpEndpoint::new(IpAddress::Ipv4(addr), port)
+}
+
+fn bound_udp(endpoint: IpEndpoint) -> udp::Socket<'static> {
+    let buffer = || udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 64], vec![0; 64 * 1024]);
+    let mut socket = udp::Socket::new(buffer(), buffer());
+    // Only fails for port 0
+    let _ = socket.bind(endpoint);
+    socket
+}
+
+fn host_udp(target: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(target)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd { fd, events, revents: 0 }
+}
+
+/// Leases [`GUEST`] to whoever asks
+mod dhcp {
+    use super::{DNS, GATEWAY, GUEST, PREFIX_LEN};
+    use std::net::Ipv4Addr;
+
+    pub const SERVER_PORT: u16 = 67;
+    pub const CLIENT_PORT: u16 = 68;
+
+    const MAGIC: [u8; 4] = [99, 130, 83, 99];
+    /// Options start after the fixed BOOTP fields and the magic cookie
+    const OPTIONS: usize = 240;
+    const LEASE_SECS: u32 = 24 * 60 * 60;
+
+    const DISCOVER: u8 = 1;
+    const OFFER: u8 = 2;
+    const REQUEST: u8 = 3;
+    const ACK: u8 = 5;
+    const NAK: u8 = 6;
+    const INFORM: u8 = 8;
+
+    /// The reply to a client message, if it calls for one
+    pub fn reply(request: &[u8]) -> Option<Vec<u8>> {
+        // A BOOTREQUEST over Ethernet
+        if request.len() < OPTIONS || request[0] != 1 || request[1] != 1 || request[2] != 6 {
+            return None;
+        }
+        if request[236..OPTIONS] != MAGIC {
+            return None;
+        }
+
+        let mut message_type = None;
+        let mut requested = None;
+        let mut server = None;
+        let mut options = &request[OPTIONS..];
+        while let [code, rest @ ..] = options {
+            match code {
+                0 => options = rest,
+                255 => break,
+                _ => {
+                    let [len, rest @ ..] = rest else {
+                        return None;
+                    };
+                    let value = rest.get(..usize::from(*len))?;
+                    match (code, value) {
+                        (53, [kind]) => message_type = Some(*kind),
+                        (50, [a, b, c, d]) => requested = Some(Ipv4Addr::new(*a, *b, *c, *d)),
+                        (54, [a, b, c, d]) => server = Some(Ipv4Addr::new(*a, *b, *c, *d)),
+                        _ => {}
+                    }
+                    options = &rest[value.len()..];
+                }
+            }
+        }
+
+        let client_ip = Ipv4Addr::new(request[12], request[13], request[14], request[15]);
+        let reply_type = match message_type? {
+            DISCOVER => OFFER,
+            // The client chose another server
+            REQUEST if server.is_some_and(|server| server != GATEWAY) => return None,
+            REQUEST if requested.or(Some(client_ip).filter(|ip| !ip.is_unspecified())) == Some(GUEST) => ACK,
+            REQUEST => NAK,
+            INFORM => ACK,
+            _ => return None,
+        };
+
+        let mut reply = vec![0; OPTIONS];
+        reply[..4].copy_from_slice(&[2, 1, 6, 0]);
+        // Transaction ID, then the broadcast flag
+        reply[4..8].copy_from_slice(&request[4..8]);
+        reply[10..12].copy_from_slice(&request[10..12]);
+        if message_type == Some(INFORM) {
+            reply[12..16].copy_from_slice(&request[12..16]);
+        } else if reply_type != NAK {
+            reply[16..20].copy_from_slice(&GUEST.octets());
+        }
+        reply[24..44].copy_from_slice(&request[24..44]);
+        reply[236..OPTIONS].copy_from_slice(&MAGIC);
+
+        reply.extend([53, 1, reply_type]);
+        reply.extend([54, 4]);
+        reply.extend(GATEWAY.octets());
+        if reply_type != NAK {
+            if message_type != Some(INFORM) {
+                reply.extend([51, 4]);
+                reply.extend(LEASE_SECS.to_be_bytes());
+            }
+            let mask = u32::MAX << (32 - PREFIX_LEN);
+            reply.extend([1, 4]);
+            reply.extend(mask.to_be_bytes());
+            reply.extend([3, 4]);
+            reply.extend(GATEWAY.octets());
+            reply.extend([6, 4]);
+            reply.extend(DNS.octets());
+        }
+        reply.push(255);
+        // The BOOTP minimum, which some clients insist on
+        reply.resize(reply.len().max(300), 0);
+        Some(reply)
+    }
+}
+
+/// Answers A queries with the host resolver's addresses
+mod dns {
+    use std::ffi::CString;
+    use std::net::Ipv4Addr;
+
+    pub const PORT: u16 = 53;
+
+    const HEADER: usize = 12;
+    const TYPE_A: u16 = 1;
+    const TYPE_ANY: u16 = 255;
+    const CLASS_IN: u16 = 1;
+    const TTL_SECS: u32 = 60;
+    /// Keeps replies within the 512 bytes of plain DNS over UDP
+    const MAX_ANSWERS: usize = 16;
+
+    const FORMERR: u8 = 1;
+    pub const SERVFAIL: u8 = 2;
+    const NXDOMAIN: u8 = 3;
+    const NOTIMP: u8 = 4;
+
+    /// The one question of a standard query
+    pub struct Question {
+        id: [u8; 2],
+        recursion_desired: bool,
+        /// The name, type and class as sent
+        raw: Vec<u8>,
+        name: String,
+        qtype: u16,
+    }
+
+    /// Parse a query; a query that cannot be answered gets the error reply
+    /// instead, unless it is too broken to reply to at all
+    pub fn parse(query: &[u8]) -> Result<Question, Option<Vec<u8>>> {
+        let header = query.get(..HEADER).ok_or(None)?;
+        // Replies are never answered
+        if header[2] & 0x80 != 0 {
+            return Err(None);
+        }
+        let error = |rcode| {
+            let mut reply = header.to_vec();
+            reply[2] = 0x80 | (header[2] & 0x79);
+            reply[3] = 0x80 | rcode;
+            reply[4..HEADER].fill(0);
+            Some(reply)
+        };
+        let opcode = (header[2] >> 3) & 0xf;
+        if opcode != 0 {
+            return Err(error(NOTIMP));
+        }
+        if header[4..6] != [0, 1] {
+            return Err(error(FORMERR));
+        }
+
+        let mut labels = Vec::new();
+        let mut pos = HEADER;
+        loop {
+            let len = usize::from(*query.get(pos).ok_or_else(|| error(FORMERR))?);
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            // No compression in questions, and labels are at most 63 bytes
+            if len > 63 {
+                return Err(error(FORMERR));
+            }
+            let label = query.get(pos..pos + len).ok_or_else(|| error(FORMERR))?;
+            labels.push(std::str::from_utf8(label).map_err(|_| error(FORMERR))?);
+            pos += len;
+        }
+        let fixed = query.get(pos..pos + 4).ok_or_else(|| error(FORMERR))?;
+        let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let question = Question {
+            id: [header[0], header[1]],
+            recursion_desired: header[2] & 1 != 0,
+            raw: query[HEADER..pos + 4].to_vec(),
+            name: labels.join("."),
+            qtype,
+        };
+        if qclass != CLASS_IN {
+            return Err(Some(question.reply(NOTIMP, &[])));
+        }
+        Ok(question)
+    }
+
+    impl Question {
+        /// Look the name up on the host, blocking, and build the reply
+        pub fn answer(&self) -> Vec<u8> {
+            match lookup(&self.name) {
+                Ok(addrs) if self.qtype == TYPE_A || self.qtype == TYPE_ANY => self.reply(0, &addrs),
+                // Only IPv4 is routed, so other types exist but have no records
+                Ok(_) => self.reply(0, &[]),
+                Err(rcode) => self.reply(rcode, &[]),
+            }
+        }
+
+        pub fn reply(&self, rcode: u8, addrs: &[Ipv4Addr]) -> Vec<u8> {
+            let addrs = &addrs[..addrs.len().min(MAX_ANSWERS)];
+            let mut reply = Vec::with_capacity(HEADER + self.raw.len() + addrs.len() * 16);
+            reply.extend(self.id);
+            // A recursive answer to a standard query
+            reply.extend([0x80 | u8::from(self.recursion_desired), 0x80 | rcode]);
+            reply.extend(1u16.to_be_bytes());
+            reply.extend((addrs.len() as u16).to_be_bytes());
+            reply.extend([0, 0, 0, 0]);
+            reply.extend(&self.raw);
+            for addr in addrs {
+                // The name is a pointer to the question's
+                reply.extend([0xc0, HEADER as u8]);
+                reply.extend(TYPE_A.to_be_bytes());
+                reply.extend(CLASS_IN.to_be_bytes());
+                reply.extend(TTL_SECS.to_be_bytes());
+                reply.extend(4u16.to_be_bytes());
+                reply.extend(addr.octets());
+            }
+            reply
+        }
+    }
+
+    /// IPv4 addresses of `name` from the host resolver, or the rcode for
+    /// why there are none
+    fn lookup(name: &str) -> Result<Vec<Ipv4Addr>, u8> {
+        let name = CString::new(name).map_err(|_| FORMERR)?;
+        let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+        hints.ai_family = libc::AF_INET;
+        hints.ai_socktype = libc::SOCK_STREAM;
+        let mut found = std::ptr::null_mut();
+        let ret = unsafe { libc::getaddrinfo(name.as_ptr(), std::ptr::null(), &hints, &mut found) };
+        if ret == libc::EAI_NONAME {
+            return Err(NXDOMAIN);
+        } else if ret != 0 {
+            return Err(SERVFAIL);
+        }
+
+        let mut addrs = Vec::new();
+        let mut next = found;
+        while let Some(info) = unsafe { next.as_ref() } {
+            if info.ai_family == libc::AF_INET && !info.ai_addr.is_null() {
+                let addr = unsafe { &*(info.ai_addr as *const libc::sockaddr_in) };
+                let addr = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+            next = info.ai_next;
+        }
+        unsafe { libc::freeaddrinfo(found) };
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::socket::dhcpv4;
+    use std::net::TcpListener;
+
+    const XID: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+    /// A DHCP client message of `kind` with the given options
+    fn dhcp_message(kind: u8, client_ip: Ipv4Addr, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut message = vec![0; 240];
+        message[..4].copy_from_slice(&[1, 1, 6, 0]);
+        message[4..8].copy_from_slice(&XID);
+        // Broadcast flag
+        message[10] = 0x80;
+        message[12..16].copy_from_slice(&client_ip.octets());
+        message[28..34].copy_from_slice(&[0x5a, 0x94, 0xef, 0xe4, 0x0c, 0xee]);
+        message[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        message.extend([53, 1, kind]);
+        for (code, value) in options {
+            message.extend([*code, value.len() as u8]);
+            message.extend(*value);
+        }
+        message.push(255);
+        message
+    }
+
+    /// The options of a DHCP reply by code
+    fn dhcp_options(reply: &[u8]) -> HashMap<u8, Vec<u8>> {
+        let mut options = HashMap::new();
+        let mut rest = &reply[240..];
+        while let [code, len, tail @ ..] = rest {
+            if *code == 255 {
+                break;
+            }
+            options.insert(*code, tail[..usize::from(*len)].to_vec());
+            rest = &tail[usize::from(*len)..];
+        }
+        options
+    }
+
+    fn yiaddr(reply: &[u8]) -> Ipv4Addr {
+        Ipv4Addr::new(reply[16], reply[17], reply[18], reply[19])
+    }
+
+    #[test]
+    fn dhcp_offers_and_acks_the_guest_address() {
+        let offer = dhcp::reply(&dhcp_message(1, Ipv4Addr::UNSPECIFIED, &[(55, &[1, 3, 6])])).unwrap();
+        assert_eq!(offer[0], 2);
+        assert_eq!(offer[4..8], XID);
+        assert_eq!(offer[10], 0x80);
+        assert_eq!(offer[28..34], [0x5a, 0x94, 0xef, 0xe4, 0x0c, 0xee]);
+        assert_eq!(yiaddr(&offer), GUEST);
+        assert!(offer.len() >= 300);
+        let options = dhcp_options(&offer);
+        assert_eq!(options[&53], [2]);
+        assert_eq!(options[&54], GATEWAY.octets());
+        assert_eq!(options[&1], [255, 255, 255, 0]);
+        assert_eq!(options[&3], GATEWAY.octets());
+        assert_eq!(options[&6], DNS.octets());
+        assert!(options.contains_key(&51));
+
+        let request = dhcp_message(3, Ipv4Addr::UNSPECIFIED, &[(50, &GUEST.octets()), (54, &GATEWAY.octets())]);
+        let ack = dhcp::reply(&request).unwrap();
+        assert_eq!(dhcp_options(&ack)[&53], [5]);
+        assert_eq!(yiaddr(&ack), GUEST);
+
+        // Renewing from the leased address, with no requested address
+        let ack = dhcp::reply(&dhcp_message(3, GUEST, &[])).unwrap();
+        assert_eq!(dhcp_options(&ack)[&53], [5]);
+    }
+
+    #[test]
+    fn dhcp_refuses_other_addresses_and_servers() {
+        let other = Ipv4Addr::new(10, 0, 2, 99);
+        let nak = dhcp::reply(&dhcp_message(3, Ipv4Addr::UNSPECIFIED, &[(50, &other.octets())])).unwrap();
+        let options = dhcp_options(&nak);
+        assert_eq!(options[&53], [6]);
+        assert_eq!(yiaddr(&nak), Ipv4Addr::UNSPECIFIED);
+        assert!(!options.contains_key(&3));
+
+        // Addressed to another server
+        let request = dhcp_message(3, Ipv4Addr::UNSPECIFIED, &[(50, &GUEST.octets()), (54, &[10, 0, 2, 1])]);
+        assert!(dhcp::reply(&request).is_none());
+
+        let ack = dhcp::reply(&dhcp_message(8, other, &[])).unwrap();
+        let options = dhcp_options(&ack);
+        assert_eq!(options[&53], [5]);
+        assert_eq!(ack[12..16], other.octets());
+        assert_eq!(yiaddr(&ack), Ipv4Addr::UNSPECIFIED);
+        assert!(!options.contains_key(&51));
+
+        // Release and decline need no reply
+        assert!(dhcp::reply(&dhcp_message(7, GUEST, &[])).is_none());
+    }
+
+    #[test]
+    fn dhcp_ignores_malformed_messages() {
+        let discover = dhcp_message(1, Ipv4Addr::UNSPECIFIED, &[(12, b"guest")]);
+        // Cut between options, only the end option is missing, which is
+        // tolerated; cut anywhere else, the message is dropped
+        let boundaries = [243, 250];
+        for len in 0..discover.len() {
+            let reply = dhcp::reply(&discover[..len]);
+            assert_eq!(reply.is_some(), boundaries.contains(&len), "truncated to {}", len);
+        }
+        let mut reply = discover.clone();
+        reply[0] = 2;
+        assert!(dhcp::reply(&reply).is_none());
+        let mut bad_magic = discover.clone();
+        bad_magic[236] = 0;
+        assert!(dhcp::reply(&bad_magic).is_none());
+        // No message type at all
+        let mut untyped = discover[..240].to_vec();
+        untyped.push(255);
+        assert!(dhcp::reply(&untyped).is_none());
+    }
+
+    /// A standard recursive query for `name`, which may hold raw labels
+    fn dns_query(id: u16, labels: &[u8], qtype: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        query.extend(labels);
+        query.extend(qtype.to_be_bytes());
+        query.extend([0, 1]);
+        query
+    }
+
+    fn labels(name: &str) -> Vec<u8> {
+        let mut labels = Vec::new();
+        for label in name.split('.') {
+            labels.push(label.len() as u8);
+            labels.extend(label.as_bytes());
+        }
+        labels.push(0);
+        labels
+    }
+
+    fn rcode(reply: &[u8]) -> u8 {
+        reply[3] & 0xf
+    }
+
+    fn answer_count(reply: &[u8]) -> u16 {
+        u16::from_be_bytes([reply[6], reply[7]])
+    }
+
+    /// The addresses of the A records in `reply`
+    fn dns_addresses(reply: &[u8], question_len: usize) -> Vec<Ipv4Addr> {
+        reply[12 + question_len..]
+            .chunks(16)
+            .map(|record| {
+                // A pointer to the question's name, then A IN
+                assert_eq!(record[..6], [0xc0, 12, 0, 1, 0, 1]);
+                assert_eq!(record[10..12], [0, 4]);
+                Ipv4Addr::new(record[12], record[13], record[14], record[15])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dns_replies_point_answers_at_the_question() {
+        let name = labels("Example.test");
+        let query = dns_query(0x1234, &name, 1);
+        let Ok(question) = dns::parse(&query) else {
+            panic!("query not parsed");
+        };
+        let addrs = [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)];
+        let reply = question.reply(0, &addrs);
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        // A response with RD echoed and RA set
+        assert_eq!(reply[2..4], [0x81, 0x80]);
+        assert_eq!(reply[4..6], [0, 1]);
+        assert_eq!(answer_count(&reply), 2);
+        // The question as sent, case included
+        assert_eq!(reply[12..12 + name.len() + 4], query[12..]);
+        assert_eq!(dns_addresses(&reply, name.len() + 4), addrs);
+
+        let many: Vec<_> = (0..40).map(|i| Ipv4Addr::new(192, 0, 2, i)).collect();
+        let reply = question.reply(0, &many);
+        assert_eq!(answer_count(&reply), 16);
+        assert!(reply.len() <= 512);
+    }
+
+    #[test]
+    fn dns_answers_a_and_aaaa_from_the_host() {
+        let name = labels("localhost");
+        let Ok(question) = dns::parse(&dns_query(1, &name, 1)) else {
+            panic!("query not parsed");
+        };
+        let reply = question.answer();
+        assert_eq!(rcode(&reply), 0);
+        assert!(dns_addresses(&reply, name.len() + 4).contains(&Ipv4Addr::LOCALHOST));
+
+        // Only IPv4 is routed, so AAAA gets no records, but no error either
+        let Ok(question) = dns::parse(&dns_query(2, &name, 28)) else {
+            panic!("query not parsed");
+        };
+        let reply = question.answer();
+        assert_eq!(rcode(&reply), 0);
+        assert_eq!(answer_count(&reply), 0);
+
+        let Ok(question) = dns::parse(&dns_query(3, &labels("no-such-host.invalid"), 1)) else {
+            panic!("query not parsed");
+        };
+        assert_ne!(rcode(&question.answer()), 0);
+    }
+
+    #[test]
+    fn dns_rejects_malformed_queries() {
+        let formerr = |query: &[u8]| match dns::parse(query) {
+            Err(Some(reply)) => {
+                assert_eq!(reply[..2], query[..2]);
+                assert_eq!(reply[2] & 0x80, 0x80);
+                rcode(&reply)
+            }
+            Err(None) => panic!("no reply"),
+            Ok(_) => panic!("parsed"),
+        };
+
+        // A compressed name: one label, then a pointer back to the header
+        let mut compressed = labels("www");
+        compressed.pop();
+        compressed.extend([0xc0, 0x0c]);
+        assert_eq!(formerr(&dns_query(1, &compressed, 1)), 1);
+        assert_eq!(formerr(&dns_query(1, &[0xc0, 0x0c], 1)), 1);
+        // Two questions
+        let mut query = dns_query(1, &labels("example.test"), 1);
+        query[5] = 2;
+        assert_eq!(formerr(&query), 1);
+        // An inverse query
+        let mut query = dns_query(1, &labels("example.test"), 1);
+        query[2] |= 1 << 3;
+        assert_eq!(formerr(&query), 4);
+        // Class CH
+        let mut query = dns_query(1, &labels("example.test"), 1);
+        let last = query.len() - 1;
+        query[last] = 3;
+        assert_eq!(formerr(&query), 4);
+        assert!(matches!(dns::parse(&dns_query(1, &[0xff, 0xfe, 0], 1)), Err(Some(_))));
+
+        let query = dns_query(1, &labels("example.test"), 1);
+        for len in 0..query.len() {
+            match dns::parse(&query[..len]) {
+                Err(None) => assert!(len < 12, "no reply to a query truncated to {}", len),
+                Err(Some(reply)) => assert_eq!(rcode(&reply), 1, "truncated to {}", len),
+                Ok(_) => panic!("parsed a query truncated to {}", len),
+            }
+        }
+        // Replies are never answered
+        let mut reply = query.clone();
+        reply[2] |= 0x80;
+        assert!(matches!(dns::parse(&reply), Err(None)));
+    }
+
+    /// The far end of the guest socket, as a smoltcp stack
+    struct Guest {
+        iface: Interface,
+        device: GuestDevice,
+        sockets: SocketSet<'static>,
+        started: Instant,
+    }
+
+    struct GuestDevice(UnixDatagram);
+    struct GuestRx(Vec<u8>);
+    struct GuestTx<'a>(&'a UnixDatagram);
+
+    impl phy::Device for GuestDevice {
+        type RxToken<'a> = GuestRx;
+        type TxToken<'a> = GuestTx<'a>;
+
+        fn receive(&mut self, _: smoltcp::time::Instant) -> Option<(GuestRx, GuestTx<'_>)> {
+            let mut frame = vec![0; MAX_FRAME];
+            let len = self.0.recv(&mut frame).ok()?;
+            frame.truncate(len);
+            Some((GuestRx(frame), GuestTx(&self.0)))
+        }
+
+        fn transmit(&mut self, _: smoltcp::time::Instant) -> Option<GuestTx<'_>> {
+            Some(GuestTx(&self.0))
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut caps = DeviceCapabilities::default();
+            caps.medium = Medium::Ethernet;
+            caps.max_transmission_unit = MAX_FRAME;
+            caps
+        }
+    }
+
+    impl phy::RxToken for GuestRx {
+        fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+            f(&self.0)
+        }
+    }
+
+    impl phy::TxToken for GuestTx<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+            let mut frame = vec![0; len];
+            let result = f(&mut frame);
+            let _ = self.0.send(&frame);
+            result
+        }
+    }
+
+    impl Guest {
+        /// Start a network and a guest attached to it
+        fn start(host_loopback: bool) -> (Guest, Nat) {
+            let (guest, host) = UnixDatagram::pair().unwrap();
+            guest.set_nonblocking(true).unwrap();
+            let nat = Nat::start(host, host_loopback).unwrap();
+            let mut device = GuestDevice(guest);
+            let mac = HardwareAddress::Ethernet(EthernetAddress(crate::spec::DEFAULT_MAC));
+            let iface = Interface::new(Config::new(mac), &mut device, smoltcp::time::Instant::ZERO);
+            let guest = Guest {
+                iface,
+                device,
+                sockets: SocketSet::new(Vec::new()),
+                started: Instant::now(),
+            };
+            (guest, nat)
+        }
+
+        /// Poll until `done` returns true; false if it never did
+        fn run_until(&mut self, mut done: impl FnMut(&mut Guest) -> bool) -> bool {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while Instant::now() < deadline {
+                let now = smoltcp::time::Instant::from_micros(self.started.elapsed().as_micros() as i64);
+                self.iface.poll(now, &mut self.device, &mut self.sockets);
+                if done(self) {
+                    return true;
+                }
+                std::thread::sleep(Duration::from_micros(200));
+            }
+            false
+        }
+
+        /// Configure the interface over DHCP, returning the DNS servers
+        fn lease(&mut self) -> Vec<Ipv4Addr> {
+            let handle = self.sockets.add(dhcpv4::Socket::new());
+            let mut dns_servers = Vec::new();
+            let leased = self.run_until(|guest| {
+                let Some(dhcpv4::Event::Configured(config)) = guest.sockets.get_mut::<dhcpv4::Socket>(handle).poll()
+                else {
+                    return false;
+                };
+                assert_eq!(config.address, smoltcp::wire::Ipv4Cidr::new(GUEST, PREFIX_LEN));
+                assert_eq!(config.router, Some(GATEWAY));
+                dns_servers = config.dns_servers.to_vec();
+                guest.iface.update_ip_addrs(|addrs| {
+                    addrs.push(IpCidr::Ipv4(config.address)).unwrap();
+                });
+                guest.iface.routes_mut().add_default_ipv4_route(GATEWAY).unwrap();
+                true
+            });
+            assert!(leased, "no DHCP lease");
+            dns_servers
+        }
+
+        fn connect(&mut self, addr: Ipv4Addr, port: u16, local_port: u16) -> SocketHandle {
+            let buffer = || tcp::SocketBuffer::new(vec![0; TCP_BUFFER]);
+            let mut socket = tcp::Socket::new(buffer(), buffer());
+            socket
+                .connect(self.iface.context(), (IpAddress::Ipv4(addr), port), local_port)
+                .unwrap();
+            self.sockets.add(socket)
+        }
+    }
+
+    /// A loopback listener echoing whatever it is sent
+    fn echo_server() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                std::thread::spawn(move || {
+                    let mut buf = [0; 4096];
+                    while let Ok(n @ 1..) = stream.read(&mut buf) {
+                        if stream.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn guest_leases_resolves_and_reaches_the_host() {
+        let port = echo_server();
+        let (mut guest, nat) = Guest::start(true);
+        assert_eq!(guest.lease(), [DNS]);
+
+        // DNS through the responder on DNS
+        let buffer = || udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 4096]);
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        socket.bind(5353).unwrap();
+        let name = labels("localhost");
+        socket
+            .send_slice(&dns_query(0x4242, &name, 1), (IpAddress::Ipv4(DNS), dns::PORT))
+            .unwrap();
+        let handle = guest.sockets.add(socket);
+        let mut reply = Vec::new();
+        assert!(guest.run_until(|guest| match guest.sockets.get_mut::<udp::Socket>(handle).recv() {
+            Ok((data, _)) => {
+                reply = data.to_vec();
+                true
+            }
+            Err(_) => false,
+        }));
+        assert_eq!(reply[..2], [0x42, 0x42]);
+        assert!(dns_addresses(&reply, name.len() + 4).contains(&Ipv4Addr::LOCALHOST));
+
+        // TCP to the gateway, which stands for the host's loopback
+        let handle = guest.connect(GATEWAY, port, 40000);
+        let payload: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let (mut sent, mut echoed) = (0, Vec::new());
+        let done = guest.run_until(|guest| {
+            let socket = guest.sockets.get_mut::<tcp::Socket>(handle);
+            if socket.can_send() && sent < payload.len() {
+                sent += socket.send_slice(&payload[sent..]).unwrap();
+            }
+            while socket.can_recv() {
+                socket
+                    .recv(|data| {
+                        echoed.extend_from_slice(data);
+                        (data.len(), ())
+                    })
+                    .unwrap();
+            }
+            echoed.len() == payload.len()
+        });
+        assert!(done, "echoed {} of {} bytes", echoed.len(), payload.len());
+        assert!(echoed == payload);
+
+        guest.sockets.get_mut::<tcp::Socket>(handle).close();
+        assert!(guest.run_until(|guest| matches!(
+            guest.sockets.get::<tcp::Socket>(handle).state(),
+            tcp::State::Closed | tcp::State::TimeWait
+        )));
+        drop(nat);
+    }
+
+    #[test]
+    fn gateway_is_refused_without_host_loopback() {
+        let port = echo_server();
+        let (mut guest, nat) = Guest::start(false);
+        guest.lease();
+
+        let handle = guest.connect(GATEWAY, port, 40000);
+        let mut established = false;
+        let closed = guest.run_until(|guest| {
+            let state = guest.sockets.get::<tcp::Socket>(handle).state();
+            established |= state == tcp::State::Established;
+            state == tcp::State::Closed
+        });
+        assert!(closed && !established, "the guest reached the host's loopback");
+        drop(nat);
+    }
+}
@@ -0,0 +1,140 @@
+//! Host ends of a VM's virtio-net backend.
+//!
+//! Except for gvproxy, which libkrun connects to itself, the crate opens the
+//! backend's socket and the supervisor receives it as [`NET_FD`]. Stream
+//! proxies are simply connected to. Datagram proxies reply to the address
+//! they receive from, so the crate binds a socket of its own under the temp
+//! directory before connecting; its path is removed once the supervisor has
+//! been reaped, and paths left behind by a process that died are removed
+//! before the first bind. The built-in [`Nat`] serves the other end of a
+//! datagram socketpair until then.
+//!
+//! [`NET_FD`]: crate::spec::NET_FD
+
+use crate::nat::Nat;
+use crate::passt::Passt;
+use crate::spec::{NetworkSpec, VmSpec};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Once;
+
+static NEXT_SOCKET: AtomicU32 = AtomicU32::new(0);
+
+/// Buffer sizes vmnet-helper and gvproxy recommend for their clients
+const SEND_BUFFER: libc::c_int = 1024 * 1024;
+const RECV_BUFFER: libc::c_int = 4 * 1024 * 1024;
+
+/// Sent first by vfkit clients, as gvproxy's vfkit mode expects
+const VFKIT_MAGIC: &[u8] = b"VFKT";
+
+/// Whatever of a backend must live until the supervisor has been reaped
+pub type Held = Box<dyn Send>;
+
+/// Open the host side of `spec`'s network, returning the end to pass to
+/// the supervisor, if any, and what to hold until it exits
+pub fn connect(spec: &VmSpec) -> io::Result<(Option<OwnedFd>, Option<Held>)> {
+    match &spec.network {
+        NetworkSpec::Tsi | NetworkSpec::None | NetworkSpec::Gvproxy { .. } => Ok((None, None)),
+        NetworkSpec::Passt { binary } => {
+            let (passt, vm_end) = Passt::spawn(binary, spec.port_map.as_deref())?;
+            Ok((Some(vm_end), Some(Box::new(passt))))
+        }
+        NetworkSpec::UnixStream { socket_path } => {
+            let stream = UnixStream::connect(socket_path).map_err(|err| with_path(err, socket_path))?;
+            Ok((Some(stream.into()), None))
+        }
+        NetworkSpec::UnixGram { socket_path, vfkit } => {
+            let bound = BoundSocket::reserve()?;
+            let socket =
+                UnixDatagram::bind(bound.path()).map_err(|err| with_path(err, &bound.path().to_string_lossy()))?;
+            socket.connect(socket_path).map_err(|err| with_path(err, socket_path))?;
+            set_buffer(&socket, libc::SO_SNDBUF, SEND_BUFFER);
+            set_buffer(&socket, libc::SO_RCVBUF, RECV_BUFFER);
+            if *vfkit {
+                socket.send(VFKIT_MAGIC)?;
+            }
+            Ok((Some(socket.into()), Some(Box::new(bound))))
+        }
+        NetworkSpec::Nat { host_loopback } => {
+            let (vm_end, host_end) = UnixDatagram::pair()?;
+            for socket in [&vm_end, &host_end] {
+                set_buffer(socket, libc::SO_SNDBUF, SEND_BUFFER);
+                set_buffer(socket, libc::SO_RCVBUF, RECV_BUFFER);
+            }
+            let nat = Nat::start(host_end, *host_loopback)?;
+            Ok((Some(vm_end.into()), Some(Box::new(nat))))
+        }
+    }
+}
+
+pub fn with_path(err: io::Error, path: &str) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path, err))
+}
+
+/// Best effort; the proxy works with the default sizes, only slower
+fn set_buffer(socket: &UnixDatagram, option: libc::c_int, size: libc::c_int) {
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+}
+
+/// Path of a socket the crate binds, removed on drop
+#[derive(Debug)]
+pub struct BoundSocket {
+    path: PathBuf,
+}
+
+impl BoundSocket {
+    /// A fresh path in this user's socket directory, for the caller to bind
+    pub fn reserve() -> io::Result<BoundSocket> {
+        static PRUNE: Once = Once::new();
+        PRUNE.call_once(|| {
+            let _ = prune();
+        });
+
+        let dir = sockets_dir();
+        // Anyone who can reach a socket can inject frames into the VM or
+        // use its egress proxy
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        let name = format!("{}-{}.sock", std::process::id(), NEXT_SOCKET.fetch_add(1, Ordering::Relaxed));
+        Ok(BoundSocket { path: dir.join(name) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for BoundSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Remove sockets bound by processes that have exited
+fn prune() -> io::Result<()> {
+    for entry in fs::read_dir(sockets_dir())? {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(crate::rootfs::orphaned) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Directory holding this user's bound sockets, one `<pid>-<n>.sock` per
+/// VM
+fn sockets_dir() -> PathBuf {
+    crate::rootfs::owned_dir("net")
+}
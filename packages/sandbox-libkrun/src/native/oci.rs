@@ -0,0 +1,907 @@
+//! Unpacking OCI images into a directory libkrun can boot from.
+//!
+//! Images are read from local disk only: an OCI image layout, either as a
+//! directory or as a tarball (which is what `docker save` writes since
+//! Docker 25), or a legacy `docker save` tarball. Layers are applied in
+//! order, honouring whiteouts and opaque directories.
+//!
+//! File modes, ownership and xattrs are kept. Without root the files cannot
+//! be chowned, so ownership and mode are also recorded in the
+//! `user.containers.override_stat` xattr, which libkrun's virtio-fs reports
+//! to the guest in place of the host's.
+
+use crate::error::KrunError;
+use napi_derive::napi;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+const OVERRIDE_STAT_XATTR: &str = "user.containers.override_stat";
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const MAX_SYMLINKS: usize = 40;
+/// Image indexes nested deeper than this are rejected
+const MAX_INDEX_DEPTH: usize = 4;
+
+/// An unpacked image and the runtime settings from its config
+#[napi(object)]
+pub struct UnpackedImage {
+    /// Number of layers applied
+    pub layers: u32,
+    /// Environment as `KEY=value`
+    pub env: Vec<String>,
+    pub entrypoint: Vec<String>,
+    pub cmd: Vec<String>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+}
+
+/// Unpack the image at `source` into `dest`, which must be missing or empty
+///
+/// On failure `dest` is left empty.
+pub fn unpack(source: &Path, dest: &Path) -> Result<UnpackedImage, KrunError> {
+    let io_error = |error| KrunError::Io {
+        op: "unpack OCI image",
+        error,
+    };
+
+    match fs::read_dir(dest) {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                return Err(KrunError::invalid("destDir", format!("{} is not empty", dest.display())));
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => fs::create_dir_all(dest).map_err(io_error)?,
+        Err(err) => return Err(io_error(err)),
+    }
+
+    unpack_into(source, dest).map_err(|err| {
+        let _ = fs::remove_dir_all(dest).and_then(|()| fs::create_dir(dest));
+        io_error(err)
+    })
+}
+
+fn unpack_into(source: &Path, dest: &Path) -> io::Result<UnpackedImage> {
+    let source = Source::open(source)?;
+    let image = Image::find(&source)?;
+    let unpacked = image.unpacked(&source)?;
+
+    let mut unpacker = Unpacker::new(dest);
+    for layer in &image.layers {
+        unpacker.apply_layer(&source, layer)?;
+    }
+    unpacker.finish()?;
+    Ok(unpacked)
+}
+
+/// Entry of a legacy `docker save` `manifest.json`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct OciIndex {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Clone, Deserialize)]
+struct Descriptor {
+    digest: String,
+    platform: Option<Platform>,
+}
+
+#[derive(Clone, Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Deserialize)]
+struct ImageConfig {
+    config: Option<RuntimeConfig>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RuntimeConfig {
+    env: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    cmd: Option<Vec<String>>,
+    working_dir: Option<String>,
+    user: Option<String>,
+}
+
+/// Config and layers of an image, as paths within its [`Source`]
+pub struct Image {
+    config: String,
+    pub layers: Vec<Layer>,
+}
+
+pub struct Layer {
+    pub path: String,
+    /// `algorithm:hex` digest of the blob, when the image records it
+    pub digest: Option<String>,
+}
+
+impl Layer {
+    /// A layer of a legacy `docker save` manifest; only blobs stored by
+    /// digest, as Docker 25 writes them, have one
+    fn legacy(path: String) -> Self {
+        let digest = path
+            .strip_prefix("blobs/")
+            .and_then(|rest| rest.split_once('/'))
+            .map(|(algorithm, hex)| format!("{}:{}", algorithm, hex))
+            .filter(|digest| blob(digest).is_ok());
+        Layer { path, digest }
+    }
+}
+
+impl Image {
+    pub fn find(source: &Source) -> io::Result<Self> {
+        if source.contains("manifest.json") {
+            let manifests: Vec<DockerManifest> = source.read_json("manifest.json")?;
+            let manifest = manifests
+                .into_iter()
+                .next()
+                .ok_or_else(|| invalid_data("manifest.json lists no images"))?;
+            return Ok(Image {
+                config: manifest.config,
+                layers: manifest.layers.into_iter().map(Layer::legacy).collect(),
+            });
+        }
+        if !source.contains("index.json") {
+            return Err(invalid_data(
+                "neither an OCI image layout nor a docker save tarball: no index.json or manifest.json",
+            ));
+        }
+
+        let index: OciIndex = source.read_json("index.json")?;
+        let mut descriptor = select(&index.manifests)?;
+        for _ in 0..MAX_INDEX_DEPTH {
+            let value: serde_json::Value = source.read_json(&blob(&descriptor.digest)?)?;
+            // An image index nested in the layout's index, as written by
+            // buildx and `docker save`
+            if value.get("manifests").is_some() {
+                let index: OciIndex = serde_json::from_value(value).map_err(invalid_data)?;
+                descriptor = select(&index.manifests)?;
+                continue;
+            }
+            let manifest: OciManifest = serde_json::from_value(value).map_err(invalid_data)?;
+            return Ok(Image {
+                config: blob(&manifest.config.digest)?,
+                layers: manifest
+                    .layers
+                    .into_iter()
+                    .map(|layer| {
+                        Ok(Layer {
+                            path: blob(&layer.digest)?,
+                            digest: Some(layer.digest),
+                        })
+                    })
+                    .collect::<io::Result<_>>()?,
+            });
+        }
+        Err(invalid_data("image indexes are nested too deeply"))
+    }
+
+    /// Runtime settings from the image config
+    pub fn unpacked(&self, source: &Source) -> io::Result<UnpackedImage> {
+        let config: ImageConfig = source.read_json(&self.config)?;
+        let runtime = config.config.unwrap_or_default();
+        Ok(UnpackedImage {
+            layers: self.layers.len() as u32,
+            env: runtime.env.unwrap_or_default(),
+            entrypoint: runtime.entrypoint.unwrap_or_default(),
+            cmd: runtime.cmd.unwrap_or_default(),
+            working_dir: runtime.working_dir.filter(|dir| !dir.is_empty()),
+            user: runtime.user.filter(|user| !user.is_empty()),
+        })
+    }
+}
+
+/// The manifest for the host architecture; the guest runs natively
+fn select(manifests: &[Descriptor]) -> io::Result<Descriptor> {
+    let arch = match std::env::consts::ARCH {
+        "aarch64" => "arm64",
+        "x86_64" => "amd64",
+        arch => arch,
+    };
+    manifests
+        .iter()
+        .find(|descriptor| {
+            descriptor
+                .platform
+                .as_ref()
+                .is_none_or(|platform| platform.os == "linux" && platform.architecture == arch)
+        })
+        .cloned()
+        .ok_or_else(|| invalid_data(format!("image has no manifest for linux/{}", arch)))
+}
+
+/// Path of the blob with `digest` within an OCI layout
+fn blob(digest: &str) -> io::Result<String> {
+    let valid = |s: &str, extra: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || extra.contains(c));
+    match digest.split_once(':') {
+        Some((algorithm, hex)) if valid(algorithm, "+._-") && valid(hex, "") => {
+            Ok(format!("blobs/{}/{}", algorithm, hex))
+        }
+        _ => Err(invalid_data(format!("invalid digest {:?}", digest))),
+    }
+}
+
+/// An image layout on disk, or a tarball of one
+pub enum Source {
+    Dir(PathBuf),
+    Tar {
+        file: File,
+        members: HashMap<PathBuf, Member>,
+    },
+}
+
+pub enum Member {
+    Data { offset: u64, size: u64 },
+    /// Symlink or hard link; `docker save` links layers shared by images
+    Link(PathBuf),
+}
+
+impl Source {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if fs::metadata(path)?.is_dir() {
+            return Ok(Source::Dir(path.to_path_buf()));
+        }
+
+        // Index the members so each can be read without unpacking the tarball
+        let file = File::open(path)?;
+        let mut members = HashMap::new();
+        let mut archive = tar::Archive::new(&file);
+        // tar reports malformed headers as `Other`
+        let not_tar = |err: io::Error| match err.kind() {
+            io::ErrorKind::Other => invalid_data(format!("{} is not a tarball or image layout", path.display())),
+            _ => err,
+        };
+        for entry in archive.entries_with_seek().map_err(not_tar)? {
+            let entry = entry.map_err(not_tar)?;
+            let Some(name) = normalize(&entry.path()?) else { continue };
+            let kind = entry.header().entry_type();
+            if kind.is_file() {
+                let member = Member::Data {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                };
+                members.insert(name, member);
+            } else if let (true, Some(target)) = (kind.is_symlink() || kind.is_hard_link(), entry.link_name()?) {
+                let target = if kind.is_symlink() {
+                    name.parent().unwrap_or(Path::new("")).join(target)
+                } else {
+                    target.into_owned()
+                };
+                members.insert(name, Member::Link(target));
+            }
+        }
+        Ok(Source::Tar { file, members })
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        match self {
+            Source::Dir(root) => root.join(name).is_file(),
+            Source::Tar { members, .. } => members.contains_key(Path::new(name)),
+        }
+    }
+
+    fn read(&self, name: &str) -> io::Result<Box<dyn Read>> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{} not found in image", name));
+        let mut path = normalize(Path::new(name)).ok_or_else(not_found)?;
+        match self {
+            Source::Dir(root) => Ok(Box::new(File::open(root.join(path))?)),
+            Source::Tar { file, members } => {
+                for _ in 0..MAX_SYMLINKS {
+                    match members.get(&path) {
+                        Some(Member::Data { offset, size }) => {
+                            let mut file = file.try_clone()?;
+                            file.seek(SeekFrom::Start(*offset))?;
+                            return Ok(Box::new(file.take(*size)));
+                        }
+                        Some(Member::Link(target)) => path = normalize(target).ok_or_else(not_found)?,
+                        None => return Err(not_found()),
+                    }
+                }
+                Err(io::Error::from_raw_os_error(libc::ELOOP))
+            }
+        }
+    }
+
+    /// `sha256:` digest of a member
+    pub fn digest(&self, name: &str) -> io::Result<String> {
+        use sha2::{Digest, Sha256};
+
+        let mut hasher = Sha256::new();
+        io::copy(&mut self.read(name)?, &mut hasher)?;
+        Ok(format!("sha256:{:x}", hasher.finalize()))
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, name: &str) -> io::Result<T> {
+        serde_json::from_reader(BufReader::new(self.read(name)?))
+            .map_err(|err| invalid_data(format!("{}: {}", name, err)))
+    }
+}
+
+/// Undo the compression of a layer, detected from its magic bytes
+fn decompress(layer: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(layer);
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(flate2::bufread::MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "zstd-compressed layers are not supported",
+        ))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Applies layers to a directory
+pub struct Unpacker<'a> {
+    dest: &'a Path,
+    /// Whether files can be chowned rather than only recording ownership
+    root: bool,
+    /// Final modes of directories, applied once every layer is in so that
+    /// read-only directories can still be filled
+    dir_modes: BTreeMap<PathBuf, u32>,
+}
+
+impl<'a> Unpacker<'a> {
+    pub fn new(dest: &'a Path) -> Self {
+        Unpacker {
+            dest,
+            root: unsafe { libc::geteuid() } == 0,
+            dir_modes: BTreeMap::new(),
+        }
+    }
+
+    /// Continue from a tree unpacked earlier, such as a copy of a cached
+    /// lower layer
+    pub fn adopt(dest: &'a Path) -> io::Result<Self> {
+        let mut unpacker = Unpacker::new(dest);
+        if !unpacker.root {
+            unpacker.open_dirs(dest)?;
+        }
+        Ok(unpacker)
+    }
+
+    /// Make the directories under `dir` writable until [`Unpacker::finish`]
+    fn open_dirs(&mut self, dir: &Path) -> io::Result<()> {
+        let mode = fs::symlink_metadata(dir)?.permissions().mode() & 0o7777;
+        fs::set_permissions(dir, Permissions::from_mode(mode | 0o700))?;
+        self.dir_modes.insert(dir.to_path_buf(), mode);
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.open_dirs(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_layer(&mut self, source: &Source, layer: &Layer) -> io::Result<()> {
+        self.apply(decompress(source.read(&layer.path)?)?)
+            .map_err(|err| io::Error::new(err.kind(), format!("layer {}: {}", layer.path, err)))
+    }
+
+    fn apply(&mut self, layer: impl Read) -> io::Result<()> {
+        let mut archive = tar::Archive::new(layer);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_ownerships(self.root);
+        archive.set_preserve_mtime(true);
+        archive.set_unpack_xattrs(true);
+        archive.set_overwrite(true);
+
+        // Paths from this layer, which its own opaque whiteouts leave alone
+        let mut written = BTreeSet::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?;
+            let path = normalize(&path)
+                .ok_or_else(|| invalid_data(format!("{} is outside the image root", path.display())))?;
+            let Some(name) = path.file_name().map(|name| name.to_string_lossy().into_owned()) else {
+                // The root directory itself
+                continue;
+            };
+
+            if name == OPAQUE_WHITEOUT {
+                let parent = path.parent().unwrap_or(Path::new(""));
+                self.clear_opaque(parent, &written)?;
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                remove(&self.resolve(&path.with_file_name(hidden))?)?;
+                continue;
+            }
+
+            let kind = entry.header().entry_type();
+            if kind.is_character_special() || kind.is_block_special() || kind.is_fifo() {
+                // libkrun's init mounts /dev, and mknod needs root
+                continue;
+            }
+
+            // An entry replaces whatever lower layers had at its path,
+            // unless both are directories
+            let target = self.resolve(&path)?;
+            match fs::symlink_metadata(&target) {
+                Ok(meta) if !(meta.is_dir() && kind.is_dir()) => remove(&target)?,
+                _ => {}
+            }
+
+            // Unpacked at the resolved path, since its parents may be
+            // symlinks meant for the guest's root, such as `var/run -> /run`
+            if !target.starts_with(self.dest) {
+                return Err(invalid_data(format!("cannot unpack {}", path.display())));
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if kind.is_hard_link() {
+                let source = entry
+                    .link_name()?
+                    .and_then(|source| normalize(&source))
+                    .ok_or_else(|| invalid_data(format!("{} links outside the image root", path.display())))?;
+                fs::hard_link(self.resolve(&source)?, &target)?;
+            } else {
+                entry.unpack(&target)?;
+            }
+            self.record(&target, entry.header())?;
+            written.insert(path);
+        }
+        Ok(())
+    }
+
+    /// Keep directories writable and record ownership the host cannot apply
+    fn record(&mut self, target: &Path, header: &tar::Header) -> io::Result<()> {
+        let kind = header.entry_type();
+        let mode = header.mode()? & 0o7777;
+        if kind.is_dir() {
+            fs::set_permissions(target, Permissions::from_mode(mode | 0o700))?;
+            self.dir_modes.insert(target.to_path_buf(), mode);
+        }
+
+        // A hard link shares the inode, and so the override, of its source
+        if !self.root && !kind.is_hard_link() {
+            let value = format!("{}:{}:0{:o}", header.uid()?, header.gid()?, mode);
+            match xattr::set(target, OVERRIDE_STAT_XATTR, value.as_bytes()) {
+                // Linux only allows user xattrs on regular files and
+                // directories
+                Err(_) if kind.is_symlink() => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove what lower layers put in `dir`, keeping this layer's entries
+    fn clear_opaque(&self, dir: &Path, written: &BTreeSet<PathBuf>) -> io::Result<()> {
+        let resolved = self.resolve(&dir.join(OPAQUE_WHITEOUT))?;
+        clear_lower(resolved.parent().unwrap_or(self.dest), dir, written)
+    }
+
+    /// Host path of `path`, following symlinks in its parents as if `dest`
+    /// were `/`; the last component is not followed
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut pending: VecDeque<OsString> = path.iter().map(OsString::from).collect();
+        let Some(last) = pending.pop_back() else {
+            return Ok(self.dest.to_path_buf());
+        };
+
+        let mut resolved = PathBuf::new();
+        let mut links = 0;
+        while let Some(part) = pending.pop_front() {
+            if part == ".." {
+                resolved.pop();
+                continue;
+            }
+            let candidate = resolved.join(&part);
+            let host = self.dest.join(&candidate);
+            if !fs::symlink_metadata(&host).is_ok_and(|meta| meta.file_type().is_symlink()) {
+                resolved = candidate;
+                continue;
+            }
+
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+            let target = fs::read_link(&host)?;
+            if target.is_absolute() {
+                resolved = PathBuf::new();
+            }
+            for component in target.components().rev() {
+                match component {
+                    Component::Normal(part) => pending.push_front(part.to_owned()),
+                    Component::ParentDir => pending.push_front("..".into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(self.dest.join(resolved).join(last))
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        for (dir, mode) in self.dir_modes.iter().rev() {
+            if fs::symlink_metadata(dir).is_ok_and(|meta| meta.is_dir()) {
+                fs::set_permissions(dir, Permissions::from_mode(*mode))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `path` relative to the root with `.` and `..` resolved lexically; `None`
+/// if it climbs above the root
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    Some(normalized)
+}
+
+/// Remove the entries of `host_dir`, which is `dir` in the image, that are
+/// not in `written`, descending into directories this layer wrote into
+fn clear_lower(host_dir: &Path, dir: &Path, written: &BTreeSet<PathBuf>) -> io::Result<()> {
+    let entries = match fs::read_dir(host_dir) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        entries => entries?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        // The entry itself or anything below it sorts first
+        let kept = written.range(path.clone()..).next().is_some_and(|w| w.starts_with(&path));
+        if !kept {
+            remove(&entry.path())?;
+        } else if entry.file_type()?.is_dir() {
+            // Lower entries of a directory the layer wrote into are hidden
+            // too; only what the layer itself wrote stays
+            clear_lower(&entry.path(), &path, written)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn invalid_data(error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, TempDir};
+    use sha2::{Digest, Sha256};
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    enum Entry<'a> {
+        File(&'a str),
+        Dir(u32),
+        Symlink(&'a str),
+        HardLink(&'a str),
+    }
+
+    fn layer(entries: &[(&str, Entry)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_uid(1000);
+            header.set_gid(1000);
+            match entry {
+                Entry::File(contents) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(contents.len() as u64);
+                    builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+                }
+                Entry::Dir(mode) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(*mode);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+                Entry::HardLink(target) => {
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_mode(0o644);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+                Entry::Symlink(target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn apply(dest: &Path, layers: &[Vec<u8>]) {
+        let mut unpacker = Unpacker::new(dest);
+        for layer in layers {
+            unpacker.apply(layer.as_slice()).unwrap();
+        }
+        unpacker.finish().unwrap();
+    }
+
+    #[test]
+    fn normalize_stays_below_the_root() {
+        let normalized = |path: &str| normalize(Path::new(path)).map(|path| path.to_string_lossy().into_owned());
+        assert_eq!(normalized("/a/./b/../c").as_deref(), Some("a/c"));
+        assert_eq!(normalized("./a/b/").as_deref(), Some("a/b"));
+        assert_eq!(normalized("/").as_deref(), Some(""));
+        assert_eq!(normalized("a/../.."), None);
+        assert_eq!(normalized("../etc/passwd"), None);
+    }
+
+    #[test]
+    fn resolve_follows_parent_symlinks_within_dest() {
+        let tmp = TempDir::new();
+        let dest = tmp.join("dest");
+        fs::create_dir_all(dest.join("real/dir")).unwrap();
+        symlink("/real", dest.join("absolute")).unwrap();
+        symlink("real/dir", dest.join("relative")).unwrap();
+        symlink("../../..", dest.join("real/dir/up")).unwrap();
+        symlink("loop", dest.join("loop")).unwrap();
+        let unpacker = Unpacker::new(&dest);
+
+        let resolve = |path: &str| unpacker.resolve(Path::new(path)).unwrap();
+        assert_eq!(resolve("absolute/file"), dest.join("real/file"));
+        assert_eq!(resolve("relative/file"), dest.join("real/dir/file"));
+        // `..` stops at dest as it would at `/`
+        assert_eq!(resolve("real/dir/up/etc/passwd"), dest.join("etc/passwd"));
+        // The last component is not followed
+        assert_eq!(resolve("real/absolute"), dest.join("real/absolute"));
+        assert_eq!(resolve("absolute"), dest.join("absolute"));
+        assert_eq!(resolve(""), dest);
+
+        let err = unpacker.resolve(Path::new("loop/file")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    }
+
+    #[test]
+    fn whiteouts_remove_lower_entries() {
+        let tmp = TempDir::new();
+        let dest = tmp.join("dest");
+        fs::create_dir(&dest).unwrap();
+        let lower = layer(&[
+            ("a/file", Entry::File("gone")),
+            ("a/kept", Entry::File("kept")),
+            ("b/dir/file", Entry::File("gone")),
+            ("real/file", Entry::File("gone")),
+            ("link", Entry::Symlink("/real")),
+        ]);
+        let upper = layer(&[
+            ("a/.wh.file", Entry::File("")),
+            (".wh.b", Entry::File("")),
+            // Through a symlinked parent, as the guest would see it
+            ("link/.wh.file", Entry::File("")),
+            (".wh.missing", Entry::File("")),
+        ]);
+        apply(&dest, &[lower, upper]);
+
+        assert!(!dest.join("a/file").exists());
+        assert_eq!(fs::read_to_string(dest.join("a/kept")).unwrap(), "kept");
+        assert!(!dest.join("b").exists());
+        assert!(dest.join("real").is_dir() && !dest.join("real/file").exists());
+        assert!(!dest.join("a/.wh.file").exists());
+    }
+
+    #[test]
+    fn opaque_dirs_hide_only_lower_entries() {
+        let tmp = TempDir::new();
+        let dest = tmp.join("dest");
+        fs::create_dir(&dest).unwrap();
+        let lower = layer(&[
+            ("d/old", Entry::File("old")),
+            ("d/sub/old", Entry::File("old")),
+            ("other/kept", Entry::File("kept")),
+        ]);
+        let upper = layer(&[
+            ("d/sub/new", Entry::File("new")),
+            ("d/.wh..wh..opq", Entry::File("")),
+            ("d/later", Entry::File("later")),
+        ]);
+        apply(&dest, &[lower, upper]);
+
+        assert!(!dest.join("d/old").exists());
+        assert!(!dest.join("d/sub/old").exists());
+        assert_eq!(fs::read_to_string(dest.join("d/sub/new")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(dest.join("d/later")).unwrap(), "later");
+        assert!(dest.join("other/kept").exists());
+        assert!(!dest.join("d/.wh..wh..opq").exists());
+    }
+
+    #[test]
+    fn entries_below_parent_symlinks_land_in_dest() {
+        let tmp = TempDir::new();
+        let dest = tmp.join("dest");
+        fs::create_dir(&dest).unwrap();
+        let lower = layer(&[
+            ("run", Entry::Dir(0o755)),
+            ("var/run", Entry::Symlink("/run")),
+            ("dangling", Entry::Symlink("/missing/dir")),
+            ("up", Entry::Symlink("../../..")),
+        ]);
+        let upper = layer(&[
+            ("var/run/file", Entry::File("pid")),
+            ("dangling/file", Entry::File("x")),
+            ("up/escaped", Entry::File("x")),
+            ("var/run/link", Entry::HardLink("var/run/file")),
+        ]);
+        apply(&dest, &[lower, upper]);
+
+        assert_eq!(fs::read_to_string(dest.join("run/file")).unwrap(), "pid");
+        assert!(dest.join("missing/dir/file").exists());
+        assert!(dest.join("escaped").exists() && !tmp.join("escaped").exists());
+        let inode = |path: &str| fs::metadata(dest.join(path)).unwrap().ino();
+        assert_eq!(inode("run/link"), inode("run/file"));
+        assert!(fs::symlink_metadata(dest.join("var/run")).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn upper_entries_replace_lower_ones() {
+        let tmp = TempDir::new();
+        let dest = tmp.join("dest");
+        fs::create_dir(&dest).unwrap();
+        let lower = layer(&[
+            ("dir/file", Entry::File("lower")),
+            ("file", Entry::File("lower")),
+            ("ro", Entry::Dir(0o555)),
+            ("ro/lower", Entry::File("lower")),
+        ]);
+        let upper = layer(&[
+            ("dir", Entry::File("now a file")),
+            ("file", Entry::Symlink("dir")),
+            ("ro/upper", Entry::File("upper")),
+        ]);
+        apply(&dest, &[lower, upper]);
+
+        assert_eq!(fs::read_to_string(dest.join("dir")).unwrap(), "now a file");
+        assert_eq!(fs::read_link(dest.join("file")).unwrap(), Path::new("dir"));
+        // Read-only directories are filled across layers and closed at the end
+        assert!(dest.join("ro/lower").exists() && dest.join("ro/upper").exists());
+        assert_eq!(fs::metadata(dest.join("ro")).unwrap().permissions().mode() & 0o7777, 0o555);
+    }
+
+    #[test]
+    fn entries_outside_the_root_are_rejected() {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..9].copy_from_slice(b"../escape");
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(0);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, io::empty()).unwrap();
+        let layer = builder.into_inner().unwrap();
+
+        let tmp = TempDir::new();
+        let dest = tmp.join("dest");
+        fs::create_dir(&dest).unwrap();
+        let err = Unpacker::new(&dest).apply(layer.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("outside the image root"));
+        assert!(!tmp.join("escape").exists());
+    }
+
+    #[test]
+    fn ownership_is_recorded_without_root() {
+        let tmp = TempDir::new();
+        let dest = tmp.join("dest");
+        fs::create_dir(&dest).unwrap();
+        let mut unpacker = Unpacker {
+            root: false,
+            ..Unpacker::new(&dest)
+        };
+        unpacker.apply(layer(&[("etc", Entry::Dir(0o750)), ("etc/file", Entry::File("x"))]).as_slice()).unwrap();
+        unpacker.finish().unwrap();
+
+        let stat = |path: &str| xattr::get(dest.join(path), OVERRIDE_STAT_XATTR).unwrap().unwrap();
+        assert_eq!(stat("etc"), b"1000:1000:0750");
+        assert_eq!(stat("etc/file"), b"1000:1000:0644");
+    }
+
+    fn add_blob(layout: &Path, contents: &[u8]) -> String {
+        let hex = format!("{:x}", Sha256::digest(contents));
+        fs::create_dir_all(layout.join("blobs/sha256")).unwrap();
+        fs::write(layout.join("blobs/sha256").join(&hex), contents).unwrap();
+        format!("sha256:{}", hex)
+    }
+
+    #[test]
+    fn unpack_reads_an_oci_layout() {
+        let tmp = TempDir::new();
+        let layout = tmp.join("layout");
+        let config = add_blob(
+            &layout,
+            br#"{"config": {"Env": ["PATH=/bin"], "Cmd": ["sh"], "WorkingDir": "", "User": "app"}}"#,
+        );
+        let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        io::Write::write_all(&mut gzipped, &layer(&[("bin/sh", Entry::File("#!"))])).unwrap();
+        let first = add_blob(&layout, &gzipped.finish().unwrap());
+        let second = add_blob(&layout, &layer(&[("bin/.wh.sh", Entry::File("")), ("etc/os", Entry::File("x"))]));
+        let manifest = add_blob(
+            &layout,
+            serde_json::json!({
+                "config": {"digest": config},
+                "layers": [{"digest": first}, {"digest": second}],
+            })
+            .to_string()
+            .as_bytes(),
+        );
+        let index = serde_json::json!({"manifests": [{"digest": manifest}]});
+        write(&layout.join("index.json"), &index.to_string());
+
+        let dest = tmp.join("dest");
+        let unpacked = unpack(&layout, &dest).unwrap();
+        assert_eq!(unpacked.layers, 2);
+        assert_eq!(unpacked.env, ["PATH=/bin"]);
+        assert_eq!(unpacked.cmd, ["sh"]);
+        assert!(unpacked.entrypoint.is_empty());
+        assert_eq!(unpacked.working_dir, None);
+        assert_eq!(unpacked.user.as_deref(), Some("app"));
+        assert!(dest.join("bin").is_dir() && !dest.join("bin/sh").exists());
+        assert!(dest.join("etc/os").exists());
+
+        let err = unpack(&layout, &dest).err().unwrap();
+        assert_eq!(err.field(), Some("destDir"));
+    }
+
+    #[test]
+    fn failed_unpack_leaves_dest_empty() {
+        let tmp = TempDir::new();
+        let layout = tmp.join("layout");
+        let missing = format!("sha256:{:x}", Sha256::digest(b"missing"));
+        let manifest = add_blob(
+            &layout,
+            serde_json::json!({
+                "config": {"digest": missing},
+                "layers": [{"digest": add_blob(&layout, &layer(&[("file", Entry::File("x"))]))}],
+            })
+            .to_string()
+            .as_bytes(),
+        );
+        write(&layout.join("index.json"), &serde_json::json!({"manifests": [{"digest": manifest}]}).to_string());
+
+        let dest = tmp.join("dest");
+        assert!(matches!(unpack(&layout, &dest), Err(KrunError::Io { .. })));
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+    }
+}
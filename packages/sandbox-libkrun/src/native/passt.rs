@@ -0,0 +1,61 @@
+//! passt processes backing a VM's network.
+//!
+//! passt is started on one end of a socketpair and the supervisor receives
+//! the other as [`NET_FD`], which it hands to `krun_set_passt_fd`. With
+//! `--fd`, passt serves that one connection and exits once the VM closes
+//! its end; a [`Passt`] is nonetheless held until the supervisor has been
+//! reaped and then killed and reaped itself, so nothing outlives the VM.
+//!
+//! [`NET_FD`]: crate::spec::NET_FD
+
+use crate::spec::PortMapping;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+
+/// A running passt process, killed and reaped on drop
+#[derive(Debug)]
+pub struct Passt {
+    child: Child,
+}
+
+impl Passt {
+    /// Start `binary`, forwarding `port_map` over TCP, and return the end
+    /// of its socket meant for the VM
+    pub fn spawn(binary: &str, port_map: Option<&[PortMapping]>) -> io::Result<(Passt, OwnedFd)> {
+        let (vm_end, passt_end) = UnixStream::pair()?;
+        let passt_raw = passt_end.as_raw_fd();
+
+        let mut command = Command::new(binary);
+        command
+            .args(["--quiet", "--foreground", "--fd"])
+            .arg(passt_raw.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        for mapping in port_map.unwrap_or_default() {
+            command.arg("--tcp-ports").arg(mapping.to_passt());
+        }
+        unsafe {
+            // The socketpair is close-on-exec; passt keeps its end
+            command.pre_exec(move || {
+                if libc::fcntl(passt_raw, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        drop(passt_end);
+
+        Ok((Passt { child }, vm_end.into()))
+    }
+}
+
+impl Drop for Passt {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,430 @@
+//! Which host directories may be shared with a guest, and which hosts it
+//! may reach through the egress proxy.
+//!
+//! A virtio-fs mount gives the guest everything under the host directory,
+//! and a caller driven by untrusted input can ask for `$HOME` or `/` as
+//! easily as a project checkout. The same goes for the root directory, and
+//! for disk images. These paths are canonicalized, so symlinks and `..`
+//! cannot sidestep the rules, then checked against the rules below, and
+//! then attached in their canonical form:
+//!
+//! - the allow list: the mount must lie inside one of its directories
+//! - the deny list: the mount must neither lie inside nor contain one of
+//!   its directories
+//! - credential stores in the user's home directory such as `~/.ssh`,
+//!   which the mount must neither lie inside nor contain
+//!
+//! Egress rules name hosts rather than paths; see [`EgressPolicy`].
+//!
+//! This module must not depend on napi; the fuzz harness builds it.
+
+use crate::error::KrunError;
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, OsStr};
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Credential stores under `$HOME` that are never shared by default
+const SENSITIVE: &[&str] = &[
+    ".ssh",
+    ".gnupg",
+    ".aws",
+    ".azure",
+    ".kube",
+    ".docker",
+    ".netrc",
+    ".npmrc",
+    ".pypirc",
+    ".git-credentials",
+    ".config/gcloud",
+    ".config/gh",
+    // macOS
+    "Library/Keychains",
+];
+
+/// A resolved mount policy
+pub struct MountPolicy {
+    /// `None` allows any directory
+    allow: Option<Vec<PathBuf>>,
+    deny: Vec<PathBuf>,
+    sensitive: Vec<PathBuf>,
+}
+
+impl MountPolicy {
+    /// Resolve the configured lists, reporting entries that are not
+    /// absolute paths
+    pub fn new(
+        allow: Option<Vec<String>>,
+        deny: Option<Vec<String>>,
+        allow_sensitive: bool,
+        problems: &mut Vec<KrunError>,
+    ) -> Self {
+        let mut resolve_all = |paths: Vec<String>, field: &str| -> Vec<PathBuf> {
+            let mut resolved = Vec::new();
+            for path in paths {
+                if Path::new(&path).is_absolute() {
+                    resolved.push(resolve(Path::new(&path)));
+                } else {
+                    problems.push(KrunError::invalid(field, format!("{:?} is not an absolute path", path)));
+                }
+            }
+            resolved
+        };
+        let allow = allow.map(|allow| resolve_all(allow, "mountPolicy.allow"));
+        let deny = resolve_all(deny.unwrap_or_default(), "mountPolicy.deny");
+
+        let sensitive = Self::sensitive(home_dir().as_deref(), allow_sensitive);
+        MountPolicy { allow, deny, sensitive }
+    }
+
+    /// Credential stores under `home` to protect
+    fn sensitive(home: Option<&Path>, allow_sensitive: bool) -> Vec<PathBuf> {
+        match home.filter(|home| home.is_absolute()) {
+            Some(home) if !allow_sensitive => {
+                let home = resolve(home);
+                SENSITIVE.iter().map(|path| resolve(&home.join(path))).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The canonical form of `host_path` to mount, or why the mount
+    /// reported as `field` is refused
+    ///
+    /// Paths that cannot be resolved are left to the existence checks, and
+    /// give `None`.
+    pub fn check(&self, field: &str, host_path: &str) -> Result<Option<String>, KrunError> {
+        let Ok(path) = std::fs::canonicalize(host_path) else {
+            return Ok(None);
+        };
+        let denied = |rule, reason: String| {
+            Err(KrunError::MountDenied {
+                field: field.to_string(),
+                rule,
+                reason,
+            })
+        };
+
+        if let Some(allow) = &self.allow {
+            if !allow.iter().any(|dir| path.starts_with(dir)) {
+                return denied("notAllowed", format!("{} is outside mountPolicy.allow", path.display()));
+            }
+        }
+        if let Some(dir) = self.deny.iter().find(|dir| overlaps(&path, dir)) {
+            return denied("denied", exposure(&path, dir));
+        }
+        if let Some(dir) = self.sensitive.iter().find(|dir| overlaps(&path, dir)) {
+            return denied("sensitive", exposure(&path, dir));
+        }
+        match path.into_os_string().into_string() {
+            Ok(path) => Ok(Some(path)),
+            Err(path) => Err(KrunError::invalid(
+                field,
+                format!("{} resolves to {:?}, which is not UTF-8", host_path, path),
+            )),
+        }
+    }
+}
+
+/// The user's home directory: `$HOME`, or the password database's entry
+/// when it is unset or relative
+fn home_dir() -> Option<PathBuf> {
+    match std::env::var_os("HOME").map(PathBuf::from) {
+        Some(home) if home.is_absolute() => Some(home),
+        _ => passwd_home(),
+    }
+}
+
+fn passwd_home() -> Option<PathBuf> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let err = unsafe { libc::getpwuid_r(libc::getuid(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+        if err == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if err != 0 || found.is_null() || entry.pw_dir.is_null() {
+            return None;
+        }
+        // Points into `buf`
+        let dir = unsafe { CStr::from_ptr(entry.pw_dir) };
+        return Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())));
+    }
+}
+
+/// Canonical form of `path`, or `path` itself if it does not exist
+fn resolve(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether mounting `path` would share `dir`
+fn overlaps(path: &Path, dir: &Path) -> bool {
+    path.starts_with(dir) || dir.starts_with(path)
+}
+
+fn exposure(path: &Path, dir: &Path) -> String {
+    if path == dir {
+        format!("{} is protected", path.display())
+    } else if path.starts_with(dir) {
+        format!("{} is inside {}", path.display(), dir.display())
+    } else {
+        format!("{} would expose {}", path.display(), dir.display())
+    }
+}
+
+/// A resolved egress policy
+///
+/// Patterns are host names, IP addresses, or `*.` followed by a domain,
+/// which matches every subdomain but not the domain itself. Names are
+/// compared case-insensitively and without a trailing dot.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EgressPolicy {
+    /// `None` allows any host not denied
+    allow: Option<Vec<String>>,
+    deny: Vec<String>,
+}
+
+impl EgressPolicy {
+    /// Normalize the configured lists, reporting entries that are not host
+    /// patterns
+    pub fn new(allow: Option<Vec<String>>, deny: Option<Vec<String>>, problems: &mut Vec<KrunError>) -> Self {
+        let mut normalize_all = |patterns: Vec<String>, field: &str| -> Vec<String> {
+            let mut normalized = Vec::new();
+            for pattern in patterns {
+                match normalize_pattern(&pattern) {
+                    Some(pattern) => normalized.push(pattern),
+                    None => problems.push(KrunError::invalid(
+                        field,
+                        format!("{:?} is not a host name, IP address or *.domain pattern", pattern),
+                    )),
+                }
+            }
+            normalized
+        };
+        let allow = allow.map(|allow| normalize_all(allow, "egress.allow"));
+        let deny = normalize_all(deny.unwrap_or_default(), "egress.deny");
+        EgressPolicy { allow, deny }
+    }
+
+    /// The rule refusing connections to `host`, which must be normalized by
+    /// [`normalize_host`], if one does
+    pub fn check(&self, host: &str) -> Option<&'static str> {
+        if self.deny.iter().any(|pattern| matches(pattern, host)) {
+            return Some("denied");
+        }
+        match &self.allow {
+            Some(allow) if !allow.iter().any(|pattern| matches(pattern, host)) => Some("notAllowed"),
+            _ => None,
+        }
+    }
+}
+
+/// `host` in the form patterns are compared in: lowercase without a
+/// trailing dot, and IPv6 addresses without brackets. `None` if it is
+/// neither a host name nor an IP address.
+pub fn normalize_host(host: &str) -> Option<String> {
+    let bare = host.strip_prefix('[').and_then(|host| host.strip_suffix(']'));
+    if let Ok(addr) = bare.unwrap_or(host).parse::<IpAddr>() {
+        return Some(addr.to_string());
+    }
+    let name = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    valid.then_some(name)
+}
+
+fn normalize_pattern(pattern: &str) -> Option<String> {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            let domain = normalize_host(domain)?;
+            // An address has no subdomains
+            (domain.parse::<IpAddr>().is_err()).then(|| format!("*.{}", domain))
+        }
+        None => normalize_host(pattern),
+    }
+}
+
+fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+        None => pattern == host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    fn strings(paths: &[PathBuf]) -> Vec<String> {
+        paths.iter().map(|path| path.to_str().unwrap().to_string()).collect()
+    }
+
+    /// A policy protecting the credential stores under `home`
+    fn mount_policy(allow: Option<&[PathBuf]>, deny: &[PathBuf], home: &Path) -> MountPolicy {
+        let mut problems = Vec::new();
+        let mut policy = MountPolicy::new(allow.map(strings), Some(strings(deny)), true, &mut problems);
+        assert!(problems.is_empty());
+        policy.sensitive = MountPolicy::sensitive(Some(home), false);
+        policy
+    }
+
+    fn rule(result: Result<Option<String>, KrunError>) -> Option<&'static str> {
+        match result {
+            Err(KrunError::MountDenied { rule, .. }) => Some(rule),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => None,
+        }
+    }
+
+    #[test]
+    fn home_falls_back_to_the_password_database() {
+        let home = passwd_home().unwrap();
+        assert!(home.is_absolute());
+    }
+
+    #[test]
+    fn symlink_into_a_credential_store_is_refused() {
+        let tmp = TempDir::new();
+        let home = tmp.join("home");
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        fs::create_dir_all(home.join("project")).unwrap();
+        symlink(home.join(".ssh"), tmp.join("innocent")).unwrap();
+        symlink(home.join(".ssh"), home.join("project/keys")).unwrap();
+        let policy = mount_policy(None, &[], &home);
+
+        let check = |path: PathBuf| rule(policy.check("mounts.a", path.to_str().unwrap()));
+        assert_eq!(check(tmp.join("innocent")), Some("sensitive"));
+        assert_eq!(check(home.join("project/keys")), Some("sensitive"));
+        assert_eq!(check(home.join("project/../.ssh")), Some("sensitive"));
+        // Containing a store exposes it too
+        assert_eq!(check(home.clone()), Some("sensitive"));
+        assert_eq!(check(home.join("project")), None);
+
+        let mut problems = Vec::new();
+        let open = MountPolicy::new(None, None, true, &mut problems);
+        assert_eq!(rule(open.check("mounts.a", tmp.join("innocent").to_str().unwrap())), None);
+    }
+
+    #[test]
+    fn allow_list_matches_whole_components() {
+        let tmp = TempDir::new();
+        let projects = tmp.join("projects");
+        for dir in ["projects/app", "projects-evil", "projectsx"] {
+            fs::create_dir_all(tmp.join(dir)).unwrap();
+        }
+        symlink(tmp.join("projects-evil"), projects.join("escape")).unwrap();
+        let policy = mount_policy(Some(std::slice::from_ref(&projects)), &[], &tmp.join("home"));
+
+        let check = |path: PathBuf| rule(policy.check("mounts.a", path.to_str().unwrap()));
+        assert_eq!(check(projects.clone()), None);
+        assert_eq!(check(projects.join("app")), None);
+        assert_eq!(check(tmp.join("projects-evil")), Some("notAllowed"));
+        assert_eq!(check(tmp.join("projectsx")), Some("notAllowed"));
+        assert_eq!(check(projects.join("escape")), Some("notAllowed"));
+        assert_eq!(check(projects.join("app/../../projects-evil")), Some("notAllowed"));
+        // Left to the existence checks
+        assert_eq!(policy.check("mounts.a", tmp.join("missing").to_str().unwrap()).unwrap(), None);
+    }
+
+    #[test]
+    fn deny_list_refuses_both_ways() {
+        let tmp = TempDir::new();
+        let secret = tmp.join("data/secret");
+        fs::create_dir_all(secret.join("inner")).unwrap();
+        fs::create_dir_all(tmp.join("data/public")).unwrap();
+        fs::create_dir_all(tmp.join("data/secretive")).unwrap();
+        let policy = mount_policy(None, std::slice::from_ref(&secret), &tmp.join("home"));
+
+        let check = |path: PathBuf| rule(policy.check("mounts.a", path.to_str().unwrap()));
+        assert_eq!(check(secret.clone()), Some("denied"));
+        assert_eq!(check(secret.join("inner")), Some("denied"));
+        assert_eq!(check(tmp.join("data")), Some("denied"));
+        assert_eq!(check(tmp.join("data/public")), None);
+        assert_eq!(check(tmp.join("data/secretive")), None);
+    }
+
+    #[test]
+    fn check_gives_the_canonical_path() {
+        let tmp = TempDir::new();
+        fs::create_dir_all(tmp.join("projects/app")).unwrap();
+        symlink(tmp.join("projects/app"), tmp.join("link")).unwrap();
+        let policy = mount_policy(None, &[], &tmp.join("home"));
+
+        let canonical = fs::canonicalize(tmp.join("projects/app")).unwrap();
+        for path in [tmp.join("link"), tmp.join("projects/./app/../app")] {
+            let checked = policy.check("mounts.a", path.to_str().unwrap()).unwrap();
+            assert_eq!(checked.as_deref(), canonical.to_str());
+        }
+    }
+
+    #[test]
+    fn relative_policy_entries_are_reported() {
+        let mut problems = Vec::new();
+        MountPolicy::new(
+            Some(vec!["relative".to_string()]),
+            Some(vec!["/ok".to_string(), "also/relative".to_string()]),
+            false,
+            &mut problems,
+        );
+        let fields: Vec<_> = problems.iter().map(|problem| problem.field().unwrap()).collect();
+        assert_eq!(fields, ["mountPolicy.allow", "mountPolicy.deny"]);
+    }
+
+    fn egress_policy(allow: Option<&[&str]>, deny: &[&str]) -> EgressPolicy {
+        let to_strings = |patterns: &[&str]| patterns.iter().map(|pattern| pattern.to_string()).collect();
+        let mut problems = Vec::new();
+        let policy = EgressPolicy::new(allow.map(to_strings), Some(to_strings(deny)), &mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+        policy
+    }
+
+    #[test]
+    fn egress_wildcards_match_subdomains_only() {
+        let policy = egress_policy(Some(&["*.Example.com.", "api.test", "10.0.0.1"]), &[]);
+        assert_eq!(policy.check("www.example.com"), None);
+        assert_eq!(policy.check("a.b.example.com"), None);
+        assert_eq!(policy.check("example.com"), Some("notAllowed"));
+        assert_eq!(policy.check("badexample.com"), Some("notAllowed"));
+        assert_eq!(policy.check("api.test"), None);
+        assert_eq!(policy.check("www.api.test"), Some("notAllowed"));
+        assert_eq!(policy.check("10.0.0.1"), None);
+        assert_eq!(policy.check("10.0.0.2"), Some("notAllowed"));
+    }
+
+    #[test]
+    fn egress_deny_wins_over_allow() {
+        let policy = egress_policy(Some(&["*.example.com"]), &["evil.example.com"]);
+        assert_eq!(policy.check("evil.example.com"), Some("denied"));
+        assert_eq!(policy.check("good.example.com"), None);
+        let open = egress_policy(None, &["*.example.com"]);
+        assert_eq!(open.check("anything.test"), None);
+        assert_eq!(open.check("www.example.com"), Some("denied"));
+    }
+
+    #[test]
+    fn egress_hosts_are_normalized() {
+        assert_eq!(normalize_host("Example.COM.").as_deref(), Some("example.com"));
+        assert_eq!(normalize_host("[::1]").as_deref(), Some("::1"));
+        assert_eq!(normalize_host("0:0::1").as_deref(), Some("::1"));
+        assert_eq!(normalize_host(""), None);
+        assert_eq!(normalize_host("a..b"), None);
+        assert_eq!(normalize_host("white space"), None);
+        assert_eq!(normalize_host(&"a".repeat(64)), None);
+
+        let mut problems = Vec::new();
+        EgressPolicy::new(Some(vec!["*.10.0.0.1".to_string(), "*".to_string()]), None, &mut problems);
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().all(|problem| problem.field() == Some("egress.allow")));
+    }
+}
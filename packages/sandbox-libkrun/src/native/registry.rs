@@ -0,0 +1,345 @@
+//! Per-context bookkeeping for contexts created through the napi API.
+//!
+//! Every context moves through `Created → Configured → Running → Exited →
+//! Freed`. Operations are checked against the current state before they reach
+//! libkrun, since libkrun itself does not guard against misuse such as
+//! starting a context twice or setting the exec of a running VM.
+
+use crate::checkpoint::Checkpoints;
+use crate::egress::EgressProxy;
+use crate::error::KrunError;
+use crate::rootfs::RootfsClone;
+use crate::scratch::ScratchImage;
+use crate::spec::VmSpec;
+use crate::store::Lease;
+use crate::supervisor::Supervisor;
+use napi_derive::napi;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Lifecycle state of a libkrun context
+#[napi(string_enum = "camelCase")]
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmState {
+    /// Context created and VM-level configuration applied
+    Created,
+    /// Exec set; the VM can be started
+    Configured,
+    /// Supervisor process running
+    Running,
+    /// Supervisor process has exited
+    Exited,
+    /// Context freed; kept so reuse is reported rather than reaching libkrun
+    Freed,
+}
+
+/// Operations that change or depend on the context state
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    SetExec,
+    Start,
+    Shutdown,
+    Free,
+    /// Take or drop a checkpoint of the mounts
+    Checkpoint,
+    /// Restore the mounts from a checkpoint
+    Rollback { force: bool },
+}
+
+impl Action {
+    fn allowed_from(self, state: VmState) -> bool {
+        use VmState::*;
+        match self {
+            Action::SetExec => matches!(state, Created | Configured),
+            Action::Start => state == Configured,
+            Action::Shutdown => matches!(state, Running | Exited),
+            Action::Free | Action::Checkpoint => state != Freed,
+            Action::Rollback { force } => state != Freed && (force || state != Running),
+        }
+    }
+
+    /// State after the action succeeds; `None` leaves it unchanged
+    fn target(self) -> Option<VmState> {
+        match self {
+            Action::SetExec => Some(VmState::Configured),
+            Action::Start => Some(VmState::Running),
+            Action::Shutdown | Action::Checkpoint | Action::Rollback { .. } => None,
+            Action::Free => Some(VmState::Freed),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::SetExec => "set the exec of",
+            Action::Start => "start",
+            Action::Shutdown => "shut down",
+            Action::Free => "free",
+            Action::Checkpoint => "checkpoint",
+            Action::Rollback { .. } => "roll back",
+        })
+    }
+}
+
+/// An action that is not valid for the context's current state
+#[derive(Debug)]
+pub struct TransitionError {
+    pub ctx_id: u32,
+    pub action: Action,
+    /// `None` if the context was never created through this module
+    pub state: Option<VmState>,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.state {
+            None => write!(
+                f,
+                "Cannot {} context {}: unknown context",
+                self.action, self.ctx_id
+            ),
+            Some(VmState::Created) if matches!(self.action, Action::Start) => write!(
+                f,
+                "Cannot start context {}: setExec must be called first",
+                self.ctx_id
+            ),
+            Some(VmState::Running) if matches!(self.action, Action::Rollback { .. }) => write!(
+                f,
+                "Cannot roll back context {} while its VM is running; pass force to roll back anyway",
+                self.ctx_id
+            ),
+            Some(state) => write!(
+                f,
+                "Cannot {} context {}: VM is {:?}",
+                self.action, self.ctx_id, state
+            ),
+        }
+    }
+}
+
+impl From<TransitionError> for KrunError {
+    fn from(err: TransitionError) -> Self {
+        KrunError::InvalidState(err.to_string())
+    }
+}
+
+pub struct Context {
+    /// Configuration replayed by the supervisor on start
+    pub spec: VmSpec,
+    /// Supervisor process, once the VM has been started
+    pub supervisor: Option<Supervisor>,
+    /// Host files the spec refers to, released with the context
+    pub backing: Backing,
+    state: VmState,
+}
+
+/// Host files backing a context
+#[derive(Debug, Default)]
+pub struct Backing {
+    /// Private rootfs the spec points at
+    pub rootfs: Option<RootfsClone>,
+    /// Lease on the cached image the rootfs was cloned from
+    pub lease: Option<Lease>,
+    /// Scratch disk image; handed to the supervisor on start so it goes
+    /// away as soon as the VM exits
+    pub scratch: Option<ScratchImage>,
+    /// Checkpoints of the writable mounts, shared with operations running
+    /// off the registry lock
+    pub checkpoints: Arc<Mutex<Checkpoints>>,
+    /// Proxy for the guest's outbound connections, kept across the VM's
+    /// run so its record of refused attempts can still be read
+    pub egress: Option<EgressProxy>,
+}
+
+impl Context {
+    pub fn state(&mut self) -> VmState {
+        if self.state == VmState::Running
+            && self.supervisor.as_ref().is_some_and(Supervisor::has_exited)
+        {
+            self.state = VmState::Exited;
+        }
+        self.state
+    }
+}
+
+/// How many freed contexts are remembered for reporting reuse
+const MAX_TOMBSTONES: usize = 1024;
+
+struct Registry {
+    contexts: BTreeMap<u32, Context>,
+    /// Freed contexts still in `contexts`, oldest first
+    tombstones: VecDeque<u32>,
+}
+
+impl Registry {
+    /// Remember that `ctx_id` was freed, forgetting the oldest tombstone
+    /// past `MAX_TOMBSTONES`
+    fn bury(&mut self, ctx_id: u32) {
+        self.tombstones.push_back(ctx_id);
+        while self.tombstones.len() > MAX_TOMBSTONES {
+            let Some(oldest) = self.tombstones.pop_front() else {
+                break;
+            };
+            // The id may since have been reused by a new context
+            if self.contexts.get(&oldest).is_some_and(|ctx| ctx.state == VmState::Freed) {
+                self.contexts.remove(&oldest);
+            }
+        }
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    contexts: BTreeMap::new(),
+    tombstones: VecDeque::new(),
+});
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    // A panic while holding the lock cannot leave the map half-updated
+    REGISTRY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn insert(ctx_id: u32, spec: VmSpec, backing: Backing) {
+    let mut registry = registry();
+    // A reused id is no longer a tombstone
+    registry.tombstones.retain(|id| *id != ctx_id);
+    registry.contexts.insert(
+        ctx_id,
+        Context {
+            spec,
+            supervisor: None,
+            backing,
+            state: VmState::Created,
+        },
+    );
+}
+
+pub fn state(ctx_id: u32) -> Option<VmState> {
+    registry().contexts.get_mut(&ctx_id).map(Context::state)
+}
+
+/// Run `f` against `ctx_id` without changing its state; `None` if the
+/// context is unknown
+pub fn inspect<R>(ctx_id: u32, f: impl FnOnce(&mut Context) -> R) -> Option<R> {
+    registry().contexts.get_mut(&ctx_id).map(f)
+}
+
+/// Run `action` against `ctx_id` if its current state allows it
+///
+/// `f` runs with the registry locked, so concurrent actions on the same
+/// context are serialized. The state only advances when `f` succeeds.
+pub fn transition<R, E>(
+    ctx_id: u32,
+    action: Action,
+    f: impl FnOnce(&mut Context) -> Result<R, E>,
+) -> Result<R, E>
+where
+    E: From<TransitionError>,
+{
+    let mut registry = registry();
+    let Some(ctx) = registry.contexts.get_mut(&ctx_id) else {
+        return Err(TransitionError {
+            ctx_id,
+            action,
+            state: None,
+        }
+        .into());
+    };
+
+    let state = ctx.state();
+    if !action.allowed_from(state) {
+        return Err(TransitionError {
+            ctx_id,
+            action,
+            state: Some(state),
+        }
+        .into());
+    }
+
+    let result = f(ctx)?;
+    if let Some(target) = action.target() {
+        ctx.state = target;
+    }
+    if matches!(action, Action::Free) {
+        // Drop everything but the tombstone
+        ctx.spec = VmSpec::default();
+        ctx.supervisor = None;
+        ctx.backing = Backing::default();
+        registry.bury(ctx_id);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The registry is global, so each test keeps to its own ids, and
+    // tests that free contexts take turns since freeing buries tombstones
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn serial() -> std::sync::MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn free(ctx_id: u32) {
+        transition(ctx_id, Action::Free, |_| Ok::<_, KrunError>(())).unwrap();
+    }
+
+    #[test]
+    fn actions_follow_the_lifecycle() {
+        let _serial = serial();
+        let ctx_id = 1_000_000;
+        insert(ctx_id, VmSpec::default(), Backing::default());
+        let err = transition(ctx_id, Action::Start, |_| Ok::<_, KrunError>(())).unwrap_err();
+        assert!(err.to_string().contains("setExec must be called first"));
+
+        transition(ctx_id, Action::SetExec, |_| Ok::<_, KrunError>(())).unwrap();
+        assert_eq!(state(ctx_id), Some(VmState::Configured));
+
+        // A failed action leaves the state alone
+        let failed = transition(ctx_id, Action::Start, |_| {
+            Err::<(), _>(KrunError::NOT_MACOS)
+        });
+        assert!(failed.is_err());
+        assert_eq!(state(ctx_id), Some(VmState::Configured));
+
+        free(ctx_id);
+        assert_eq!(state(ctx_id), Some(VmState::Freed));
+        let err = transition(ctx_id, Action::Free, |_| Ok::<_, KrunError>(())).unwrap_err();
+        assert!(err.to_string().contains("VM is Freed"));
+        let err = transition(ctx_id + 1, Action::Free, |_| Ok::<_, KrunError>(())).unwrap_err();
+        assert!(err.to_string().contains("unknown context"));
+    }
+
+    #[test]
+    fn tombstones_are_capped() {
+        let _serial = serial();
+        let first = 2_000_000;
+        let reused = first + 1;
+        for ctx_id in first..first + MAX_TOMBSTONES as u32 {
+            insert(ctx_id, VmSpec::default(), Backing::default());
+            free(ctx_id);
+        }
+        assert_eq!(state(first), Some(VmState::Freed));
+
+        // Reusing an id takes it out of the tombstones
+        insert(reused, VmSpec::default(), Backing::default());
+
+        let last = first + MAX_TOMBSTONES as u32;
+        insert(last, VmSpec::default(), Backing::default());
+        free(last);
+        insert(last + 1, VmSpec::default(), Backing::default());
+        free(last + 1);
+        assert_eq!(state(first), None);
+        assert_eq!(state(reused), Some(VmState::Created));
+        assert_eq!(state(first + 2), Some(VmState::Freed));
+        assert_eq!(state(last + 1), Some(VmState::Freed));
+
+        let registry = registry();
+        assert!(registry.tombstones.len() <= MAX_TOMBSTONES);
+    }
+}
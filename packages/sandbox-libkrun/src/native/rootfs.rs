@@ -0,0 +1,605 @@
+//! Private per-context copies of a base rootfs.
+//!
+//! virtiofs passes guest writes straight through to the host, so contexts
+//! sharing a rootfs directory see each other's changes. [`RootfsClone`]
+//! builds a private tree from a base directory as cheaply as the filesystem
+//! allows:
+//!
+//! - files are reflinked where the filesystem supports it (APFS, btrfs, XFS)
+//! - otherwise they are copied up front
+//!
+//! A clone never shares an inode with its base: the guest owns the files
+//! of a virtiofs mount, so it could make a shared file writable and change
+//! the base and every other clone through it. Only [`clone_into_shared`],
+//! for trees no guest can reach, hardlinks the files the host user cannot
+//! write.
+//!
+//! Directories and symlinks are always recreated, so adding, replacing or
+//! removing files in a clone never touches the base. Recreated and copied
+//! entries keep the base's modes, times and xattrs, and its ownership when
+//! running as root; files hardlinked within the base stay linked.
+//!
+//! A clone is removed when dropped; clones left behind by a process that
+//! died are removed by [`prune`], once the supervisors of its VMs have
+//! exited too.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Once;
+
+#[cfg(target_os = "macos")]
+const CLONE_NOFOLLOW: u32 = 0x0001;
+
+static NEXT_CLONE: AtomicU32 = AtomicU32::new(0);
+
+/// A clone of a base rootfs, removed on drop
+#[derive(Debug)]
+pub struct RootfsClone {
+    path: PathBuf,
+}
+
+impl RootfsClone {
+    pub fn create(base: &Path) -> io::Result<Self> {
+        // Take the chance to clean up after earlier processes
+        static PRUNE: Once = Once::new();
+        PRUNE.call_once(|| {
+            let _ = prune();
+        });
+
+        let dir = clones_dir();
+        fs::create_dir_all(&dir)?;
+        let name = format!("{}-{}", std::process::id(), NEXT_CLONE.fetch_add(1, Ordering::Relaxed));
+        // Owns the partial tree if cloning fails
+        let clone = RootfsClone { path: dir.join(name) };
+        clone_into(base, &clone.path)?;
+        Ok(clone)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for RootfsClone {
+    fn drop(&mut self) {
+        let _ = remove_tree(&self.path);
+    }
+}
+
+/// Remove clones whose owning process has exited, returning how many
+pub fn prune() -> io::Result<u32> {
+    // Marks of supervisors that are gone, left by a process that died
+    if let Ok(marks) = fs::read_dir(owned_dir(SUPERVISORS)) {
+        for mark in marks.flatten() {
+            let name = mark.file_name();
+            let supervisor = name.to_str().and_then(|name| name.split_once('-')).map(|(_, pid)| pid.parse());
+            if supervisor.is_some_and(|pid| pid.is_ok_and(|pid| !alive(pid))) {
+                let _ = fs::remove_file(mark.path());
+            }
+        }
+    }
+
+    let entries = match fs::read_dir(clones_dir()) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(orphaned) {
+            remove_tree(&entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Per-user directory for `kind` of file named after the process that
+/// owns it
+///
+/// Per user, since the temporary directory is shared on Linux. Each test
+/// thread gets one of its own, so tests never prune a developer's files.
+pub fn owned_dir(kind: &str) -> PathBuf {
+    #[cfg(test)]
+    {
+        thread_local! {
+            static DIR: crate::testutil::TempDir = crate::testutil::TempDir::new();
+        }
+        DIR.with(|dir| dir.join(kind))
+    }
+    #[cfg(not(test))]
+    {
+        let uid = unsafe { libc::getuid() };
+        std::env::temp_dir().join(format!("libkrun-node-{}-{}", kind, uid))
+    }
+}
+
+/// Marks of the supervisors each process started, named
+/// `<owner pid>-<supervisor pid>`
+const SUPERVISORS: &str = "supervisors";
+
+/// Keeps this process's files from being taken for orphans while
+/// supervisor `pid` lives, which can be a while after this process dies as
+/// its guest shuts down; removed on drop
+#[derive(Debug)]
+pub struct SupervisorMark {
+    path: PathBuf,
+}
+
+impl SupervisorMark {
+    pub fn new(pid: u32) -> io::Result<Self> {
+        let dir = owned_dir(SUPERVISORS);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}-{}", std::process::id(), pid));
+        fs::File::create(&path)?;
+        Ok(SupervisorMark { path })
+    }
+}
+
+impl Drop for SupervisorMark {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Directory holding this user's clones, named `<pid>-<n>`
+fn clones_dir() -> PathBuf {
+    owned_dir("rootfs")
+}
+
+/// Clone `base` to `dest`, which must not exist, the same way as
+/// [`RootfsClone`] but without removing it on drop
+pub fn clone_into(base: &Path, dest: &Path) -> io::Result<()> {
+    clone_tree(base, dest, &mut CloneState::default())
+}
+
+/// [`clone_into`], but hardlinking files the host user cannot write when
+/// reflinks are unavailable
+///
+/// Only for trees no guest is given, such as store entries: changing a
+/// hardlinked file in place changes it in `base` too.
+pub fn clone_into_shared(base: &Path, dest: &Path) -> io::Result<()> {
+    let mut state = CloneState {
+        hardlink: true,
+        ..CloneState::default()
+    };
+    clone_tree(base, dest, &mut state)
+}
+
+/// Whether `name`, of the form `<pid>-<n>`, belongs to another process
+/// that has exited, along with every supervisor it started
+pub fn orphaned(name: &str) -> bool {
+    let owner = name
+        .split_once('-')
+        .and_then(|(pid, _)| pid.parse::<libc::pid_t>().ok());
+    owner.is_some_and(|pid| u32::try_from(pid) != Ok(std::process::id()) && !alive(pid) && !supervised(pid))
+}
+
+/// Whether a supervisor `owner` started may still be running a VM
+fn supervised(owner: libc::pid_t) -> bool {
+    let Ok(marks) = fs::read_dir(owned_dir(SUPERVISORS)) else {
+        return false;
+    };
+    marks.flatten().any(|mark| {
+        let name = mark.file_name();
+        let Some((marked, supervisor)) = name.to_str().and_then(|name| name.split_once('-')) else {
+            return false;
+        };
+        marked.parse() == Ok(owner) && supervisor.parse().is_ok_and(alive)
+    })
+}
+
+fn alive(pid: libc::pid_t) -> bool {
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+struct CloneState {
+    /// Cleared once the filesystem turns out not to support reflinks
+    reflink: bool,
+    /// Hardlink read-only files to the base rather than copying them
+    hardlink: bool,
+    /// First clone of each file hardlinked within the base, by device and
+    /// inode
+    links: HashMap<(u64, u64), PathBuf>,
+}
+
+impl Default for CloneState {
+    fn default() -> Self {
+        CloneState {
+            reflink: true,
+            hardlink: false,
+            links: HashMap::new(),
+        }
+    }
+}
+
+/// Replace the contents of the existing directory `dest` with a clone of
+/// `base`, giving `dest` the modes, times and xattrs of `base`
+///
+/// `dest` itself is kept, so anything holding it open sees the new
+/// contents. If this fails part way, `dest` is left partly replaced.
+pub fn restore_into(base: &Path, dest: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dest)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_tree(&entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    let mut state = CloneState::default();
+    clone_entries(base, dest, &mut state)?;
+    copy_metadata(base, dest, &fs::symlink_metadata(base)?)
+}
+
+fn clone_tree(src: &Path, dst: &Path, state: &mut CloneState) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    fs::create_dir(dst)?;
+    clone_entries(src, dst, state)?;
+    // Applied last so read-only directories can be filled first
+    copy_metadata(src, dst, &metadata)
+}
+
+fn clone_entries(src: &Path, dst: &Path, state: &mut CloneState) -> io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let (from, to) = (entry.path(), dst.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            clone_tree(&from, &to, state)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+            copy_metadata(&from, &to, &entry.metadata()?)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            if metadata.nlink() > 1 {
+                let inode = (metadata.dev(), metadata.ino());
+                if let Some(first) = state.links.get(&inode) {
+                    fs::hard_link(first, &to)?;
+                    continue;
+                }
+                state.links.insert(inode, to.clone());
+            }
+            if !clone_file(&from, &to, state)? {
+                copy_metadata(&from, &to, &metadata)?;
+            }
+        }
+        // Sockets, FIFOs and device nodes are left out; libkrun's init
+        // mounts /dev itself
+    }
+    Ok(())
+}
+
+/// Returns whether `to` was hardlinked, and so already shares `from`'s
+/// metadata
+fn clone_file(from: &Path, to: &Path, state: &mut CloneState) -> io::Result<bool> {
+    if state.reflink {
+        match reflink_file(from, to) {
+            Ok(()) => return Ok(false),
+            // Not supported by this filesystem; don't try again
+            Err(err) if is_unsupported(&err) => state.reflink = false,
+            Err(err) => return Err(err),
+        }
+    }
+
+    if state.hardlink && !writable(from) {
+        match fs::hard_link(from, to) {
+            Ok(()) => return Ok(true),
+            Err(err) if is_unsupported(&err) || err.raw_os_error() == Some(libc::EMLINK) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    fs::copy(from, to).map(|_| false)
+}
+
+fn copy_metadata(from: &Path, to: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    // Before the mode, since chown clears setuid and setgid
+    if unsafe { libc::geteuid() } == 0 {
+        std::os::unix::fs::lchown(to, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    for name in xattr::list(from)? {
+        let Some(value) = xattr::get(from, &name)? else { continue };
+        match xattr::set(to, &name, &value) {
+            // Symlinks cannot carry user xattrs on Linux, and some
+            // filesystems none at all
+            Err(err) if is_unsupported(&err) => {}
+            result => result?,
+        }
+    }
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(to, metadata.permissions())?;
+    }
+
+    let time = |sec: i64, nsec: i64| libc::timespec {
+        tv_sec: sec as libc::time_t,
+        tv_nsec: nsec as _,
+    };
+    let times = [
+        time(metadata.atime(), metadata.atime_nsec()),
+        time(metadata.mtime(), metadata.mtime_nsec()),
+    ];
+    let to = c_path(to)?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, to.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn reflink_file(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    {
+        let (from, to) = (c_path(from)?, c_path(to)?);
+        if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), CLONE_NOFOLLOW) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        let src = fs::File::open(from)?;
+        let mode = src.metadata()?.permissions().mode();
+        let dst = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(to)?;
+        if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+            let err = io::Error::last_os_error();
+            drop(dst);
+            let _ = fs::remove_file(to);
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+fn is_unsupported(err: &io::Error) -> bool {
+    // ENOTSUP and EOPNOTSUPP are distinct on macOS only
+    const ERRNOS: &[i32] = &[
+        libc::ENOTSUP,
+        libc::EOPNOTSUPP,
+        libc::EXDEV,
+        libc::EINVAL,
+        libc::ENOTTY,
+        libc::ENOSYS,
+        libc::EPERM,
+    ];
+    err.raw_os_error().is_some_and(|errno| ERRNOS.contains(&errno))
+}
+
+fn writable(path: &Path) -> bool {
+    c_path(path)
+        .map(|path| unsafe { libc::access(path.as_ptr(), libc::W_OK) } == 0)
+        .unwrap_or(false)
+}
+
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))
+}
+
+/// Remove a clone, including directories the base made read-only
+pub fn remove_tree(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            make_dirs_writable(path)?;
+            fs::remove_dir_all(path)
+        }
+        result => result,
+    }
+}
+
+fn make_dirs_writable(dir: &Path) -> io::Result<()> {
+    let mut permissions = fs::symlink_metadata(dir)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o700);
+    fs::set_permissions(dir, permissions)?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_dirs_writable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, TempDir};
+    use std::os::unix::fs::symlink;
+
+    fn inode(path: &Path) -> u64 {
+        fs::symlink_metadata(path).unwrap().ino()
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().mode() & 0o7777
+    }
+
+    /// A base with a read-only file, two linked names, a symlink and a
+    /// read-only directory
+    fn base(tmp: &TempDir) -> PathBuf {
+        let base = tmp.join("base");
+        write(&base.join("etc/os-release"), "ID=test\n");
+        fs::set_permissions(base.join("etc/os-release"), fs::Permissions::from_mode(0o444)).unwrap();
+        write(&base.join("bin/busybox"), "busybox");
+        fs::hard_link(base.join("bin/busybox"), base.join("bin/sh")).unwrap();
+        symlink("busybox", base.join("bin/ls")).unwrap();
+        write(&base.join("ro/file"), "ro");
+        fs::set_permissions(base.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        base
+    }
+
+    #[test]
+    fn clone_shares_no_inode_with_base() {
+        let tmp = TempDir::new();
+        let base = base(&tmp);
+        let clone = tmp.join("clone");
+        clone_into(&base, &clone).unwrap();
+
+        for name in ["etc/os-release", "bin/busybox", "bin/sh", "ro/file"] {
+            assert_ne!(inode(&base.join(name)), inode(&clone.join(name)), "{}", name);
+            assert_eq!(fs::read(base.join(name)).unwrap(), fs::read(clone.join(name)).unwrap());
+        }
+        assert_eq!(mode(&clone.join("etc/os-release")), 0o444);
+        assert_eq!(mode(&clone.join("ro")), 0o555);
+        // Linked within the base, so linked within the clone
+        assert_eq!(inode(&clone.join("bin/busybox")), inode(&clone.join("bin/sh")));
+        assert_eq!(fs::read_link(clone.join("bin/ls")).unwrap(), Path::new("busybox"));
+    }
+
+    #[test]
+    fn writing_a_clone_leaves_the_base() {
+        let tmp = TempDir::new();
+        let base = base(&tmp);
+        let clone = tmp.join("clone");
+        clone_into(&base, &clone).unwrap();
+
+        // What a guest can do to a read-only file it owns
+        let file = clone.join("etc/os-release");
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(&file, "ID=changed\n").unwrap();
+        fs::write(clone.join("bin/sh"), "changed").unwrap();
+
+        assert_eq!(fs::read_to_string(base.join("etc/os-release")).unwrap(), "ID=test\n");
+        assert_eq!(mode(&base.join("etc/os-release")), 0o444);
+        assert_eq!(fs::read_to_string(base.join("bin/busybox")).unwrap(), "busybox");
+    }
+
+    #[test]
+    fn shared_clone_links_read_only_files() {
+        let tmp = TempDir::new();
+        let base = base(&tmp);
+        let clone = tmp.join("clone");
+        clone_into_shared(&base, &clone).unwrap();
+
+        let file = Path::new("etc/os-release");
+        assert_eq!(fs::read(base.join(file)).unwrap(), fs::read(clone.join(file)).unwrap());
+        // Root can write anything, so only the unprivileged case shares
+        if !writable(&base.join(file)) && !reflink_supported(&base) {
+            assert_eq!(inode(&base.join(file)), inode(&clone.join(file)));
+        }
+        assert_ne!(inode(&base.join("bin/busybox")), inode(&clone.join("bin/busybox")));
+    }
+
+    fn reflink_supported(dir: &Path) -> bool {
+        let (from, to) = (dir.join(".reflink-from"), dir.join(".reflink-to"));
+        fs::write(&from, "").unwrap();
+        let supported = reflink_file(&from, &to).is_ok();
+        let _ = fs::remove_file(&from);
+        let _ = fs::remove_file(&to);
+        supported
+    }
+
+    #[test]
+    fn restore_replaces_contents_in_place() {
+        let tmp = TempDir::new();
+        let base = base(&tmp);
+        let clone = tmp.join("clone");
+        clone_into(&base, &clone).unwrap();
+        let root = inode(&clone);
+
+        fs::remove_file(clone.join("bin/sh")).unwrap();
+        write(&clone.join("tmp/new"), "new");
+        fs::set_permissions(clone.join("ro"), fs::Permissions::from_mode(0o755)).unwrap();
+        write(&clone.join("ro/other"), "other");
+        restore_into(&base, &clone).unwrap();
+
+        assert_eq!(inode(&clone), root);
+        assert_eq!(fs::read_to_string(clone.join("bin/sh")).unwrap(), "busybox");
+        assert!(!clone.join("tmp").exists());
+        assert!(!clone.join("ro/other").exists());
+        assert_eq!(mode(&clone.join("ro")), 0o555);
+    }
+
+    #[test]
+    fn clone_is_removed_on_drop() {
+        let tmp = TempDir::new();
+        let base = base(&tmp);
+        let clone = RootfsClone::create(&base).unwrap();
+        let path = clone.path().to_path_buf();
+        assert!(path.starts_with(clones_dir()));
+        assert_eq!(fs::read_to_string(path.join("ro/file")).unwrap(), "ro");
+        drop(clone);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn orphaned_only_matches_exited_owners() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let exited = child.id();
+        child.wait().unwrap();
+
+        assert!(orphaned(&format!("{}-0", exited)));
+        assert!(!orphaned(&format!("{}-0", std::process::id())));
+        assert!(!orphaned("1-0"));
+        assert!(!orphaned("not-a-pid"));
+        assert!(!orphaned("42"));
+    }
+
+    #[test]
+    fn prune_removes_orphaned_clones() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let exited = child.id();
+        child.wait().unwrap();
+
+        let dir = clones_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let stale = dir.join(format!("{}-0", exited));
+        write(&stale.join("ro/file"), "stale");
+        fs::set_permissions(stale.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        let ours = dir.join(format!("{}-test", std::process::id()));
+        fs::create_dir_all(&ours).unwrap();
+
+        assert_eq!(prune().unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(ours.exists());
+    }
+
+    #[test]
+    fn clones_outlive_their_owner_while_its_supervisor_runs() {
+        let mut owner = std::process::Command::new("true").spawn().unwrap();
+        let exited = owner.id();
+        owner.wait().unwrap();
+        let mut supervisor = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+
+        let dir = owned_dir(SUPERVISORS);
+        fs::create_dir_all(&dir).unwrap();
+        let mark = dir.join(format!("{}-{}", exited, supervisor.id()));
+        fs::File::create(&mark).unwrap();
+        let clone = clones_dir().join(format!("{}-0", exited));
+        fs::create_dir_all(&clone).unwrap();
+
+        assert!(!orphaned(&format!("{}-0", exited)));
+        assert_eq!(prune().unwrap(), 0);
+        assert!(clone.exists() && mark.exists());
+
+        supervisor.kill().unwrap();
+        supervisor.wait().unwrap();
+        assert!(orphaned(&format!("{}-0", exited)));
+        assert_eq!(prune().unwrap(), 1);
+        assert!(!clone.exists() && !mark.exists());
+    }
+
+    #[test]
+    fn supervisor_mark_is_removed_on_drop() {
+        let mark = SupervisorMark::new(std::process::id()).unwrap();
+        let path = mark.path.clone();
+        assert!(path.starts_with(owned_dir(SUPERVISORS)));
+        assert!(path.exists());
+        drop(mark);
+        assert!(!path.exists());
+    }
+}
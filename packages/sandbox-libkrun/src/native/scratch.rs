@@ -0,0 +1,78 @@
+//! Scratch disk images made for a single context.
+//!
+//! A [`ScratchImage`] is a sparse raw file, so it only takes host space as
+//! the guest writes to it. It is removed when dropped, which happens when
+//! the context is freed or its supervisor exits, whichever comes first;
+//! images left behind by a process that died are removed by [`prune`].
+
+use crate::rootfs;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Once;
+
+static NEXT_IMAGE: AtomicU32 = AtomicU32::new(0);
+
+/// A scratch image, removed on drop
+#[derive(Debug)]
+pub struct ScratchImage {
+    path: PathBuf,
+}
+
+impl ScratchImage {
+    pub fn create(size_mib: u32) -> io::Result<Self> {
+        static PRUNE: Once = Once::new();
+        PRUNE.call_once(|| {
+            let _ = prune();
+        });
+
+        let dir = images_dir();
+        // Guests may write anything to the image, so keep it to this user
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        let name = format!("{}-{}.img", std::process::id(), NEXT_IMAGE.fetch_add(1, Ordering::Relaxed));
+        let path = dir.join(name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        let image = ScratchImage { path };
+        file.set_len(u64::from(size_mib) << 20)?;
+        Ok(image)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchImage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Remove images whose owning process has exited, returning how many
+pub fn prune() -> io::Result<u32> {
+    let entries = match fs::read_dir(images_dir()) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(rootfs::orphaned) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Directory holding this user's images, named `<pid>-<n>.img`
+fn images_dir() -> PathBuf {
+    rootfs::owned_dir("scratch")
+}
@@ -0,0 +1,555 @@
+//! Serializable description of a libkrun context.
+//!
+//! libkrun contexts live inside the process that created them, so the
+//! configuration applied through the napi API is also recorded here. The
+//! supervisor binary receives this spec over stdin and replays it against a
+//! fresh context of its own before calling `krun_start_enter`.
+//!
+//! This module is shared with `bin/krun-supervisor.rs` and must not depend on
+//! napi.
+
+use crate::error::KrunError;
+use crate::policy::EgressPolicy;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Descriptor on which the supervisor reports why a VM could not be started
+pub const STATUS_FD: i32 = 3;
+
+/// Descriptor on which the supervisor receives its end of the socket
+/// connecting the VM to a userspace network backend such as passt
+pub const NET_FD: i32 = 4;
+
+/// MAC address of virtio-net devices whose config gives none
+pub const DEFAULT_MAC: [u8; 6] = [0x5a, 0x94, 0xef, 0xe4, 0x0c, 0xee];
+
+/// Block ID of a disk root
+pub const ROOT_BLOCK_ID: &str = "root";
+
+/// Block ID of the scratch disk
+pub const SCRATCH_BLOCK_ID: &str = "scratch";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VmSpec {
+    pub cpus: u8,
+    pub memory_mib: u32,
+    pub root: RootSpec,
+    pub workdir: Option<String>,
+    /// virtiofs mounts, applied in order
+    pub mounts: Vec<MountSpec>,
+    /// Block devices, attached in order after a disk root
+    pub disks: Vec<DiskSpec>,
+    /// Scratch image made for the context, attached after `disks`
+    pub scratch_disk: Option<ScratchDiskSpec>,
+    pub network: NetworkSpec,
+    /// MAC address of the virtio-net device, if the network has one
+    pub mac: Option<[u8; 6]>,
+    /// `None` lets libkrun expose every listening guest port; an empty list
+    /// exposes none. With passt the mappings become passt's TCP forwards.
+    pub port_map: Option<Vec<PortMapping>>,
+    pub egress: Option<EgressSpec>,
+    /// Base guest environment as (key, value); see [`merge_env`]
+    pub env: Vec<(String, String)>,
+    pub exec: Option<ExecSpec>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RootSpec {
+    /// Host directory shared as the root over virtiofs
+    Dir(String),
+    /// Raw image attached as block device `root`, which libkrun's init
+    /// mounts over a placeholder virtiofs root before running the workload
+    Disk {
+        path: String,
+        device: String,
+        fstype: Option<String>,
+        options: Option<String>,
+    },
+}
+
+impl Default for RootSpec {
+    fn default() -> Self {
+        RootSpec::Dir(String::new())
+    }
+}
+
+/// How the guest reaches the network
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetworkSpec {
+    /// libkrun's implicit vsock device, which proxies the guest's TCP and
+    /// UDP sockets through the VMM (transparent socket impersonation)
+    #[default]
+    Tsi,
+    /// No network device at all; libkrun's implicit vsock device, which
+    /// carries TSI, is left out
+    None,
+    /// A virtio-net device connected to a passt process over [`NET_FD`]
+    Passt {
+        /// Path, or a name looked up on `PATH`
+        binary: String,
+    },
+    /// gvproxy's vfkit-mode datagram socket, which libkrun connects to
+    Gvproxy { socket_path: String },
+    /// A proxy listening on a stream socket, connected to by the host and
+    /// passed over [`NET_FD`]
+    UnixStream { socket_path: String },
+    /// A proxy on a datagram socket, connected to by the host from a socket
+    /// of its own and passed over [`NET_FD`]
+    UnixGram {
+        socket_path: String,
+        /// Announce the connection the way vfkit does, as gvproxy's vfkit
+        /// mode expects
+        vfkit: bool,
+    },
+    /// The crate's own network stack, on a datagram socketpair passed over
+    /// [`NET_FD`]
+    Nat {
+        /// Let connections to the gateway reach the host's loopback
+        host_loopback: bool,
+    },
+}
+
+impl NetworkSpec {
+    /// Whether the supervisor receives the backend's socket as [`NET_FD`]
+    pub fn uses_net_fd(&self) -> bool {
+        matches!(
+            self,
+            NetworkSpec::Passt { .. }
+                | NetworkSpec::UnixStream { .. }
+                | NetworkSpec::UnixGram { .. }
+                | NetworkSpec::Nat { .. }
+        )
+    }
+
+    /// The proxy socket of the socket-based backends
+    pub fn socket_path(&self) -> Option<&str> {
+        match self {
+            NetworkSpec::Gvproxy { socket_path }
+            | NetworkSpec::UnixStream { socket_path }
+            | NetworkSpec::UnixGram { socket_path, .. } => Some(socket_path),
+            NetworkSpec::Tsi | NetworkSpec::None | NetworkSpec::Passt { .. } | NetworkSpec::Nat { .. } => None,
+        }
+    }
+}
+
+/// The crate's egress proxy, reached by the guest over vsock
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EgressSpec {
+    pub policy: EgressPolicy,
+    pub vsock_port: u32,
+    /// Host socket libkrun connects the port to; empty until the context
+    /// has started the proxy
+    pub socket_path: String,
+    /// File refused attempts are appended to
+    pub log_path: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ExecSpec {
+    pub path: String,
+    pub args: Vec<String>,
+    /// Environment as (key, value), applied in order
+    pub env: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MountSpec {
+    pub tag: String,
+    pub host_path: String,
+    /// Where [`VmSpec::guest_exec`] mounts the tag; `None` leaves mounting
+    /// to the guest
+    pub guest_path: Option<String>,
+    pub read_only: bool,
+    /// DAX window size; `None` for libkrun's default
+    pub shm_size_mib: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DiskSpec {
+    pub block_id: String,
+    pub path: String,
+    pub format: DiskFormat,
+    pub read_only: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScratchDiskSpec {
+    pub size_mib: u32,
+    /// Host path of the image; empty until the context has created it
+    pub path: String,
+}
+
+/// Image format of a disk, always given explicitly: libkrun's docs warn
+/// that probing lets a guest which rewrote a raw image as qcow2 reach
+/// arbitrary host files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+impl DiskFormat {
+    /// `KRUN_DISK_FORMAT_*` value
+    pub fn to_krun(self) -> u32 {
+        match self {
+            DiskFormat::Raw => 0,
+            DiskFormat::Qcow2 => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub host_port: u16,
+    pub guest_port: u16,
+    /// Host address to bind; `None` for libkrun's default
+    pub host_addr: Option<IpAddr>,
+}
+
+impl PortMapping {
+    /// Entry in libkrun's `host_port:guest_port` format
+    pub fn to_krun(&self) -> String {
+        format!("{}:{}", self.host_port, self.guest_port)
+    }
+
+    /// Forward in the `[address/]port:target` format of passt's `--tcp-ports`
+    pub fn to_passt(&self) -> String {
+        match self.host_addr {
+            Some(addr) => format!("{}/{}:{}", addr, self.host_port, self.guest_port),
+            None => format!("{}:{}", self.host_port, self.guest_port),
+        }
+    }
+}
+
+/// Exit code of the mount wrapper when a mount fails, which libkrun's init
+/// also uses for workloads it cannot run
+const MOUNT_FAILED: u8 = 125;
+
+impl VmSpec {
+    /// `exec`, wrapped so that mounts with a `guest_path` are mounted first
+    ///
+    /// libkrun only attaches virtio-fs devices, so the wrapper runs
+    /// `mount -t virtiofs` through the guest's `/bin/sh` and then execs the
+    /// workload with its original arguments. `exec` is returned unchanged
+    /// when no mount has a guest path.
+    pub fn guest_exec(&self, exec: ExecSpec) -> ExecSpec {
+        let mounts: Vec<&MountSpec> = self.mounts.iter().filter(|m| m.guest_path.is_some()).collect();
+        if mounts.is_empty() {
+            return exec;
+        }
+
+        let mut script = String::new();
+        for mount in mounts {
+            let guest_path = shell_quote(mount.guest_path.as_deref().unwrap_or_default());
+            let options = if mount.read_only { "-o ro " } else { "" };
+            script.push_str(&format!(
+                "mkdir -p {path} && mount -t virtiofs {options}{tag} {path} || exit {code}\n",
+                path = guest_path,
+                options = options,
+                tag = shell_quote(&mount.tag),
+                code = MOUNT_FAILED,
+            ));
+        }
+        script.push_str("exec \"$0\" \"$@\"\n");
+
+        let mut args = vec!["-c".to_string(), script, exec.path];
+        args.extend(exec.args);
+        ExecSpec {
+            path: "/bin/sh".to_string(),
+            args,
+            env: exec.env,
+        }
+    }
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Problems with a port map that libkrun would accept but cannot honour
+pub fn port_map_problems(port_map: &[PortMapping], network: &NetworkSpec) -> Vec<KrunError> {
+    let mut problems = Vec::new();
+    let mut host_ports = std::collections::BTreeSet::new();
+    for mapping in port_map {
+        if !host_ports.insert(mapping.host_port) {
+            problems.push(KrunError::invalid(
+                "portMap",
+                format!("duplicate host port {}", mapping.host_port),
+            ));
+        }
+        // TSI binds the mapped ports itself and its port map has no address
+        // field, so only the wildcard address can be honoured
+        let tsi = *network == NetworkSpec::Tsi;
+        if let Some(addr) = mapping.host_addr.filter(|addr| tsi && !addr.is_unspecified()) {
+            problems.push(KrunError::invalid(
+                "portMap",
+                format!(
+                    "hostAddr {} of host port {} is not supported by libkrun's port map",
+                    addr, mapping.host_port
+                ),
+            ));
+        }
+    }
+    // Only TSI takes libkrun's port map, and the crate only starts passt
+    if !port_map.is_empty() && !matches!(network, NetworkSpec::Tsi | NetworkSpec::Passt { .. }) {
+        problems.push(KrunError::invalid(
+            "portMap",
+            "is only supported with tsi and passt networking",
+        ));
+    }
+    problems
+}
+
+/// Environment variables that cannot be passed as `KEY=value`
+pub fn env_problems(env: &[(String, String)]) -> Vec<KrunError> {
+    let mut problems = Vec::new();
+    for (key, value) in env {
+        if key.is_empty() {
+            problems.push(KrunError::invalid("env", "empty key"));
+        } else if key.contains('=') || key.contains('\0') {
+            problems.push(KrunError::invalid(
+                "env",
+                format!("key {:?} must not contain '=' or NUL", key),
+            ));
+        }
+        if value.contains('\0') {
+            problems.push(KrunError::invalid(
+                "env",
+                format!("value of {:?} must not contain NUL", key),
+            ));
+        }
+    }
+    problems
+}
+
+/// Overlay the exec environment on the context's base environment
+///
+/// libkrun keeps a single environment per context and `krun_set_exec`
+/// replaces whatever `krun_set_env` installed, so the merge happens here:
+/// keys from `exec` win, and the result is sorted by key.
+pub fn merge_env(base: &[(String, String)], exec: &[(String, String)]) -> Vec<(String, String)> {
+    let mut merged: std::collections::BTreeMap<&str, &str> = base
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    merged.extend(exec.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    merged
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+mod apply {
+    use super::{ExecSpec, NetworkSpec, VmSpec};
+    use crate::error::{check, KrunError};
+    use crate::ffi::*;
+    use crate::marshal::{cstring, ConfigArgs, ExecArgs, RootArgs};
+    use std::ptr;
+
+    impl VmSpec {
+        /// Apply the VM-level configuration (everything except the exec) to
+        /// `ctx_id`. The caller owns the context and frees it on error.
+        pub fn apply_config(&self, ctx_id: u32) -> Result<(), KrunError> {
+            // Marshal everything up front so a bad string cannot leave the
+            // context half-configured
+            let args = ConfigArgs::new(self)?;
+            unsafe {
+                check(
+                    krun_set_vm_config(ctx_id, self.cpus, self.memory_mib),
+                    "krun_set_vm_config",
+                    None,
+                )?;
+                if let RootArgs::Dir(path) = &args.root {
+                    check(krun_set_root(ctx_id, path.as_ptr()), "krun_set_root", Some("rootfsPath"))?;
+                }
+
+                if let Some(workdir) = &args.workdir {
+                    check(krun_set_workdir(ctx_id, workdir.as_ptr()), "krun_set_workdir", Some("workdir"))?;
+                }
+
+                for mount in &args.mounts {
+                    match mount.shm_size {
+                        Some(shm_size) => check(
+                            krun_add_virtiofs2(ctx_id, mount.tag.as_ptr(), mount.path.as_ptr(), shm_size),
+                            "krun_add_virtiofs2",
+                            Some(&mount.field),
+                        )?,
+                        None => check(
+                            krun_add_virtiofs(ctx_id, mount.tag.as_ptr(), mount.path.as_ptr()),
+                            "krun_add_virtiofs",
+                            Some(&mount.field),
+                        )?,
+                    };
+                }
+
+                for disk in &args.disks {
+                    check(
+                        krun_add_disk2(
+                            ctx_id,
+                            disk.block_id.as_ptr(),
+                            disk.path.as_ptr(),
+                            disk.format,
+                            disk.read_only,
+                        ),
+                        "krun_add_disk2",
+                        Some(&disk.field),
+                    )?;
+                }
+
+                // A disk root is attached with the other disks, as
+                // krun_add_disk2 rules out the deprecated krun_set_root_disk,
+                // and must be attached before it can be remounted
+                if let RootArgs::Disk {
+                    device,
+                    fstype,
+                    options,
+                } = &args.root
+                {
+                    check(
+                        krun_set_root_disk_remount(
+                            ctx_id,
+                            device.as_ptr(),
+                            fstype.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                            options.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                        ),
+                        "krun_set_root_disk_remount",
+                        Some("root"),
+                    )?;
+                }
+
+                if let Some(env) = &args.env {
+                    check(krun_set_env(ctx_id, env.as_ptr()), "krun_set_env", Some("env"))?;
+                }
+
+                if let Some(port_map) = &args.port_map {
+                    check(
+                        krun_set_port_map(ctx_id, port_map.as_ptr()),
+                        "krun_set_port_map",
+                        Some("portMap"),
+                    )?;
+                }
+
+                // The ports below need a vsock device, which must then be
+                // added explicitly, without TSI
+                if self.network == NetworkSpec::None {
+                    let vsock = VsockCalls::lookup().ok_or(KrunError::Unsupported(
+                        "network kind none and egress need a libkrun exporting krun_disable_implicit_vsock \
+                         and krun_add_vsock",
+                    ))?;
+                    check(
+                        (vsock.krun_disable_implicit_vsock)(ctx_id),
+                        "krun_disable_implicit_vsock",
+                        Some("network"),
+                    )?;
+                    if args.egress.is_some() {
+                        check((vsock.krun_add_vsock)(ctx_id, 0), "krun_add_vsock", Some("network"))?;
+                    }
+                }
+
+                if let Some((port, path)) = &args.egress {
+                    check(
+                        krun_add_vsock_port2(ctx_id, *port, path.as_ptr(), false),
+                        "krun_add_vsock_port2",
+                        Some("egress.vsockPort"),
+                    )?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl VmSpec {
+        /// Attach the network backend to `ctx_id`
+        ///
+        /// Only the supervisor calls this, since it holds [`NET_FD`]; the
+        /// context created to check the config goes without a network.
+        ///
+        /// [`NET_FD`]: super::NET_FD
+        pub fn apply_network(&self, ctx_id: u32) -> Result<(), KrunError> {
+            let mut mac = self.mac.unwrap_or(super::DEFAULT_MAC);
+            // The older passt and gvproxy calls take the MAC separately, and
+            // only when one is configured
+            let set_mac = |mac: &mut [u8; 6]| match self.mac {
+                Some(_) => check(
+                    unsafe { krun_set_net_mac(ctx_id, mac.as_mut_ptr()) },
+                    "krun_set_net_mac",
+                    Some("network.mac"),
+                ),
+                None => Ok(0),
+            };
+            unsafe {
+                match &self.network {
+                    NetworkSpec::Tsi | NetworkSpec::None => {}
+                    NetworkSpec::Passt { .. } => {
+                        check(krun_set_passt_fd(ctx_id, super::NET_FD), "krun_set_passt_fd", Some("network"))?;
+                        set_mac(&mut mac)?;
+                    }
+                    NetworkSpec::Gvproxy { socket_path } => {
+                        let path = cstring(socket_path, "network.socketPath")?;
+                        check(
+                            krun_set_gvproxy_path(ctx_id, path.as_ptr().cast_mut()),
+                            "krun_set_gvproxy_path",
+                            Some("network.socketPath"),
+                        )?;
+                        set_mac(&mut mac)?;
+                    }
+                    NetworkSpec::UnixStream { .. } => {
+                        check(
+                            krun_add_net_unixstream(
+                                ctx_id,
+                                ptr::null(),
+                                super::NET_FD,
+                                mac.as_mut_ptr(),
+                                COMPAT_NET_FEATURES,
+                                0,
+                            ),
+                            "krun_add_net_unixstream",
+                            Some("network"),
+                        )?;
+                    }
+                    // The host connected the socket and announced it, so no
+                    // vfkit flag
+                    NetworkSpec::UnixGram { .. } => {
+                        check(
+                            krun_add_net_unixgram(
+                                ctx_id,
+                                ptr::null(),
+                                super::NET_FD,
+                                mac.as_mut_ptr(),
+                                COMPAT_NET_FEATURES,
+                                0,
+                            ),
+                            "krun_add_net_unixgram",
+                            Some("network"),
+                        )?;
+                    }
+                    // The crate's stack neither checks nor fills in checksums
+                    // and takes no segments above the MTU
+                    NetworkSpec::Nat { .. } => {
+                        check(
+                            krun_add_net_unixgram(ctx_id, ptr::null(), super::NET_FD, mac.as_mut_ptr(), 0, 0),
+                            "krun_add_net_unixgram",
+                            Some("network"),
+                        )?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl ExecSpec {
+        pub fn apply(&self, ctx_id: u32) -> Result<(), KrunError> {
+            let args = ExecArgs::new(self)?;
+            unsafe {
+                check(
+                    krun_set_exec(ctx_id, args.path.as_ptr(), args.argv.as_ptr(), args.envp.as_ptr()),
+                    "krun_set_exec",
+                    Some("execPath"),
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,556 @@
+//! Content-addressed cache of unpacked images.
+//!
+//! Each entry is the rootfs left by applying a stack of layers, keyed by the
+//! chain of their digests, so images sharing base layers share entries and a
+//! new image only unpacks the layers above the longest cached prefix. An
+//! entry is built by cloning the entry below it (see [`crate::rootfs`]) and
+//! applying one layer on top, and is complete on its own once built.
+//!
+//! ```text
+//! <store>/<key>/rootfs/      the unpacked tree
+//! <store>/<key>/meta.json    layer digest, parent key and size
+//! <store>/<key>/lock         flocked shared by each user of the entry
+//! <store>/tmp-<pid>-<n>/     an entry being built
+//! ```
+//!
+//! Entries are never booted directly: contexts boot a clone and hold a
+//! [`Lease`] on the entry while they live, which [`RootfsStore::gc`] checks
+//! by trying to lock the entry exclusively. The lock file's mtime records
+//! when the entry was last used.
+
+use crate::error::KrunError;
+use crate::oci::{Image, Layer, Source, UnpackedImage, Unpacker};
+use crate::{rootfs, Settled};
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::SystemTime;
+
+const META: &str = "meta.json";
+const LOCK: &str = "lock";
+const ROOTFS: &str = "rootfs";
+const TMP_PREFIX: &str = "tmp-";
+
+static NEXT_BUILD: AtomicU32 = AtomicU32::new(0);
+
+/// A cached image ready to boot
+#[napi(object)]
+pub struct CachedRootfs {
+    /// Pass as `rootfsPath`, with `cloneRootfs: true`
+    pub path: String,
+    /// Chain key of the image's layers
+    pub key: String,
+    /// Layers found in the cache rather than unpacked
+    pub reused_layers: u32,
+    pub image: UnpackedImage,
+}
+
+#[napi(object)]
+pub struct GcOptions {
+    /// Evict least recently used entries until the store fits in this many
+    /// bytes (default: 0, evicting every unused entry)
+    pub keep_bytes: Option<i64>,
+}
+
+#[napi(object)]
+pub struct GcResult {
+    /// Entries removed
+    pub evicted: u32,
+    pub freed_bytes: i64,
+    /// Size of the entries left
+    pub kept_bytes: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    layer: String,
+    parent: Option<String>,
+    /// Allocated bytes, counting each hardlinked file once
+    size_bytes: u64,
+}
+
+/// A directory of unpacked images, shared by every process using it
+///
+/// `prepare` unpacks an image into the store, reusing layers it already
+/// holds; `gc` evicts entries no live `VmHandle` was created from.
+#[napi]
+pub struct RootfsStore {
+    dir: PathBuf,
+}
+
+#[napi]
+impl RootfsStore {
+    /// Open the store in `dir`, by default the user's cache directory
+    ///
+    /// The directory is created on first use.
+    #[napi(constructor, catch_unwind)]
+    pub fn new(env: Env, dir: Option<String>) -> Result<Self> {
+        let dir = match dir {
+            Some(dir) => PathBuf::from(dir),
+            None => default_dir().map_err(|error| KrunError::Io { op: "locate rootfs store", error }.into_napi(env))?,
+        };
+        Ok(RootfsStore { dir })
+    }
+
+    /// Directory holding the store
+    #[napi(getter, catch_unwind)]
+    pub fn dir(&self) -> String {
+        self.dir.to_string_lossy().into_owned()
+    }
+
+    /// Unpack a local OCI image into the store, as `unpackOciImage` does,
+    /// unpacking only the layers not already cached
+    ///
+    /// Layers whose digest the image does not record, as in older
+    /// `docker save` tarballs, are hashed first.
+    #[napi(catch_unwind)]
+    pub async fn prepare(&self, layout_or_tar_path: String) -> Settled<CachedRootfs> {
+        let dir = self.dir.clone();
+        let prepared = tokio::task::spawn_blocking(move || prepare(&dir, layout_or_tar_path.as_ref())).await;
+        Settled(prepared.unwrap_or_else(|err| Err(io::Error::other(err))).map_err(|error| KrunError::Io {
+            op: "prepare cached rootfs",
+            error,
+        }))
+    }
+
+    /// Evict entries no live context was created from, least recently used
+    /// first, until the store fits in `keepBytes`
+    ///
+    /// Also removes entries left half-built by processes that died. Sizes
+    /// count data shared with other entries through hardlinks or reflinks
+    /// in full, so the store usually takes less space than reported.
+    #[napi(catch_unwind)]
+    pub async fn gc(&self, options: Option<GcOptions>) -> Settled<GcResult> {
+        let dir = self.dir.clone();
+        let keep_bytes = options.and_then(|o| o.keep_bytes).unwrap_or(0).max(0) as u64;
+        let collected = tokio::task::spawn_blocking(move || gc(&dir, keep_bytes)).await;
+        Settled(collected.unwrap_or_else(|err| Err(io::Error::other(err))).map_err(|error| KrunError::Io {
+            op: "collect rootfs store",
+            error,
+        }))
+    }
+}
+
+/// A shared lock on a store entry, keeping it from being evicted
+#[derive(Debug)]
+pub struct Lease {
+    _lock: File,
+}
+
+impl Lease {
+    /// Lease the entry `rootfs` belongs to; `None` if it is not the rootfs
+    /// of a store entry
+    pub fn acquire(rootfs: &Path) -> io::Result<Option<Lease>> {
+        if !is_entry(rootfs) {
+            return Ok(None);
+        }
+        let entry = rootfs.parent().unwrap_or(Path::new("/"));
+        let lock = match File::options().write(true).open(entry.join(LOCK)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            lock => lock?,
+        };
+        flock(&lock, libc::LOCK_SH)?;
+        // Lost a race with gc, which removes the metadata before the tree
+        if !entry.join(META).exists() {
+            return Ok(None);
+        }
+        // Best effort: only the owner can set times on a shared store
+        let _ = lock.set_modified(SystemTime::now());
+        Ok(Some(Lease { _lock: lock }))
+    }
+}
+
+/// Whether `path` is the rootfs of a store entry, which must not be booted
+/// directly
+pub fn is_entry(path: &Path) -> bool {
+    path.file_name() == Some(ROOTFS.as_ref()) && path.parent().is_some_and(|entry| entry.join(META).is_file())
+}
+
+fn default_dir() -> io::Result<PathBuf> {
+    let home = || {
+        std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))
+    };
+
+    #[cfg(target_os = "macos")]
+    let cache = home()?.join("Library/Caches");
+    #[cfg(not(target_os = "macos"))]
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => home()?.join(".cache"),
+    };
+
+    Ok(cache.join("libkrun-node/rootfs"))
+}
+
+fn prepare(dir: &Path, source: &Path) -> io::Result<CachedRootfs> {
+    let source = Source::open(source)?;
+    let image = Image::find(&source)?;
+    let unpacked = image.unpacked(&source)?;
+    if image.layers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "image has no layers"));
+    }
+    fs::create_dir_all(dir)?;
+
+    let mut keys = Vec::with_capacity(image.layers.len());
+    let mut digests = Vec::with_capacity(image.layers.len());
+    for layer in &image.layers {
+        let digest = match &layer.digest {
+            Some(digest) => digest.clone(),
+            None => source.digest(&layer.path)?,
+        };
+        let mut chain = Sha256::new();
+        if let Some(parent) = keys.last() {
+            chain.update(format!("{} ", parent));
+        }
+        chain.update(&digest);
+        keys.push(format!("{:x}", chain.finalize()));
+        digests.push(digest);
+    }
+
+    // Longest cached prefix, leased so it survives while layers are added
+    let mut reused_layers = 0;
+    let mut _lease = None;
+    for (i, key) in keys.iter().enumerate().rev() {
+        if let Some(lease) = Lease::acquire(&dir.join(key).join(ROOTFS))? {
+            (reused_layers, _lease) = (i + 1, Some(lease));
+            break;
+        }
+    }
+
+    for (i, layer) in image.layers.iter().enumerate().skip(reused_layers) {
+        let parent = i.checked_sub(1).map(|i| keys[i].as_str());
+        build(dir, &source, layer, &keys[i], &digests[i], parent)?;
+        // Entries are complete on their own, so only the newest needs holding
+        _lease = Lease::acquire(&dir.join(&keys[i]).join(ROOTFS))?;
+    }
+
+    let key = keys.pop().unwrap_or_default();
+    Ok(CachedRootfs {
+        path: dir.join(&key).join(ROOTFS).to_string_lossy().into_owned(),
+        key,
+        reused_layers: reused_layers as u32,
+        image: unpacked,
+    })
+}
+
+/// Build entry `key` by applying `layer` on top of entry `parent`
+fn build(
+    dir: &Path,
+    source: &Source,
+    layer: &Layer,
+    key: &str,
+    digest: &str,
+    parent: Option<&str>,
+) -> io::Result<()> {
+    let tmp = dir.join(format!(
+        "{}{}-{}",
+        TMP_PREFIX,
+        std::process::id(),
+        NEXT_BUILD.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = build_in(&tmp, dir, source, layer, digest, parent).and_then(|()| {
+        match fs::rename(&tmp, dir.join(key)) {
+            // Another process built the same entry first
+            Err(_) if dir.join(key).join(META).exists() => rootfs::remove_tree(&tmp),
+            result => result,
+        }
+    });
+    if result.is_err() && tmp.exists() {
+        let _ = rootfs::remove_tree(&tmp);
+    }
+    result
+}
+
+fn build_in(
+    tmp: &Path,
+    dir: &Path,
+    source: &Source,
+    layer: &Layer,
+    digest: &str,
+    parent: Option<&str>,
+) -> io::Result<()> {
+    fs::create_dir(tmp)?;
+    let rootfs = tmp.join(ROOTFS);
+    let mut unpacker = match parent {
+        Some(parent) => {
+            let base = dir.join(parent).join(ROOTFS);
+            let lease = Lease::acquire(&base)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("cached layer {} was evicted", parent))
+            })?;
+            // Entries are only ever booted through a clone of their own
+            rootfs::clone_into_shared(&base, &rootfs)?;
+            drop(lease);
+            Unpacker::adopt(&rootfs)?
+        }
+        None => {
+            fs::create_dir(&rootfs)?;
+            Unpacker::new(&rootfs)
+        }
+    };
+    unpacker.apply_layer(source, layer)?;
+    unpacker.finish()?;
+
+    let meta = Meta {
+        layer: digest.to_string(),
+        parent: parent.map(str::to_string),
+        size_bytes: tree_size(&rootfs, &mut HashSet::new())?,
+    };
+    File::create(tmp.join(LOCK))?;
+    let json = serde_json::to_vec(&meta).map_err(io::Error::other)?;
+    fs::write(tmp.join(META), json)
+}
+
+/// Allocated size of `path`, skipping inodes already in `seen`
+fn tree_size(path: &Path, seen: &mut HashSet<(u64, u64)>) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut size = 0;
+    if seen.insert((metadata.dev(), metadata.ino())) {
+        size += metadata.blocks() * 512;
+    }
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            size += tree_size(&entry?.path(), seen)?;
+        }
+    }
+    Ok(size)
+}
+
+fn gc(dir: &Path, keep_bytes: u64) -> io::Result<GcResult> {
+    let entries = match fs::read_dir(dir) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(GcResult {
+                evicted: 0,
+                freed_bytes: 0,
+                kept_bytes: 0,
+            })
+        }
+        entries => entries?,
+    };
+
+    let mut candidates = Vec::new();
+    let mut total = 0;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else { continue };
+
+        if let Some(build) = name.strip_prefix(TMP_PREFIX) {
+            if rootfs::orphaned(build) {
+                rootfs::remove_tree(&path)?;
+            }
+            continue;
+        }
+
+        let meta: Meta = match fs::read(path.join(META)) {
+            Ok(json) => match serde_json::from_slice(&json) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let last_used = fs::metadata(path.join(LOCK))
+            .and_then(|lock| lock.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        total += meta.size_bytes;
+        candidates.push((last_used, meta.size_bytes, path));
+    }
+
+    candidates.sort();
+    let mut evicted = 0;
+    let mut freed = 0;
+    for (_, size, path) in candidates {
+        if total <= keep_bytes {
+            break;
+        }
+        let Ok(lock) = File::open(path.join(LOCK)) else { continue };
+        // Held shared by every context booted from the entry
+        if flock(&lock, libc::LOCK_EX | libc::LOCK_NB).is_err() {
+            continue;
+        }
+        // Leases taken from here on see the entry is gone
+        fs::remove_file(path.join(META))?;
+        rootfs::remove_tree(&path)?;
+        total -= size;
+        freed += size;
+        evicted += 1;
+    }
+
+    Ok(GcResult {
+        evicted,
+        freed_bytes: freed as i64,
+        kept_bytes: total as i64,
+    })
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, TempDir};
+    use std::time::Duration;
+
+    /// An OCI layout of one image whose layers each add the given files
+    fn layout(path: &Path, layers: &[&[(&str, &str)]]) -> PathBuf {
+        let add_blob = |contents: &[u8]| {
+            let hex = format!("{:x}", Sha256::digest(contents));
+            fs::create_dir_all(path.join("blobs/sha256")).unwrap();
+            fs::write(path.join("blobs/sha256").join(&hex), contents).unwrap();
+            format!("sha256:{}", hex)
+        };
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|files| {
+                let mut builder = tar::Builder::new(Vec::new());
+                for (name, contents) in *files {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_uid(0);
+                    header.set_gid(0);
+                    header.set_mode(0o644);
+                    header.set_size(contents.len() as u64);
+                    builder.append_data(&mut header, name, contents.as_bytes()).unwrap();
+                }
+                serde_json::json!({"digest": add_blob(&builder.into_inner().unwrap())})
+            })
+            .collect();
+        let config = add_blob(b"{}");
+        let manifest = add_blob(
+            serde_json::json!({"config": {"digest": config}, "layers": layers})
+                .to_string()
+                .as_bytes(),
+        );
+        write(
+            &path.join("index.json"),
+            &serde_json::json!({"manifests": [{"digest": manifest}]}).to_string(),
+        );
+        path.to_path_buf()
+    }
+
+    fn meta(dir: &Path, key: &str) -> Meta {
+        serde_json::from_slice(&fs::read(dir.join(key).join(META)).unwrap()).unwrap()
+    }
+
+    fn entries(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn keys_chain_layer_digests() {
+        let tmp = TempDir::new();
+        let store = tmp.join("store");
+        let base: &[(&str, &str)] = &[("etc/os", "base")];
+        let app: &[(&str, &str)] = &[("app", "app"), ("etc/.wh.os", "")];
+
+        let first = prepare(&store, &layout(&tmp.join("a"), &[base, app])).unwrap();
+        assert_eq!(first.reused_layers, 0);
+        let rootfs = Path::new(&first.path);
+        assert!(rootfs.join("app").exists() && !rootfs.join("etc/os").exists());
+
+        // Both entries are complete on their own
+        let parent = meta(&store, &first.key).parent.unwrap();
+        assert!(store.join(&parent).join("rootfs/etc/os").exists());
+        assert_eq!(meta(&store, &parent).parent, None);
+        assert_eq!(entries(&store), 2);
+
+        let again = prepare(&store, &layout(&tmp.join("b"), &[base, app])).unwrap();
+        assert_eq!((again.key.as_str(), again.reused_layers), (first.key.as_str(), 2));
+
+        // A shared base is reused, but the same layer on another base is not
+        let other: &[(&str, &str)] = &[("other", "other")];
+        let sibling = prepare(&store, &layout(&tmp.join("c"), &[base, other])).unwrap();
+        assert_eq!(sibling.reused_layers, 1);
+        assert_eq!(meta(&store, &sibling.key).parent.as_deref(), Some(parent.as_str()));
+        let alone = prepare(&store, &layout(&tmp.join("d"), &[app])).unwrap();
+        assert_eq!(alone.reused_layers, 0);
+        assert_ne!(alone.key, first.key);
+        assert_eq!(meta(&store, &alone.key).layer, meta(&store, &first.key).layer);
+        assert_eq!(entries(&store), 4);
+    }
+
+    #[test]
+    fn entries_are_leased_through_their_rootfs() {
+        let tmp = TempDir::new();
+        let store = tmp.join("store");
+        let prepared = prepare(&store, &layout(&tmp.join("image"), &[&[("file", "x")]])).unwrap();
+        let rootfs = Path::new(&prepared.path);
+        assert!(is_entry(rootfs));
+        assert!(Lease::acquire(rootfs).unwrap().is_some());
+
+        let clone = tmp.join("clone/rootfs");
+        fs::create_dir_all(&clone).unwrap();
+        assert!(!is_entry(&clone));
+        assert!(Lease::acquire(&clone).unwrap().is_none());
+    }
+
+    #[test]
+    fn gc_evicts_unleased_entries_least_recently_used_first() {
+        let tmp = TempDir::new();
+        let store = tmp.join("store");
+        let prepare_one = |name: &str| {
+            let image = layout(&tmp.join(name), &[&[(name, "contents")]]);
+            prepare(&store, &image).unwrap()
+        };
+        let (old, new, leased) = (prepare_one("old"), prepare_one("new"), prepare_one("leased"));
+        let used = |prepared: &CachedRootfs, age: u64| {
+            let lock = File::options().write(true).open(store.join(&prepared.key).join(LOCK)).unwrap();
+            lock.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+        };
+        used(&old, 300);
+        used(&new, 200);
+        used(&leased, 100);
+        let _lease = Lease::acquire(Path::new(&leased.path)).unwrap().unwrap();
+
+        // An orphaned build is removed; this process's own is left alone
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let exited = child.id();
+        child.wait().unwrap();
+        fs::create_dir(store.join(format!("{}{}-0", TMP_PREFIX, exited))).unwrap();
+        let building = store.join(format!("{}{}-999", TMP_PREFIX, std::process::id()));
+        fs::create_dir(&building).unwrap();
+
+        let total: u64 = [&old, &new, &leased].iter().map(|p| meta(&store, &p.key).size_bytes).sum();
+        let size = |prepared: &CachedRootfs| meta(&store, &prepared.key).size_bytes;
+        let (old_size, new_size) = (size(&old), size(&new));
+
+        let result = gc(&store, total - 1).unwrap();
+        assert_eq!((result.evicted, result.freed_bytes as u64), (1, old_size));
+        assert!(!store.join(&old.key).exists() && store.join(&new.key).exists());
+        assert_eq!(entries(&store), 3);
+
+        // A leased entry survives even when nothing may be kept
+        let result = gc(&store, 0).unwrap();
+        assert_eq!((result.evicted, result.freed_bytes as u64), (1, new_size));
+        assert_eq!(result.kept_bytes as u64, size(&leased));
+        assert!(store.join(&leased.key).join("rootfs/leased").exists());
+        assert!(building.exists());
+    }
+
+    #[test]
+    fn gc_of_a_missing_store_is_empty() {
+        let tmp = TempDir::new();
+        let result = gc(&tmp.join("missing"), 0).unwrap();
+        assert_eq!((result.evicted, result.freed_bytes, result.kept_bytes), (0, 0, 0));
+    }
+}
@@ -0,0 +1,346 @@
+//! Supervisor child processes.
+//!
+//! `krun_start_enter` never returns on success and calls `exit()` once the
+//! guest shuts down, so it cannot run inside the Node process. Each VM is
+//! instead started by the `krun-supervisor` helper binary, which receives the
+//! recorded [`VmSpec`] on stdin, rebuilds the context and enters the VM.
+//!
+//! The spec is sent as one line and stdin is then kept open until the helper
+//! has been reaped. The helper takes EOF on it as this process having died,
+//! and shuts the VM down rather than leave it running with no owner.
+//!
+//! The helper also inherits the write end of a status pipe as
+//! [`STATUS_FD`]. It only writes to it when the VM cannot be started, which
+//! lets the exit of a broken configuration be told apart from a guest that
+//! happened to exit with the same code. A socket network backend's end for
+//! the VM is inherited as [`NET_FD`].
+
+use crate::spec::{VmSpec, NET_FD, STATUS_FD};
+use std::ffi::{c_void, CStr, OsStr};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Environment variable overriding the helper binary location
+pub const SUPERVISOR_PATH_ENV: &str = "LIBKRUN_SUPERVISOR_PATH";
+
+const SUPERVISOR_BIN: &str = "krun-supervisor";
+
+/// How a supervisor process ended
+#[derive(Clone, Debug)]
+pub struct Exit {
+    pub status: ExitStatus,
+    /// Time from spawn to reap
+    pub wall_time: Duration,
+    /// Error reported by the helper if the VM could not be started
+    pub start_error: Option<String>,
+    /// Whether the host sent SIGKILL, or a SIGTERM that ended the supervisor
+    pub killed_by_host: bool,
+    /// Whether the host asked the guest to shut down
+    pub shutdown_requested: bool,
+}
+
+#[derive(Default)]
+struct Flags {
+    killed: AtomicBool,
+    shutdown_requested: AtomicBool,
+}
+
+impl Flags {
+    /// Whether the host killed the supervisor: it sent SIGKILL, or sent
+    /// SIGTERM and the supervisor died of it, which happens when it could
+    /// not pass the shutdown on to the guest
+    fn host_signalled(&self, status: &ExitStatus) -> bool {
+        use std::os::unix::process::ExitStatusExt;
+
+        self.killed.load(Ordering::SeqCst)
+            || (status.signal() == Some(libc::SIGTERM) && self.shutdown_requested.load(Ordering::SeqCst))
+    }
+}
+
+/// Values dropped once the supervisor has been reaped; `None` after that
+type Held = Arc<Mutex<Option<Vec<Box<dyn Send>>>>>;
+
+/// Handle to a running (or exited) supervisor process
+#[derive(Clone)]
+pub struct Supervisor {
+    pid: u32,
+    flags: Arc<Flags>,
+    held: Held,
+    exit: watch::Receiver<Option<Result<Exit, String>>>,
+}
+
+impl Supervisor {
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Whether the supervisor has already been reaped
+    pub fn has_exited(&self) -> bool {
+        self.exit.borrow().is_some()
+    }
+
+    /// Keep `value` until the supervisor has been reaped, or drop it now if
+    /// it already has
+    pub fn hold_until_exit(&self, value: impl Send + 'static) {
+        let mut held = self.held.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(held) = held.as_mut() {
+            held.push(Box::new(value));
+        }
+    }
+
+    /// Wait for the supervisor to exit
+    pub async fn wait(&self) -> io::Result<Exit> {
+        let mut exit = self.exit.clone();
+        let exit = exit
+            .wait_for(Option::is_some)
+            .await
+            .map(|exit| exit.clone());
+        match exit {
+            Ok(Some(Ok(exit))) => Ok(exit),
+            Ok(Some(Err(reason))) => Err(io::Error::other(reason)),
+            _ => Err(io::Error::other("supervisor reaper exited without a status")),
+        }
+    }
+
+    /// Send `signal` to the supervisor unless it has already been reaped
+    pub fn signal(&self, signal: i32) -> io::Result<()> {
+        if self.has_exited() {
+            return Ok(());
+        }
+        match signal {
+            libc::SIGKILL => self.flags.killed.store(true, Ordering::SeqCst),
+            libc::SIGTERM => self.flags.shutdown_requested.store(true, Ordering::SeqCst),
+            _ => {}
+        }
+        if unsafe { libc::kill(self.pid as libc::pid_t, signal) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the guest to shut down and wait up to `timeout` for the
+    /// supervisor to exit, then kill it. Returns whether the exit was clean.
+    pub async fn shutdown(&self, timeout: Duration) -> io::Result<bool> {
+        // The supervisor turns SIGTERM into a write on the shutdown eventfd
+        self.signal(libc::SIGTERM)?;
+        match tokio::time::timeout(timeout, self.wait()).await {
+            Ok(exit) => exit.map(|_| true),
+            Err(_) => {
+                self.signal(libc::SIGKILL)?;
+                self.wait().await.map(|_| false)
+            }
+        }
+    }
+}
+
+/// Spawn a supervisor for `spec` and hand it the spec over stdin, along
+/// with `net_fd` if the network backend needs one
+pub fn spawn(spec: &VmSpec, net_fd: Option<OwnedFd>) -> io::Result<Supervisor> {
+    spawn_at(&supervisor_path()?, spec, net_fd)
+}
+
+fn spawn_at(program: &Path, spec: &VmSpec, net_fd: Option<OwnedFd>) -> io::Result<Supervisor> {
+    let payload = serde_json::to_vec(spec).map_err(io::Error::other)?;
+
+    // Both ends are close-on-exec; only the dup2'd copy reaches the helper
+    let (mut status_reader, status_writer) = io::pipe()?;
+    let status_raw = status_writer.as_raw_fd();
+    // Moved clear of the descriptors the helper expects, so installing one
+    // cannot overwrite another
+    let net_fd = net_fd.map(|fd| above_reserved(&fd)).transpose()?;
+    let net_raw = net_fd.as_ref().map(AsRawFd::as_raw_fd);
+
+    let mut command = Command::new(program);
+    command.stdin(Stdio::piped());
+    unsafe {
+        command.pre_exec(move || {
+            if status_raw == STATUS_FD {
+                // dup2 onto itself would keep FD_CLOEXEC set
+                if libc::fcntl(STATUS_FD, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            } else if libc::dup2(status_raw, STATUS_FD) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(net_raw) = net_raw {
+                if libc::dup2(net_raw, NET_FD) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let started = Instant::now();
+    let mut child = command.spawn()?;
+    drop(status_writer);
+    // Only the helper may hold the VM's end, so the backend sees it close
+    // when the VM exits
+    drop(net_fd);
+
+    // Stdin stays open once the spec is written; it only closes when the
+    // supervisor is reaped or this process dies
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    if let Err(err) = stdin.write_all(&payload).and_then(|()| stdin.write_all(b"\n")) {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(err);
+    }
+
+    let pid = child.id();
+    let flags = Arc::new(Flags::default());
+    let held: Held = Arc::new(Mutex::new(Some(vec![Box::new(stdin)])));
+    let (tx, rx) = watch::channel(None);
+    let reaper_flags = flags.clone();
+    let reaper_held = held.clone();
+    std::thread::Builder::new()
+        .name(format!("krun-reaper-{}", pid))
+        .spawn(move || {
+            let status = loop {
+                match child.wait() {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result.map_err(|err| err.to_string()),
+                }
+            };
+
+            // The helper writes its report before exiting, so whatever it sent
+            // is already buffered; don't block on descendants holding the pipe
+            let mut report = Vec::new();
+            unsafe {
+                let fd = status_reader.as_raw_fd();
+                libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK);
+            }
+            let _ = status_reader.read_to_end(&mut report);
+            let report = String::from_utf8_lossy(&report);
+            let exit = status.map(|status| Exit {
+                status,
+                wall_time: started.elapsed(),
+                start_error: Some(report.trim().to_string()).filter(|r| !r.is_empty()),
+                killed_by_host: reaper_flags.host_signalled(&status),
+                shutdown_requested: reaper_flags.shutdown_requested.load(Ordering::SeqCst),
+            });
+            // Released before the exit is reported, so waiters see it done
+            drop(reaper_held.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take());
+            let _ = tx.send(Some(exit));
+        })?;
+
+    Ok(Supervisor {
+        pid,
+        flags,
+        held,
+        exit: rx,
+    })
+}
+
+/// A close-on-exec duplicate of `fd` numbered above [`STATUS_FD`] and
+/// [`NET_FD`]
+fn above_reserved(fd: &OwnedFd) -> io::Result<OwnedFd> {
+    let raw = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, NET_FD.max(STATUS_FD) + 1) };
+    if raw < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(raw) })
+}
+
+/// Locate the helper binary: `$LIBKRUN_SUPERVISOR_PATH`, otherwise next to
+/// the loaded `.node` addon
+fn supervisor_path() -> io::Result<PathBuf> {
+    if let Some(path) = std::env::var_os(SUPERVISOR_PATH_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+
+    let path = addon_dir()
+        .map(|dir| dir.join(SUPERVISOR_BIN))
+        .filter(|path| path.is_file());
+    path.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} binary not found next to the native module; set {}",
+                SUPERVISOR_BIN, SUPERVISOR_PATH_ENV
+            ),
+        )
+    })
+}
+
+fn addon_dir() -> Option<PathBuf> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let addr = addon_dir as *const c_void;
+    if unsafe { libc::dladdr(addr, &mut info) } == 0 || info.dli_fname.is_null() {
+        return None;
+    }
+    let file = unsafe { CStr::from_ptr(info.dli_fname) };
+    let file = PathBuf::from(OsStr::from_bytes(file.to_bytes()));
+    file.parent().map(|dir| dir.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, TempDir};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn flags(killed: bool, shutdown_requested: bool) -> Flags {
+        Flags {
+            killed: AtomicBool::new(killed),
+            shutdown_requested: AtomicBool::new(shutdown_requested),
+        }
+    }
+
+    #[test]
+    fn stdin_stays_open_until_the_supervisor_is_reaped() {
+        let tmp = TempDir::new();
+        let helper = tmp.join("helper");
+        let received = tmp.join("spec");
+        write(
+            &helper,
+            &format!(
+                "#!/bin/sh\nread -r spec || exit 2\nprintf '%s' \"$spec\" > '{}'\nexec cat > /dev/null\n",
+                received.display()
+            ),
+        );
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let spec = VmSpec::default();
+        let supervisor = spawn_at(&helper, &spec, None).unwrap();
+        // The helper would exit on EOF
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!supervisor.has_exited());
+        assert_eq!(std::fs::read(&received).unwrap(), serde_json::to_vec(&spec).unwrap());
+
+        supervisor.signal(libc::SIGKILL).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let exit = runtime.block_on(supervisor.wait()).unwrap();
+        assert!(exit.killed_by_host);
+    }
+
+    #[test]
+    fn sigterm_from_the_host_counts_as_a_kill() {
+        let sigterm = ExitStatus::from_raw(libc::SIGTERM);
+        assert!(flags(false, true).host_signalled(&sigterm));
+        // Someone else's SIGTERM
+        assert!(!flags(false, false).host_signalled(&sigterm));
+        // The guest shut down as asked
+        assert!(!flags(false, true).host_signalled(&ExitStatus::from_raw(0)));
+    }
+
+    #[test]
+    fn sigkill_from_the_host_counts_as_a_kill() {
+        let sigkill = ExitStatus::from_raw(libc::SIGKILL);
+        assert!(flags(true, false).host_signalled(&sigkill));
+        assert!(flags(true, true).host_signalled(&sigkill));
+        assert!(!flags(false, false).host_signalled(&sigkill));
+        assert!(!flags(false, true).host_signalled(&ExitStatus::from_raw(libc::SIGSEGV)));
+    }
+}
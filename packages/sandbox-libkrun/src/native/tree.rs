@@ -0,0 +1,366 @@
+//! Snapshots of a host directory tree and the changes between two of them.
+//!
+//! A guest writes to its mounts straight through virtio-fs, so the way to
+//! see what a run changed is to compare the host directory before and
+//! after. [`TreeSnapshot`] records every file, directory and symlink with
+//! its mode and size, and the SHA-256 of each file's contents; hashing is
+//! spread over one thread per CPU since it dominates the cost. Symlinks are
+//! recorded by target and never followed.
+
+use napi_derive::napi;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// What a tree entry is
+#[napi(string_enum = "camelCase")]
+#[derive(Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    kind: EntryKind,
+    /// Permission bits, including setuid, setgid and sticky
+    mode: u32,
+    /// File length, or symlink target length
+    size: u64,
+    /// File contents or symlink target; unused for directories
+    digest: [u8; 32],
+}
+
+/// The state of a directory tree at one point in time
+#[napi]
+pub struct TreeSnapshot {
+    root: PathBuf,
+    /// Keyed by path relative to `root`
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+#[napi]
+impl TreeSnapshot {
+    /// Directory the snapshot was taken of
+    #[napi(getter, catch_unwind)]
+    pub fn root(&self) -> String {
+        self.root.to_string_lossy().into_owned()
+    }
+
+    /// Number of regular files
+    #[napi(getter, catch_unwind)]
+    pub fn file_count(&self) -> u32 {
+        self.files().count() as u32
+    }
+
+    /// Total size of the regular files
+    #[napi(getter, catch_unwind)]
+    pub fn total_bytes(&self) -> i64 {
+        self.files().map(|entry| entry.size as i64).sum()
+    }
+}
+
+impl TreeSnapshot {
+    fn files(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values().filter(|entry| entry.kind == EntryKind::File)
+    }
+}
+
+/// A path that differs between two snapshots
+#[napi(object)]
+pub struct TreeChange {
+    /// Path relative to the snapshot root
+    pub path: String,
+    /// Kind in the later snapshot, or the earlier one for deletions
+    pub kind: EntryKind,
+    /// Size in the later snapshot, or the earlier one for deletions
+    pub size: i64,
+    /// Size in the earlier snapshot, for modifications
+    pub previous_size: Option<i64>,
+    pub mode: u32,
+    /// Mode in the earlier snapshot, when it changed
+    pub previous_mode: Option<u32>,
+}
+
+/// Differences between two snapshots, each list sorted by path
+#[napi(object)]
+pub struct TreeDiff {
+    pub added: Vec<TreeChange>,
+    /// Contents, symlink target or kind changed
+    pub modified: Vec<TreeChange>,
+    pub deleted: Vec<TreeChange>,
+    /// Only the mode changed
+    pub mode_changed: Vec<TreeChange>,
+}
+
+/// Record every entry under `root`, hashing file contents in parallel
+///
+/// Symlinks are recorded by target rather than followed, and sockets,
+/// FIFOs and device nodes are skipped. Files that disappear while the
+/// snapshot is taken are left out.
+pub fn snapshot(root: &Path) -> io::Result<TreeSnapshot> {
+    let root = fs::canonicalize(root)?;
+    if !fs::metadata(&root)?.is_dir() {
+        return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+    }
+
+    let mut entries = BTreeMap::new();
+    let mut files = Vec::new();
+    walk(&root, Path::new(""), &mut entries, &mut files)?;
+
+    // Hash on a pool of threads pulling from a shared index
+    let next = AtomicUsize::new(0);
+    let hashed = Mutex::new(Vec::with_capacity(files.len()));
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get()).min(files.len().max(1));
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| -> io::Result<()> {
+                    loop {
+                        let Some(relative) = files.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                            return Ok(());
+                        };
+                        match hash_file(&root.join(relative)) {
+                            Ok(digest) => hashed
+                                .lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                .push((relative, digest)),
+                            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                            Err(err) => {
+                                return Err(io::Error::new(
+                                    err.kind(),
+                                    format!("{}: {}", root.join(relative).display(), err),
+                                ))
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap_or_else(|_| Err(io::Error::other("hashing thread panicked"))))
+    })?;
+
+    let hashed = hashed.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut digests: BTreeMap<&PathBuf, [u8; 32]> = hashed.into_iter().collect();
+    // Files that vanished before they could be hashed
+    entries.retain(|path, entry| match entry.kind {
+        EntryKind::File => match digests.remove(path) {
+            Some(digest) => {
+                entry.digest = digest;
+                true
+            }
+            None => false,
+        },
+        _ => true,
+    });
+
+    Ok(TreeSnapshot { root, entries })
+}
+
+/// Record the entries under `root.join(relative)`, collecting the files to
+/// hash
+fn walk(
+    root: &Path,
+    relative: &Path,
+    entries: &mut BTreeMap<PathBuf, Entry>,
+    files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let dir = match fs::read_dir(root.join(relative)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        dir => dir?,
+    };
+    for dirent in dir {
+        let dirent = dirent?;
+        let path = relative.join(dirent.file_name());
+        let metadata = match dirent.metadata() {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            metadata => metadata?,
+        };
+        let mode = metadata.permissions().mode() & 0o7777;
+        let file_type = metadata.file_type();
+
+        let entry = if file_type.is_file() {
+            files.push(path.clone());
+            Entry {
+                kind: EntryKind::File,
+                mode,
+                size: metadata.len(),
+                digest: [0; 32],
+            }
+        } else if file_type.is_symlink() {
+            let target = match fs::read_link(root.join(&path)) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                target => target?,
+            };
+            let target = target.as_os_str().as_bytes();
+            Entry {
+                kind: EntryKind::Symlink,
+                mode,
+                size: target.len() as u64,
+                digest: Sha256::digest(target).into(),
+            }
+        } else if file_type.is_dir() {
+            walk(root, &path, entries, files)?;
+            Entry {
+                kind: EntryKind::Dir,
+                mode,
+                size: 0,
+                digest: [0; 32],
+            }
+        } else {
+            continue;
+        };
+        entries.insert(path, entry);
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Compare two snapshots
+pub fn diff(before: &TreeSnapshot, after: &TreeSnapshot) -> TreeDiff {
+    let mut diff = TreeDiff {
+        added: Vec::new(),
+        modified: Vec::new(),
+        deleted: Vec::new(),
+        mode_changed: Vec::new(),
+    };
+    let change = |path: &Path, entry: &Entry| TreeChange {
+        path: path.to_string_lossy().into_owned(),
+        kind: entry.kind,
+        size: entry.size as i64,
+        previous_size: None,
+        mode: entry.mode,
+        previous_mode: None,
+    };
+
+    for (path, old) in &before.entries {
+        let Some(new) = after.entries.get(path) else {
+            diff.deleted.push(change(path, old));
+            continue;
+        };
+        let mut changed = change(path, new);
+        changed.previous_mode = Some(old.mode).filter(|mode| *mode != new.mode);
+        let content_changed = old.kind != new.kind || (new.kind != EntryKind::Dir && old.digest != new.digest);
+        if content_changed {
+            changed.previous_size = Some(old.size as i64);
+            diff.modified.push(changed);
+        } else if changed.previous_mode.is_some() {
+            diff.mode_changed.push(changed);
+        }
+    }
+    for (path, new) in &after.entries {
+        if !before.entries.contains_key(path) {
+            diff.added.push(change(path, new));
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{write, TempDir};
+    use std::os::unix::fs::symlink;
+
+    fn paths(changes: &[TreeChange]) -> Vec<&str> {
+        changes.iter().map(|change| change.path.as_str()).collect()
+    }
+
+    #[test]
+    fn diff_sorts_changes_by_kind() {
+        let tmp = TempDir::new();
+        let root = tmp.join("root");
+        write(&root.join("kept"), "same");
+        write(&root.join("edited"), "before");
+        write(&root.join("gone/file"), "bye");
+        write(&root.join("chmodded"), "same");
+        symlink("kept", root.join("link")).unwrap();
+        let before = snapshot(&root).unwrap();
+
+        write(&root.join("edited"), "after!!");
+        fs::remove_dir_all(root.join("gone")).unwrap();
+        fs::set_permissions(root.join("chmodded"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::remove_file(root.join("link")).unwrap();
+        symlink("edited", root.join("link")).unwrap();
+        write(&root.join("new/file"), "hello");
+        let after = snapshot(&root).unwrap();
+
+        let diff = diff(&before, &after);
+        assert_eq!(paths(&diff.added), ["new", "new/file"]);
+        assert_eq!(paths(&diff.modified), ["edited", "link"]);
+        assert_eq!(paths(&diff.deleted), ["gone", "gone/file"]);
+        assert_eq!(paths(&diff.mode_changed), ["chmodded"]);
+
+        let edited = &diff.modified[0];
+        assert_eq!((edited.size, edited.previous_size), (7, Some(6)));
+        assert_eq!(edited.previous_mode, None);
+        let chmodded = &diff.mode_changed[0];
+        assert_eq!(chmodded.mode, 0o600);
+        assert_ne!(chmodded.previous_mode, Some(0o600));
+        assert_eq!(diff.deleted[1].kind, EntryKind::File);
+    }
+
+    #[test]
+    fn same_size_rewrites_are_modifications() {
+        let tmp = TempDir::new();
+        let root = tmp.join("root");
+        write(&root.join("file"), "aaaa");
+        let before = snapshot(&root).unwrap();
+        write(&root.join("file"), "bbbb");
+        let diff = diff(&before, &snapshot(&root).unwrap());
+        assert_eq!(paths(&diff.modified), ["file"]);
+        assert_eq!(diff.modified[0].previous_size, Some(4));
+    }
+
+    #[test]
+    fn kind_changes_are_modifications() {
+        let tmp = TempDir::new();
+        let root = tmp.join("root");
+        write(&root.join("entry"), "file");
+        let before = snapshot(&root).unwrap();
+        fs::remove_file(root.join("entry")).unwrap();
+        fs::create_dir(root.join("entry")).unwrap();
+        let diff = diff(&before, &snapshot(&root).unwrap());
+        assert_eq!(paths(&diff.modified), ["entry"]);
+        assert_eq!(diff.modified[0].kind, EntryKind::Dir);
+    }
+
+    #[test]
+    fn snapshot_counts_files_and_skips_sockets() {
+        let tmp = TempDir::new();
+        let root = tmp.join("root");
+        write(&root.join("a"), "12345");
+        write(&root.join("dir/b"), "123");
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+        let snapshot = snapshot(&root).unwrap();
+        assert_eq!(snapshot.file_count(), 2);
+        assert_eq!(snapshot.total_bytes(), 8);
+        assert!(!snapshot.entries.contains_key(Path::new("socket")));
+        assert!(self::snapshot(&root.join("a")).is_err());
+    }
+
+    #[test]
+    fn unchanged_trees_have_no_diff() {
+        let tmp = TempDir::new();
+        let root = tmp.join("root");
+        write(&root.join("dir/file"), "contents");
+        let before = snapshot(&root).unwrap();
+        let diff = diff(&before, &snapshot(&root).unwrap());
+        assert!(diff.added.is_empty() && diff.modified.is_empty());
+        assert!(diff.deleted.is_empty() && diff.mode_changed.is_empty());
+    }
+}
@@ -0,0 +1,437 @@
+//! Pre-flight checks of a [`VmSpec`] against the host.
+//!
+//! libkrun accepts most settings without looking at the host, so a missing
+//! rootfs or an oversized VM otherwise only shows up once the guest boots.
+//! Every check runs and all problems are reported together.
+
+use crate::error::KrunError;
+use crate::spec::{NetworkSpec, RootSpec, VmSpec, ROOT_BLOCK_ID, SCRATCH_BLOCK_ID};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// virtio-fs limits tags to 36 bytes
+const MAX_TAG_LEN: usize = 36;
+
+/// Everything wrong with `spec` on this host
+pub fn problems(spec: &VmSpec) -> Vec<KrunError> {
+    let mut problems = Vec::new();
+
+    if spec.cpus == 0 {
+        problems.push(KrunError::invalid("cpus", "must be at least 1"));
+    } else if let Some(max) = max_vcpus() {
+        if u32::from(spec.cpus) > max {
+            problems.push(KrunError::invalid(
+                "cpus",
+                format!("{} exceeds the {} vCPUs supported on this host", spec.cpus, max),
+            ));
+        }
+    }
+
+    if spec.memory_mib == 0 {
+        problems.push(KrunError::invalid("memoryMib", "must be at least 1"));
+    } else if let Some(host) = host_memory_mib() {
+        if u64::from(spec.memory_mib) > host {
+            problems.push(KrunError::invalid(
+                "memoryMib",
+                format!("{} MiB exceeds the host's {} MiB", spec.memory_mib, host),
+            ));
+        }
+    }
+
+    // Each block ID and what it is attached for
+    let mut block_ids = BTreeMap::new();
+    match &spec.root {
+        RootSpec::Dir(path) if path.is_empty() => {
+            problems.push(KrunError::invalid("rootfsPath", "either rootfsPath or root is required"));
+        }
+        RootSpec::Dir(path) => check_dir(&mut problems, "rootfsPath", path),
+        RootSpec::Disk {
+            path,
+            device,
+            fstype,
+            options,
+        } => {
+            // The image is always attached read-write
+            if check_image(&mut problems, "root.path", path) && !writable(path) {
+                problems.push(KrunError::invalid("root.path", format!("{} is not writable", path)));
+            }
+            if !device.starts_with("/dev/") {
+                problems.push(KrunError::invalid("root.device", "must be a guest path under /dev/"));
+            }
+            for (field, value) in [
+                ("root.device", Some(device)),
+                ("root.fstype", fstype.as_ref()),
+                ("root.options", options.as_ref()),
+            ] {
+                if value.is_some_and(|value| value.contains('\0')) {
+                    problems.push(KrunError::invalid(field, "must not contain NUL"));
+                }
+            }
+            // The image occupies this block ID
+            block_ids.insert(ROOT_BLOCK_ID, "the disk root");
+        }
+    }
+
+    if let Some(workdir) = &spec.workdir {
+        if !workdir.starts_with('/') {
+            problems.push(KrunError::invalid("workdir", "must be an absolute guest path"));
+        } else if workdir.contains('\0') {
+            problems.push(KrunError::invalid("workdir", "must not contain NUL"));
+        }
+    }
+
+    let mut tags = BTreeSet::new();
+    let mut guest_paths = BTreeSet::new();
+    for mount in &spec.mounts {
+        let tag = &mount.tag;
+        let field = format!("mounts.{}", tag);
+        if tag.is_empty() {
+            problems.push(KrunError::invalid("mounts", "tag must not be empty"));
+        } else if tag.contains('\0') {
+            problems.push(KrunError::invalid("mounts", "tag must not contain NUL"));
+        } else if tag.len() > MAX_TAG_LEN {
+            problems.push(KrunError::invalid(
+                &field,
+                format!("tag is longer than {} bytes", MAX_TAG_LEN),
+            ));
+        }
+        if !tags.insert(tag.as_str()) {
+            problems.push(KrunError::invalid(&field, "duplicate virtiofs tag"));
+        }
+        check_dir(&mut problems, &field, &mount.host_path);
+
+        match &mount.guest_path {
+            Some(path) if !path.starts_with('/') || path == "/" => problems.push(KrunError::invalid(
+                format!("{}.guestPath", field),
+                "must be an absolute guest path other than /",
+            )),
+            Some(path) if path.contains('\0') => {
+                problems.push(KrunError::invalid(format!("{}.guestPath", field), "must not contain NUL"));
+            }
+            Some(path) if !guest_paths.insert(path.trim_end_matches('/')) => {
+                problems.push(KrunError::invalid(format!("{}.guestPath", field), "duplicate guestPath"));
+            }
+            Some(_) => {}
+            // Only the guest's mount can be read-only; libkrun's virtio-fs
+            // has no such mode
+            None if mount.read_only => {
+                problems.push(KrunError::invalid(format!("{}.readOnly", field), "requires guestPath"));
+            }
+            None => {}
+        }
+        if mount.shm_size_mib == Some(0) {
+            problems.push(KrunError::invalid(format!("{}.shmSizeMib", field), "must be at least 1"));
+        }
+    }
+
+    if let NetworkSpec::Passt { binary } = &spec.network {
+        if binary.is_empty() {
+            problems.push(KrunError::invalid("network.binary", "must not be empty"));
+        } else if binary.contains('\0') {
+            problems.push(KrunError::invalid("network.binary", "must not contain NUL"));
+        } else if find_executable(binary).is_none() {
+            problems.push(KrunError::invalid(
+                "network.binary",
+                format!("{} is not an executable file or a command on PATH", binary),
+            ));
+        }
+    }
+
+    if let Some(path) = spec.network.socket_path() {
+        check_socket(&mut problems, path);
+    }
+
+    if let Some(egress) = &spec.egress {
+        // Any network of its own gives the guest a way around the proxy
+        if spec.network != NetworkSpec::None {
+            problems.push(KrunError::invalid(
+                "egress",
+                "requires network kind none, as the guest could bypass the proxy over any other network",
+            ));
+        }
+        if let Some(path) = &egress.log_path {
+            if !path.starts_with('/') {
+                problems.push(KrunError::invalid("egress.logPath", "must be an absolute path"));
+            } else if path.contains('\0') {
+                problems.push(KrunError::invalid("egress.logPath", "must not contain NUL"));
+            }
+        }
+    }
+
+    if let Some(scratch) = &spec.scratch_disk {
+        if scratch.size_mib == 0 {
+            problems.push(KrunError::invalid("scratchDisk.sizeMib", "must be at least 1"));
+        }
+        block_ids.insert(SCRATCH_BLOCK_ID, "the scratch disk");
+    }
+
+    for disk in &spec.disks {
+        let field = format!("disks.{}", disk.block_id);
+        if disk.block_id.is_empty() {
+            problems.push(KrunError::invalid("disks", "blockId must not be empty"));
+        } else if disk.block_id.contains('\0') {
+            problems.push(KrunError::invalid("disks", "blockId must not contain NUL"));
+        }
+        if let Some(user) = block_ids.insert(disk.block_id.as_str(), "another disk") {
+            problems.push(KrunError::invalid(&field, format!("blockId is already used by {}", user)));
+        }
+        // libkrun opens writable disks read-write and fails on images the
+        // caller cannot write, such as ones under /usr/share
+        if check_image(&mut problems, &field, &disk.path) && !disk.read_only && !writable(&disk.path) {
+            problems.push(KrunError::invalid(
+                &field,
+                format!("{} is not writable; set readOnly", disk.path),
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Whether `path` is a disk image libkrun can open
+fn check_image(problems: &mut Vec<KrunError>, field: &str, path: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_file() || meta.file_type().is_block_device() => true,
+        Ok(_) => {
+            problems.push(KrunError::invalid(field, format!("{} is not a file or block device", path)));
+            false
+        }
+        Err(err) => {
+            problems.push(KrunError::invalid(field, format!("{}: {}", path, err)));
+            false
+        }
+    }
+}
+
+fn writable(path: &str) -> bool {
+    std::ffi::CString::new(path)
+        .map(|path| unsafe { libc::access(path.as_ptr(), libc::W_OK) } == 0)
+        .unwrap_or(false)
+}
+
+/// Whether `path` is a Unix socket whose address fits in a `sockaddr_un`
+fn check_socket(problems: &mut Vec<KrunError>, path: &str) {
+    use std::os::unix::fs::FileTypeExt;
+
+    // sun_path also holds the terminating NUL
+    let max_len = unsafe { std::mem::zeroed::<libc::sockaddr_un>() }.sun_path.len() - 1;
+    if path.is_empty() {
+        problems.push(KrunError::invalid(
+            "network.socketPath",
+            "is required for gvproxy, unixstream and unixgram",
+        ));
+    } else if path.contains('\0') {
+        problems.push(KrunError::invalid("network.socketPath", "must not contain NUL"));
+    } else if path.len() > max_len {
+        problems.push(KrunError::invalid(
+            "network.socketPath",
+            format!("{} is {} bytes, over the {}-byte limit for socket paths", path, path.len(), max_len),
+        ));
+    } else {
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => {}
+            Ok(_) => problems.push(KrunError::invalid("network.socketPath", format!("{} is not a socket", path))),
+            Err(err) => problems.push(KrunError::invalid("network.socketPath", format!("{}: {}", path, err))),
+        }
+    }
+}
+
+/// `name` if it contains a slash, otherwise the first match on `PATH`, as
+/// long as it is an executable file
+fn find_executable(name: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let executable =
+        |path: &Path| std::fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0);
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return executable(&path).then_some(path);
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|path| executable(path))
+}
+
+fn check_dir(problems: &mut Vec<KrunError>, field: &str, path: &str) {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => problems.push(KrunError::invalid(field, format!("{} is not a directory", path))),
+        Err(err) => problems.push(KrunError::invalid(field, format!("{}: {}", path, err))),
+    }
+}
+
+/// vCPU limit of the hypervisor, if libkrun can tell
+fn max_vcpus() -> Option<u32> {
+    #[cfg(target_os = "macos")]
+    {
+        let max = unsafe { crate::ffi::krun_get_max_vcpus() };
+        u32::try_from(max).ok().filter(|max| *max > 0)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        None
+    }
+}
+
+/// Physical memory of the host in MiB
+fn host_memory_mib() -> Option<u64> {
+    #[cfg(target_os = "macos")]
+    let bytes = {
+        let mut bytes: u64 = 0;
+        let mut len = std::mem::size_of::<u64>();
+        let ret = unsafe {
+            libc::sysctlbyname(
+                c"hw.memsize".as_ptr(),
+                &mut bytes as *mut u64 as *mut libc::c_void,
+                &mut len,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        (ret == 0).then_some(bytes)?
+    };
+
+    #[cfg(not(target_os = "macos"))]
+    let bytes = {
+        let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        u64::try_from(pages).ok()? * u64::try_from(page_size).ok()?
+    };
+
+    Some(bytes / (1024 * 1024))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{DiskFormat, DiskSpec, ScratchDiskSpec};
+    use crate::testutil::{write, TempDir};
+
+    #[test]
+    fn disk_ids_of_root_and_scratch_are_reserved() {
+        let tmp = TempDir::new();
+        let image = tmp.join("disk.img");
+        write(&image, "");
+        let image = image.to_str().unwrap().to_string();
+        let disk = |block_id: &str| DiskSpec {
+            block_id: block_id.to_string(),
+            path: image.clone(),
+            format: DiskFormat::Raw,
+            read_only: false,
+        };
+        let spec = VmSpec {
+            root: RootSpec::Disk {
+                path: image.clone(),
+                device: "/dev/vda".to_string(),
+                fstype: None,
+                options: None,
+            },
+            disks: vec![disk("root"), disk("scratch"), disk("data"), disk("data")],
+            scratch_disk: Some(ScratchDiskSpec {
+                size_mib: 1,
+                path: String::new(),
+            }),
+            ..VmSpec::default()
+        };
+        let problems: Vec<_> = problems(&spec)
+            .iter()
+            .filter(|problem| problem.field().is_some_and(|field| field.starts_with("disks")))
+            .map(|problem| (problem.field().unwrap().to_string(), problem.to_string()))
+            .collect();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert_eq!(problems[0].0, "disks.root");
+        assert!(problems[0].1.contains("already used by the disk root"), "{}", problems[0].1);
+        assert_eq!(problems[1].0, "disks.scratch");
+        assert!(problems[1].1.contains("already used by the scratch disk"), "{}", problems[1].1);
+        assert_eq!(problems[2].0, "disks.data");
+        assert!(problems[2].1.contains("already used by another disk"), "{}", problems[2].1);
+    }
+
+    #[test]
+    fn scratch_disk_combines_with_a_disk_root() {
+        let tmp = TempDir::new();
+        let image = tmp.join("root.img");
+        write(&image, "");
+        let image = image.to_str().unwrap().to_string();
+        let spec = VmSpec {
+            root: RootSpec::Disk {
+                path: image.clone(),
+                device: "/dev/vda".to_string(),
+                fstype: None,
+                options: None,
+            },
+            disks: vec![DiskSpec {
+                block_id: "data".to_string(),
+                path: image,
+                format: DiskFormat::Raw,
+                read_only: true,
+            }],
+            scratch_disk: Some(ScratchDiskSpec {
+                size_mib: 64,
+                path: String::new(),
+            }),
+            ..VmSpec::default()
+        };
+        let problems: Vec<_> = problems(&spec)
+            .iter()
+            .filter_map(|problem| problem.field().map(str::to_string))
+            .filter(|field| field.starts_with("root") || field.starts_with("disks") || field.starts_with("scratch"))
+            .collect();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn disk_ids_are_free_with_a_dir_root() {
+        let tmp = TempDir::new();
+        let image = tmp.join("disk.img");
+        write(&image, "");
+        let spec = VmSpec {
+            root: RootSpec::Dir(tmp.join("").to_str().unwrap().to_string()),
+            disks: vec![DiskSpec {
+                block_id: "root".to_string(),
+                path: image.to_str().unwrap().to_string(),
+                format: DiskFormat::Raw,
+                read_only: false,
+            }],
+            ..VmSpec::default()
+        };
+        assert!(problems(&spec).iter().all(|problem| problem.field() != Some("disks.root")));
+    }
+
+    #[test]
+    fn egress_requires_network_none() {
+        let egress = |log_path: Option<&str>| {
+            Some(crate::spec::EgressSpec {
+                policy: Default::default(),
+                vsock_port: 3128,
+                socket_path: String::new(),
+                log_path: log_path.map(str::to_string),
+            })
+        };
+        let egress_problems = |network, egress| {
+            let spec = VmSpec {
+                network,
+                egress,
+                ..VmSpec::default()
+            };
+            problems(&spec)
+                .iter()
+                .filter_map(|problem| problem.field().filter(|field| field.starts_with("egress")).map(str::to_string))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(egress_problems(NetworkSpec::Tsi, egress(None)), ["egress"]);
+        assert_eq!(
+            egress_problems(NetworkSpec::Nat { host_loopback: false }, egress(None)),
+            ["egress"]
+        );
+        assert!(egress_problems(NetworkSpec::None, egress(None)).is_empty());
+        assert!(egress_problems(NetworkSpec::None, egress(Some("/tmp/egress.log"))).is_empty());
+        assert_eq!(egress_problems(NetworkSpec::None, egress(Some("egress.log"))), ["egress.logPath"]);
+        assert!(egress_problems(NetworkSpec::Tsi, None).is_empty());
+    }
+}
@@ -4,7 +4,7 @@ import type {
   SandboxConfig,
   ProviderInfo,
 } from '@sandbox/core';
-import type { LibkrunNative, LibkrunConfig } from './types.js';
+import type { LibkrunNative, LibkrunConfig, LibkrunProviderOptions } from './types.js';
 import { LibkrunSandbox } from './sandbox.js';
 
 /**
@@ -16,6 +16,8 @@ export class LibkrunProvider implements ISandboxProvider {
   private loadAttempted = false;
   private loadError: Error | null = null;
 
+  constructor(private readonly options: LibkrunProviderOptions = {}) {}
+
   /**
    * Load the native module
    */
@@ -53,6 +55,8 @@ export class LibkrunProvider implements ISandboxProvider {
       cpus: config.cpus ?? 1,
       memoryMib: config.memoryMib ?? 512,
       rootfsPath: '/opt/libkrun/rootfs', // Default rootfs location
+      // Keep each sandbox's changes out of the shared base
+      cloneRootfs: true,
       workdir: '/workspace',
       mounts: {
         workspace: config.mountPath,
@@ -61,20 +65,27 @@ export class LibkrunProvider implements ISandboxProvider {
     };
 
     if (config.sshPort) {
-      libkrunConfig.portMap = [`${config.sshPort}:22`];
+      libkrunConfig.portMap = [{ hostPort: config.sshPort, guestPort: 22 }];
+    }
+
+    const problems = native.validateConfig(libkrunConfig);
+    if (problems.length > 0) {
+      const details = problems.map((p) => `  ${p.message}`).join('\n');
+      throw new Error(`Invalid libkrun config:\n${details}`);
     }
 
     const startTime = performance.now();
-    const vmInfo = native.createContext(libkrunConfig);
+    const handle = new native.VmHandle(libkrunConfig);
     const startupMs = performance.now() - startTime;
 
     return new LibkrunSandbox({
       id: config.id,
+      handle,
       native,
-      vmInfo,
       sshPort: config.sshPort ?? 0,
       mountPath: config.mountPath,
       startupMs,
+      reportChanges: this.options.reportChanges ?? false,
     });
   }
 
@@ -4,15 +4,27 @@ import type {
   SandboxMetrics,
 } from '@sandbox/core';
 import { SSHClient, waitForSSH } from '@sandbox/core';
-import type { LibkrunNative, VmInfo } from './types.js';
+import type {
+  EgressBlock,
+  LibkrunNative,
+  RollbackOptions,
+  TreeDiff,
+  TreeSnapshot,
+  VmExit,
+  VmHandle,
+  VmInfo,
+  VmProcess,
+} from './types.js';
 
 interface LibkrunSandboxOptions {
   id: string;
-  native: LibkrunNative;
-  vmInfo: VmInfo;
+  handle: VmHandle;
+  native: Pick<LibkrunNative, 'snapshotTree' | 'diffTree'>;
   sshPort: number;
   mountPath: string;
   startupMs: number;
+  /** Snapshot mountPath on start and stop for getChanges() */
+  reportChanges: boolean;
 }
 
 /**
@@ -24,16 +36,24 @@ export class LibkrunSandbox implements ISandbox {
   readonly mountPath: string;
   readonly provider = 'libkrun';
 
-  private readonly native: LibkrunNative;
+  private readonly handle: VmHandle;
+  private readonly native: LibkrunSandboxOptions['native'];
+  private readonly reportChanges: boolean;
   private readonly vmInfo: VmInfo;
   private running = false;
+  private process: VmProcess | null = null;
+  private exit: VmExit | null = null;
+  private baseline: TreeSnapshot | null = null;
+  private changes: TreeDiff | null = null;
   private sshClient: SSHClient | null = null;
   private metrics: SandboxMetrics;
 
   constructor(options: LibkrunSandboxOptions) {
     this.id = options.id;
+    this.handle = options.handle;
     this.native = options.native;
-    this.vmInfo = options.vmInfo;
+    this.reportChanges = options.reportChanges;
+    this.vmInfo = options.handle.info();
     this.sshPort = options.sshPort;
     this.mountPath = options.mountPath;
     this.metrics = {
@@ -45,23 +65,43 @@ export class LibkrunSandbox implements ISandbox {
   }
 
   /**
-   * Start the VM in a subprocess
-   * Note: krun_start_enter blocks, so we need to fork
+   * Start the VM in a supervisor subprocess
+   * Note: krun_start_enter takes over its process, so the native module
+   * runs it in the krun-supervisor helper
    */
   async start(): Promise<void> {
     if (this.running) return;
 
+    // Baseline for the change report made on stop; hashing the whole
+    // mount path is only worth it when the report was asked for
+    this.changes = null;
+    if (this.reportChanges) {
+      try {
+        this.baseline = await this.native.snapshotTree(this.mountPath);
+      } catch (err) {
+        console.warn('Error snapshotting libkrun workspace:', err);
+        this.baseline = null;
+      }
+    }
+
     // Set up the init process
-    this.native.setExec(
-      this.vmInfo.ctxId,
+    this.handle.setExec(
       '/sbin/init',
       ['init'],
       { PATH: '/usr/local/bin:/usr/bin:/bin' }
     );
 
-    // TODO: Start VM in subprocess and communicate via vsock
-    // For now, mark as running - actual VM launch needs subprocess management
+    this.process = this.handle.start();
     this.running = true;
+
+    this.exit = null;
+    this.process.wait().then(
+      (exit) => {
+        this.exit = exit;
+        this.running = false;
+      },
+      () => { this.running = false; }
+    );
   }
 
   async exec(cmd: string, args: string[] = []): Promise<ExecResult> {
@@ -126,11 +166,29 @@ export class LibkrunSandbox implements ISandbox {
 
     if (this.running) {
       try {
-        this.native.freeContext(this.vmInfo.ctxId);
+        // Give the guest a chance to flush the workspace before teardown
+        await this.handle.shutdown({ timeoutMs: 10000 });
+      } catch (err) {
+        console.warn('Error shutting down libkrun VM:', err);
+      }
+    }
+
+    try {
+      this.handle.stop();
+    } catch (err) {
+      console.warn('Error freeing libkrun context:', err);
+    }
+    this.running = false;
+    this.process = null;
+
+    if (this.baseline) {
+      try {
+        const after = await this.native.snapshotTree(this.mountPath);
+        this.changes = this.native.diffTree(this.baseline, after);
       } catch (err) {
-        console.warn('Error freeing libkrun context:', err);
+        console.warn('Error snapshotting libkrun workspace:', err);
       }
-      this.running = false;
+      this.baseline = null;
     }
   }
 
@@ -145,6 +203,58 @@ export class LibkrunSandbox implements ISandbox {
     return this.vmInfo.cid;
   }
 
+  /**
+   * How the VM stopped, once it has
+   */
+  getExit(): VmExit | null {
+    return this.exit ? { ...this.exit } : null;
+  }
+
+  /**
+   * Save the workspace as checkpoint `tag`, e.g. before a risky command
+   */
+  async checkpoint(tag: string): Promise<void> {
+    await this.handle.checkpoint(tag);
+  }
+
+  /**
+   * Restore the workspace from checkpoint `tag`
+   * Refuses while the VM is running unless `force` is set
+   */
+  async rollback(tag: string, options?: RollbackOptions): Promise<void> {
+    await this.handle.rollback(tag, options);
+  }
+
+  /**
+   * Remove checkpoint `tag`, resolving to whether it existed
+   */
+  async dropCheckpoint(tag: string): Promise<boolean> {
+    return this.handle.dropCheckpoint(tag);
+  }
+
+  /**
+   * Connections the egress proxy refused, oldest first
+   */
+  getBlockedEgress(): EgressBlock[] {
+    return this.handle.blockedEgress();
+  }
+
+  /**
+   * How many refused connections getBlockedEgress() no longer lists
+   */
+  getBlockedEgressDropped(): number {
+    return this.handle.blockedEgressDropped();
+  }
+
+  /**
+   * Files the VM added, modified, deleted or chmodded under the mount
+   * path, once it has stopped; null unless the provider was created with
+   * reportChanges
+   */
+  getChanges(): TreeDiff | null {
+    return this.changes;
+  }
+
   /**
    * Get VM info
    */
@@ -7,18 +7,150 @@ export interface LibkrunConfig {
   cpus?: number;
   /** Memory in MiB (default: 512) */
   memoryMib?: number;
-  /** Root filesystem path */
-  rootfsPath: string;
+  /** Root filesystem directory; exclusive with root */
+  rootfsPath?: string;
+  /** Raw disk image to boot from instead of rootfsPath */
+  root?: RootConfig;
+  /** Boot from a private clone of rootfsPath, removed when the context is freed */
+  cloneRootfs?: boolean;
   /** Working directory inside VM */
   workdir?: string;
-  /** virtiofs mounts: { tag: hostPath } */
-  mounts?: Record<string, string>;
-  /** Port mappings: ["hostPort:guestPort", ...] */
-  portMap?: string[];
-  /** Environment variables */
+  /** virtiofs mounts; the { tag: hostPath } map form is still accepted */
+  mounts?: Mount[] | Record<string, string>;
+  /** Which host paths mounts, rootfsPath, root and disks may share (default: any not exposing credentials like ~/.ssh) */
+  mountPolicy?: MountPolicy;
+  /** Disk images attached as virtio-blk devices, in order */
+  disks?: Disk[];
+  /** Sparse scratch image attached as block device 'scratch', removed with the VM */
+  scratchDisk?: ScratchDisk;
+  /** Network backend (default: libkrun's TSI, which has no raw sockets or ICMP) */
+  network?: NetworkConfig;
+  /** Forwarded ports; omitted exposes every guest port (none with passt), [] exposes none; tsi and passt only */
+  portMap?: PortMapping[];
+  /** Send the guest's outbound connections through a host proxy that only lets allowed hosts through */
+  egress?: EgressConfig;
+  /** Guest environment; setExec env is merged on top (keys must not contain '=' or NUL) */
   env?: Record<string, string>;
 }
 
+export interface RootConfig {
+  kind: 'disk';
+  /** Host path of the raw image; attached read-write as block device 'root' */
+  path: string;
+  /** Guest device holding the root filesystem, e.g. '/dev/vda1' */
+  device: string;
+  /** Filesystem type, e.g. 'ext4' (default: auto-detect) */
+  fstype?: string;
+  /** Comma-separated mount options */
+  options?: string;
+}
+
+export interface Mount {
+  /** virtio-fs tag, at most 36 bytes; must be unique within a config */
+  tag: string;
+  /** Host directory to share */
+  hostPath: string;
+  /** Guest mountpoint, mounted by a /bin/sh wrapper around the exec before the workload starts */
+  guestPath?: string;
+  /** Mount read-only in the guest; requires guestPath, and a guest with root can remount it writable */
+  readOnly?: boolean;
+  /** Size of the DAX shared memory window in MiB (default: libkrun's) */
+  shmSizeMib?: number;
+}
+
+/** Checked against the host paths of mounts, rootfsPath, root and disks after resolving symlinks */
+export interface MountPolicy {
+  /** Only directories inside one of these may be mounted (default: any) */
+  allow?: string[];
+  /** Directories that may neither be mounted nor contained in a mount */
+  deny?: string[];
+  /** Permit mounts exposing ~/.ssh, ~/.aws and other credential stores (default: false) */
+  allowSensitive?: boolean;
+}
+
+/** Mount policy rule that refused a mount */
+export type MountRule = 'notAllowed' | 'denied' | 'sensitive';
+
+export type DiskFormat = 'raw' | 'qcow2';
+
+export interface Disk {
+  /** Partition identifier; must be unique within a config */
+  blockId: string;
+  /** Host path of the image */
+  path: string;
+  /** Image format; never probed from the image contents */
+  format: DiskFormat;
+  /** Attach read-only (default: false); writable images must be writable by this process */
+  readOnly?: boolean;
+}
+
+export interface ScratchDisk {
+  /** Size of the image in MiB; host space is only used as the guest writes */
+  sizeMib: number;
+}
+
+export interface PortMapping {
+  /** Host port, 1-65535; must be unique within a config */
+  hostPort: number;
+  /** Guest port, 1-65535 */
+  guestPort: number;
+  /** Host address to bind (default: all addresses) */
+  hostAddr?: string;
+}
+
+/** 'none' leaves out every device, TSI's vsock included, so the guest only has loopback */
+export type NetworkKind = 'tsi' | 'passt' | 'gvproxy' | 'unixstream' | 'unixgram' | 'nat' | 'none';
+
+export interface NetworkConfig {
+  kind: NetworkKind;
+  /** passt binary, as a path or a name on PATH (default: 'passt'); started per VM and stopped with it */
+  binary?: string;
+  /** Proxy socket; required for 'gvproxy', 'unixstream' and 'unixgram' */
+  socketPath?: string;
+  /** Send vfkit's handshake on a 'unixgram' socket, for gvproxy in vfkit mode (default: false) */
+  vfkit?: boolean;
+  /** Guest interface MAC as 'xx:xx:xx:xx:xx:xx' (default: '5a:94:ef:e4:0c:ee'); not for 'tsi' or 'none' */
+  mac?: string;
+  /** Let 'nat' guests reach the host's 127.0.0.1 services at the gateway, 10.0.2.2 (default: false) */
+  hostLoopback?: boolean;
+}
+
+/**
+ * Host proxy on vsock port vsockPort of CID 2, taking CONNECT, proxy-form HTTP and direct TLS by SNI.
+ * Requires network kind 'none', so the proxy is the guest's only way out.
+ */
+export interface EgressConfig {
+  /** Host names, IP addresses or '*.example.com' for any subdomain (default: any host) */
+  allow?: string[];
+  /** Hosts never reached, in the form of allow; wins over allow */
+  deny?: string[];
+  /** vsock port the proxy listens on (default: 3128) */
+  vsockPort?: number;
+  /** Host file each refused attempt is appended to as a line of EgressBlock JSON */
+  logPath?: string;
+}
+
+/** Why the egress proxy refused a connection; loopback and link-local addresses are always refused */
+export type EgressRule = 'denied' | 'notAllowed' | 'localAddress' | 'unidentified';
+
+export interface EgressBlock {
+  /** Requested host; empty if the request named none */
+  host: string;
+  /** Requested port; 0 if the request named none */
+  port: number;
+  rule: EgressRule;
+  /** Milliseconds since the Unix epoch */
+  timeMs: number;
+}
+
+export interface ConfigProblem {
+  /** Config field at fault, e.g. 'memoryMib' or 'mounts.workspace' */
+  field: string;
+  message: string;
+  /** Set when the mount policy refused a mount */
+  rule?: MountRule;
+}
+
 export interface VmInfo {
   /** libkrun context ID */
   ctxId: number;
@@ -30,14 +162,203 @@ export interface VmInfo {
   memoryMib: number;
 }
 
+export interface ShutdownOptions {
+  /** How long to wait for the guest before killing the VM (default: 10000) */
+  timeoutMs?: number;
+}
+
+export interface RollbackOptions {
+  /** Roll back even while the VM is running; the guest may keep stale cached files (default: false) */
+  force?: boolean;
+}
+
+export interface UnpackedImage {
+  /** Number of layers applied */
+  layers: number;
+  /** Environment as KEY=value */
+  env: string[];
+  entrypoint: string[];
+  cmd: string[];
+  workingDir?: string;
+  user?: string;
+}
+
+export interface CachedRootfs {
+  /** Pass as rootfsPath, with cloneRootfs: true */
+  path: string;
+  /** Chain key of the image's layers */
+  key: string;
+  /** Layers found in the cache rather than unpacked */
+  reusedLayers: number;
+  image: UnpackedImage;
+}
+
+export interface GcOptions {
+  /** Evict least recently used entries until the store fits in this many bytes (default: 0) */
+  keepBytes?: number;
+}
+
+export interface GcResult {
+  evicted: number;
+  freedBytes: number;
+  /** Size of the entries left */
+  keptBytes: number;
+}
+
+/**
+ * Content-addressed cache of unpacked images, keyed by layer digest
+ */
+export interface RootfsStore {
+  readonly dir: string;
+  /** Unpack an image into the store, reusing cached layers */
+  prepare(layoutOrTarPath: string): Promise<CachedRootfs>;
+  /** Evict entries no live VmHandle uses, least recently used first */
+  gc(options?: GcOptions): Promise<GcResult>;
+}
+
+/** What a tree entry is */
+export type EntryKind = 'file' | 'dir' | 'symlink';
+
+/**
+ * State of a directory tree, with the SHA-256 of every file
+ */
+export interface TreeSnapshot {
+  readonly root: string;
+  /** Number of regular files */
+  readonly fileCount: number;
+  /** Total size of the regular files */
+  readonly totalBytes: number;
+}
+
+/** A path that differs between two snapshots */
+export interface TreeChange {
+  /** Path relative to the snapshot root */
+  path: string;
+  /** Kind in the later snapshot, or the earlier one for deletions */
+  kind: EntryKind;
+  /** Size in the later snapshot, or the earlier one for deletions */
+  size: number;
+  /** Size in the earlier snapshot, for modifications */
+  previousSize?: number;
+  mode: number;
+  /** Mode in the earlier snapshot, when it changed */
+  previousMode?: number;
+}
+
+/** Differences between two snapshots, each list sorted by path */
+export interface TreeDiff {
+  added: TreeChange[];
+  /** Contents, symlink target or kind changed */
+  modified: TreeChange[];
+  deleted: TreeChange[];
+  /** Only the mode changed */
+  modeChanged: TreeChange[];
+}
+
+/** Why a VM stopped */
+export type VmExitReason =
+  | 'exited'
+  | 'shutdown'
+  | 'initFailed'
+  | 'startFailed'
+  | 'killed'
+  | 'crashed';
+
+/** Lifecycle state of a libkrun context */
+export type VmState = 'created' | 'configured' | 'running' | 'exited' | 'freed';
+
+export interface VmExit {
+  reason: VmExitReason;
+  /** Exit code of the guest init (absent if the VMM was signalled or never started) */
+  exitCode?: number;
+  /** Whether the host killed the VM */
+  killedByHost: boolean;
+  /** Signal that ended the VMM process */
+  signal?: number;
+  /** Wall time from start to exit in milliseconds */
+  wallTimeMs: number;
+  /** Why the VM could not be started */
+  error?: string;
+}
+
+/**
+ * Handle to a VM running in its supervisor process
+ */
+export interface VmProcess {
+  /** PID of the supervisor process */
+  readonly pid: number;
+  /** Resolves once the VM has stopped, describing how it ended */
+  wait(): Promise<VmExit>;
+}
+
+/**
+ * libkrun context owned by JavaScript, freed on stop() or garbage collection
+ */
+export interface VmHandle {
+  setExec(execPath: string, args: string[], env: Record<string, string>): void;
+  start(): VmProcess;
+  /** Resolves to true if the guest shut down before the timeout */
+  shutdown(options?: ShutdownOptions): Promise<boolean>;
+  stop(): void;
+  /** Save the writable mounts as checkpoint tag, reflinking files where the host allows */
+  checkpoint(tag: string): Promise<void>;
+  /** Restore the writable mounts from tag; throws ERR_INVALID_STATE while running unless forced */
+  rollback(tag: string, options?: RollbackOptions): Promise<void>;
+  /** Resolves to whether tag existed */
+  dropCheckpoint(tag: string): Promise<boolean>;
+  /** Connections the egress proxy refused, oldest first; the most recent 1024 are kept */
+  blockedEgress(): EgressBlock[];
+  /** How many refused connections blockedEgress() no longer lists */
+  blockedEgressDropped(): number;
+  /** Current lifecycle state; setExec after start, or a second start, throws */
+  readonly state: VmState;
+  info(): VmInfo;
+}
+
+/**
+ * Error thrown (or rejected with) by the native module
+ */
+export interface KrunError extends Error {
+  /** Errno name from libkrun or the host (e.g. 'EINVAL', 'ENOENT'), 'ERR_INVALID_STATE' or 'ERR_MOUNT_DENIED' */
+  code: string;
+  /** libkrun function that failed, e.g. 'krun_set_root' */
+  call?: string;
+  /** Config field the failure relates to, e.g. 'rootfsPath' */
+  field?: string;
+  /** Mount policy rule, for 'ERR_MOUNT_DENIED' */
+  rule?: MountRule;
+}
+
+export interface LibkrunProviderOptions {
+  /** Snapshot the mount path on start and stop so getChanges() can report the VM's changes (default: false) */
+  reportChanges?: boolean;
+}
+
 /**
  * Native module interface (loaded from .node file)
  */
 export interface LibkrunNative {
   isAvailable(): boolean;
   getVersion(): string;
+  /** Every problem with config on this host; empty if it is usable */
+  validateConfig(config: LibkrunConfig): ConfigProblem[];
+  /** Remove rootfs clones of processes that died; returns how many */
+  pruneRootfsClones(): number;
+  /** Unpack an OCI layout or docker save tarball into an empty destDir */
+  unpackOciImage(layoutOrTarPath: string, destDir: string): Promise<UnpackedImage>;
+  VmHandle: new (config: LibkrunConfig) => VmHandle;
+  /** Store in dir, by default the user's cache directory */
+  RootfsStore: new (dir?: string) => RootfsStore;
+  /** Snapshot every file, directory and symlink under path */
+  snapshotTree(path: string): Promise<TreeSnapshot>;
+  diffTree(before: TreeSnapshot, after: TreeSnapshot): TreeDiff;
+  /** @deprecated Use VmHandle */
   createContext(config: LibkrunConfig): VmInfo;
-  startVm(ctxId: number): number;
+  /** @deprecated Use VmHandle.start() */
+  startVm(ctxId: number): VmProcess;
+  shutdown(ctxId: number, options?: ShutdownOptions): Promise<boolean>;
+  /** @deprecated Use VmHandle.stop() */
   freeContext(ctxId: number): void;
+  /** @deprecated Use VmHandle.setExec() */
   setExec(ctxId: number, execPath: string, args: string[], env: Record<string, string>): void;
 }
@@ -0,0 +1,134 @@
+/**
+ * Self-test: a libkrun VM with network kind 'none' cannot connect out
+ *
+ * Boots the given rootfs twice with a probe that tries TCP connections to
+ * public addresses and to a listener on this host, and a DNS lookup:
+ * first with TSI, to show the probe does reach something, then with
+ * network kind 'none'. Passes if the air-gapped probe reaches nothing and
+ * the listener sees no connection from it.
+ *
+ * The rootfs needs busybox's sh, nc, nslookup and timeout; Alpine works.
+ *
+ * Usage: node test-libkrun-airgap.mjs <rootfs>   (or set LIBKRUN_ROOTFS)
+ */
+import { createRequire } from 'node:module';
+import { createServer } from 'node:net';
+import { networkInterfaces } from 'node:os';
+
+const require = createRequire(import.meta.url);
+const native = require('./packages/sandbox-libkrun/index.js');
+
+const rootfsPath = process.argv[2] ?? process.env.LIBKRUN_ROOTFS;
+if (!rootfsPath) {
+  console.error('Usage: node test-libkrun-airgap.mjs <rootfs>');
+  process.exit(2);
+}
+
+// Exit codes of the probe
+const REACHED_NOTHING = 0;
+const REACHED = 10;
+const NO_TOOLS = 11;
+
+// $@ is the list of host:port targets
+const PROBE = `
+for tool in nc nslookup timeout; do
+  command -v $tool >/dev/null 2>&1 || { echo "probe: $tool not found"; exit ${NO_TOOLS}; }
+done
+reached=0
+for target in "$@"; do
+  if nc -w 3 "\${target%:*}" "\${target##*:}" </dev/null >/dev/null 2>&1; then
+    echo "probe: connected to $target"
+    reached=1
+  else
+    echo "probe: no connection to $target"
+  fi
+done
+if timeout 5 nslookup example.com >/dev/null 2>&1; then
+  echo "probe: resolved example.com"
+  reached=1
+else
+  echo "probe: could not resolve example.com"
+fi
+[ $reached = 0 ] && exit ${REACHED_NOTHING}
+exit ${REACHED}
+`;
+
+/** This host's external IPv4 addresses */
+function hostAddresses() {
+  return Object.values(networkInterfaces())
+    .flat()
+    .filter((iface) => iface.family === 'IPv4' && !iface.internal)
+    .map((iface) => iface.address);
+}
+
+async function probe(label, network, targets) {
+  console.log(`\n== ${label}`);
+  const handle = new native.VmHandle({ rootfsPath, cpus: 1, memoryMib: 256, network });
+  try {
+    handle.setExec('/bin/sh', ['-c', PROBE, 'probe', ...targets], {});
+    const exit = await handle.start().wait();
+    console.log(`${label}: ${exit.reason}, exit code ${exit.exitCode}`);
+    return exit;
+  } finally {
+    handle.stop();
+  }
+}
+
+async function test() {
+  if (!native.isAvailable()) {
+    console.error('libkrun not available');
+    process.exit(2);
+  }
+
+  // A listener the guest could only reach by leaving the VM
+  let accepted = 0;
+  const server = createServer((socket) => {
+    accepted++;
+    socket.destroy();
+  });
+  await new Promise((resolve) => server.listen(0, '0.0.0.0', resolve));
+  const { port } = server.address();
+  const targets = [
+    ...hostAddresses().map((address) => `${address}:${port}`),
+    '1.1.1.1:443',
+    '8.8.8.8:53',
+    '9.9.9.9:443',
+  ];
+  console.log('Targets:', targets.join(' '));
+
+  let failed = false;
+  try {
+    const control = await probe('tsi (control)', undefined, targets);
+    if (control.exitCode === NO_TOOLS) {
+      console.error('FAIL: the rootfs lacks the probe tools');
+      process.exit(2);
+    }
+    if (control.exitCode !== REACHED) {
+      console.error('FAIL: the control probe reached nothing, so the air-gapped run would prove nothing');
+      process.exit(1);
+    }
+
+    const acceptedBefore = accepted;
+    const airgapped = await probe('none', { kind: 'none' }, targets);
+    if (airgapped.exitCode !== REACHED_NOTHING) {
+      console.error(`FAIL: the air-gapped probe exited with ${airgapped.exitCode} (${airgapped.reason})`);
+      failed = true;
+    }
+    if (accepted !== acceptedBefore) {
+      console.error(`FAIL: the host listener accepted ${accepted - acceptedBefore} connection(s) from the air-gapped VM`);
+      failed = true;
+    }
+  } finally {
+    server.close();
+  }
+
+  if (failed) {
+    process.exit(1);
+  }
+  console.log('\nPASS: no outbound connection succeeded with network kind none');
+}
+
+test().catch((err) => {
+  console.error('Error:', err);
+  process.exit(1);
+});